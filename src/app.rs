@@ -1,33 +1,107 @@
-use std::collections::HashSet;
-use std::path::PathBuf;
-use std::time::Instant;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::path::{Path, PathBuf};
 
 use egui_extras::{Size, StripBuilder};
 
 use eframe::{egui_glow, glow};
+use log::{error, info, warn};
+use rayon::prelude::*;
 use rfd::FileDialog;
 use std::sync::Arc;
 
 use egui::{FontData, FontDefinitions, FontFamily, Frame};
 
-use crate::app::converter::{AudioConverter, AudioFiletype};
-use crate::app::thread_handler::ThreadHandler;
-use glob::glob;
+use crate::app::converter::{
+    AudioConverter, AudioFiletype, EncoderSettings, TagOverrides, TrackTags,
+    AUDIOBOOK_BITRATE_KBPS, SUPPORTED_BITRATES,
+};
+use crate::app::atomic_write::{sweep_stale_temp_files, write_atomically};
+use crate::app::dry_run::{ConversionPlan, PlanAction};
+use crate::app::job::{excluded_files, included_files, Job, SourceRoot};
+use crate::app::library::{format_duration, scan_library, LibraryAlbum, THUMBNAIL_PX};
+use crate::app::log_console::{error_count, LogConsole};
+use crate::app::loudness::GainMode;
+use crate::app::device::{destination_warning, detect_memory_sticks, MemoryStick};
+use crate::app::path_template::{DEFAULT_PATH_TEMPLATE, PLACEHOLDERS};
+use crate::app::playlist::{PlaylistFormat, PlaylistOptions, PLAYLIST_EXTENSIONS};
+use crate::app::preview::{scan_problems, Finding, Problem};
+use crate::app::planner::{format_bytes, parse_size, CapacityPlan, FitMode};
+use crate::app::profiles::{all_profiles, install_profile, Profile};
+use crate::app::settings::Settings;
+use crate::app::sources::{collect_sources, SourceEntry, AUDIO_EXTENSIONS};
+use crate::app::thread_handler::{catch_panic, ThreadHandler};
 use std::default::Default;
-use std::fs::DirEntry;
 use std::sync::atomic::Ordering;
 
 use eframe::epaint::mutex::Mutex;
-use std::thread;
 
+mod atomic_write;
+mod chapters;
+pub mod cli;
 mod converter;
+mod cue;
+mod device;
+mod dry_run;
+mod id3;
+mod job;
+mod lame;
+mod library;
+pub mod log_console;
+mod loudness;
+mod lyrics;
+mod passthrough;
+mod path_template;
+mod preview;
+mod planner;
+mod playlist;
+mod profiles;
+mod report;
+mod settings;
+mod silence;
+mod sources;
 mod thread_handler;
 
 pub struct TemplateApp {
     // Example stuff:
-    folder_directories: HashSet<PathBuf>,
+    sources: Vec<SourceEntry>,
+    // exclusions and overrides of folders that came from a job file
+    source_roots: Vec<SourceRoot>,
     destination_directory: Option<PathBuf>,
-    start_time: Instant,
+    memory_sticks: Vec<MemoryStick>,
+    encoder_settings: EncoderSettings,
+    // instead of a fixed bitrate, pick the highest that fills `size_budget`
+    fit_to_budget: bool,
+    size_budget: String,
+    fit_mode: FitMode,
+    predicted_bytes: Option<u64>,
+    playlist_options: PlaylistOptions,
+    profiles: Vec<Profile>,
+    // the profile last picked, the settings may have been changed since
+    profile_name: String,
+    // set when a batch doesn't fit on the destination, until the user resolves it
+    capacity_plan: Option<CapacityPlan>,
+    // the batch "convert" was clicked for, None while its sources are still being probed
+    measured_plan: Arc<Mutex<Option<CapacityPlan>>>,
+    measuring: bool,
+    tag_editor_open: bool,
+    library_open: bool,
+    preview_open: bool,
+    dry_run_open: bool,
+    log_open: bool,
+    log_console: LogConsole,
+    // what converting the queue would do, None while it's still being worked out
+    dry_run: Arc<Mutex<Option<ConversionPlan>>>,
+    // problems found in the queued sources, None while they are still being looked for
+    findings: Arc<Mutex<Option<Vec<Finding>>>>,
+    // albums of the queued sources, None while they are still being scanned
+    library: Arc<Mutex<Option<Vec<LibraryAlbum>>>>,
+    // one per album of `library`, uploaded once the scan is done
+    library_textures: Vec<Option<egui::TextureHandle>>,
+    // tracks of the albums unticked in the library, left out of conversions
+    unticked_tracks: HashSet<PathBuf>,
+    // tags of the queued files, None while they are still being read
+    scanned_tags: Arc<Mutex<Option<ScannedTags>>>,
+    tag_overrides: HashMap<PathBuf, TagOverrides>,
     xmbwaveshader: Arc<Mutex<XmbWaveShader>>,
     thread_handler: ThreadHandler,
     t: f32,
@@ -56,14 +130,98 @@ impl TemplateApp {
             .as_ref()
             .expect("You need to run eframe with the glow backend");
 
-        Self {
+        let settings = Settings::load(cc.storage);
+
+        let mut app = Self {
             destination_directory: None,
-            folder_directories: HashSet::new(),
-            start_time: Instant::now(),
+            memory_sticks: detect_memory_sticks(),
+            sources: settings.sources,
+            source_roots: Vec::new(),
+            encoder_settings: settings.encoder_settings,
+            fit_to_budget: settings.fit_to_budget,
+            size_budget: settings.size_budget,
+            fit_mode: settings.fit_mode,
+            predicted_bytes: None,
+            playlist_options: settings.playlist_options,
+            profiles: all_profiles(),
+            profile_name: settings.profile,
+            capacity_plan: None,
+            measured_plan: Arc::new(Mutex::new(None)),
+            measuring: false,
+            tag_editor_open: false,
+            scanned_tags: Arc::new(Mutex::new(None)),
+            library_open: false,
+            preview_open: false,
+            dry_run_open: false,
+            log_open: false,
+            log_console: LogConsole::default(),
+            dry_run: Arc::new(Mutex::new(None)),
+            findings: Arc::new(Mutex::new(None)),
+            library: Arc::new(Mutex::new(None)),
+            library_textures: Vec::new(),
+            unticked_tracks: HashSet::new(),
+            tag_overrides: HashMap::new(),
             xmbwaveshader: Arc::new(Mutex::new(XmbWaveShader::new(gl))),
             thread_handler: ThreadHandler::new(),
             t: 0.0,
             acc: 0.5,
+        };
+        if let Some(destination) = settings.destination {
+            // leftovers of a run that got interrupted, a big Memory Stick takes a while to walk
+            let swept = destination.clone();
+            std::thread::spawn(move || {
+                let removed = sweep_stale_temp_files(&swept);
+                if removed > 0 {
+                    info!("Removed {} stale temp file/s", removed);
+                }
+            });
+            app.set_destination(destination);
+        }
+        app
+    }
+
+    fn settings(&self) -> Settings {
+        Settings {
+            sources: self.sources.clone(),
+            destination: self.destination_directory.clone(),
+            encoder_settings: self.encoder_settings.clone(),
+            fit_to_budget: self.fit_to_budget,
+            size_budget: self.size_budget.clone(),
+            fit_mode: self.fit_mode,
+            playlist_options: self.playlist_options,
+            profile: self.profile_name.clone(),
+            ..Default::default()
+        }
+    }
+
+    /// Switches to the profile called `name`, false if there is none.
+    pub fn use_profile(&mut self, name: &str) -> bool {
+        let Some(profile) = self
+            .profiles
+            .iter()
+            .find(|profile| profile.name.eq_ignore_ascii_case(name))
+            .cloned()
+        else {
+            return false;
+        };
+        self.apply_profile(profile);
+        true
+    }
+
+    fn apply_profile(&mut self, profile: Profile) {
+        self.encoder_settings = profile.encoder_settings;
+        self.playlist_options = profile.playlist_options;
+        self.size_budget = profile.size_budget;
+        self.profile_name = profile.name;
+    }
+
+    // what is picked right now, as a profile called `name`
+    fn current_profile(&self, name: String) -> Profile {
+        Profile {
+            name,
+            size_budget: self.size_budget.clone(),
+            encoder_settings: self.encoder_settings.clone(),
+            playlist_options: self.playlist_options,
         }
     }
 }
@@ -85,29 +243,96 @@ impl eframe::App for TemplateApp {
                     "{}/{}",
                     self.thread_handler
                         .num_finished
-                        .load(Ordering::Relaxed)
-                        .to_string(),
+                        .load(Ordering::Relaxed),
                     self.thread_handler
                         .num_processing
                         .load(Ordering::Relaxed)
-                        .to_string()
                 ));
 
-                if ui.button("Add Folder").clicked() {
-                    if let Some(file_path) = FileDialog::new().pick_folder() {
-                        self.folder_directories.insert(file_path);
-                    }
+                if let Some(predicted_bytes) = self.predicted_bytes {
+                    ui.label(format!(
+                        "predicted: {} / actual: {}",
+                        format_bytes(predicted_bytes),
+                        format_bytes(self.thread_handler.bytes_written.load(Ordering::Relaxed))
+                    ));
+                }
+
+                let clipped_tracks = self.thread_handler.clipped_tracks.load(Ordering::Relaxed);
+                if clipped_tracks > 0 {
+                    let warning = format!("{} track/s clip", clipped_tracks);
+                    ui.colored_label(egui::Color32::YELLOW, warning).on_hover_text(
+                        "their sources go past full scale, \"prevent clipping\" turns them down",
+                    );
+                }
+
+                let lossy_transcodes = self.thread_handler.lossy_transcodes.load(Ordering::Relaxed);
+                if lossy_transcodes > 0 {
+                    let warning = format!("{} track/s re-encoded from a lossy source", lossy_transcodes);
+                    ui.colored_label(egui::Color32::YELLOW, warning).on_hover_text(
+                        "MP3s the PSP can play are copied as they are, unless their bitrate is \
+                         above the one picked or the volume gets levelled",
+                    );
                 }
 
+                ui.horizontal(|ui| {
+                    if ui.button("Add Files").clicked() {
+                        let files = FileDialog::new()
+                            .add_filter("audio", &AUDIO_EXTENSIONS)
+                            .add_filter("playlist", &PLAYLIST_EXTENSIONS)
+                            .pick_files();
+                        for file in files.unwrap_or_default() {
+                            self.add_source(file);
+                        }
+                    }
+                    if ui.button("Add Folders").clicked() {
+                        for folder in FileDialog::new().pick_folders().unwrap_or_default() {
+                            self.add_source(folder);
+                        }
+                    }
+                });
+
+                ui.horizontal(|ui| {
+                    if ui.button("open job…").clicked() {
+                        if let Some(path) = job_dialog().pick_file() {
+                            match Job::load(&path) {
+                                Ok(job) => self.open_job(job),
+                                Err(e) => error!("Error opening job {:?}: {}", path, e),
+                            }
+                        }
+                    }
+                    if ui.button("save job…").clicked() {
+                        if let Some(path) = job_dialog().set_file_name("m2psp.toml").save_file() {
+                            if let Err(e) = self.job().save(&path) {
+                                error!("Error saving job {:?}: {}", path, e);
+                            }
+                        }
+                    }
+                });
+
                 if ui.button("Select Destination Folder").clicked() {
+                    // cancelling the picker keeps the destination there was
                     if let Some(file_path) = FileDialog::new().pick_folder() {
-                        self.destination_directory = Some(file_path);
-                        self.thread_handler.destination =
-                            self.destination_directory.clone().unwrap();
-                    } else {
-                        self.destination_directory = None;
+                        self.set_destination(file_path);
                     }
                 }
+
+                ui.horizontal(|ui| {
+                    if ui.button("Detect PSP").clicked() {
+                        self.memory_sticks = detect_memory_sticks();
+                    }
+                    let mut picked = None;
+                    for stick in &self.memory_sticks {
+                        if ui.button(stick.music_dir.to_string_lossy()).clicked() {
+                            picked = Some(stick.music_dir.clone());
+                        }
+                    }
+                    if let Some(music_dir) = picked {
+                        if let Err(e) = std::fs::create_dir_all(&music_dir) {
+                            error!("Error creating directory: {}", e);
+                        }
+                        self.set_destination(music_dir);
+                    }
+                });
                 ui.horizontal(|ui| {
                     ui.label("dest dir:");
                     let dst_ops = self.destination_directory.clone();
@@ -118,20 +343,200 @@ impl eframe::App for TemplateApp {
                     }
                     ui.text_edit_singleline(&mut dst_str);
                 });
+                if let Some(warning) = self.destination_directory.as_ref().and_then(|dir| {
+                    if dir.is_dir() {
+                        destination_warning(dir, &self.memory_sticks)
+                    } else {
+                        Some("Missing, maybe the drive isn't plugged in".to_string())
+                    }
+                }) {
+                    ui.colored_label(egui::Color32::YELLOW, warning);
+                }
+                ui.horizontal(|ui| {
+                    let report_dir = &mut self.thread_handler.report_dir;
+                    ui.label("report to:");
+                    match report_dir {
+                        Some(dir) => ui.label(dir.to_string_lossy()),
+                        None => ui.weak("the destination"),
+                    };
+                    if ui.button("report folder…").clicked() {
+                        if let Some(dir) = FileDialog::new().pick_folder() {
+                            *report_dir = Some(dir);
+                        }
+                    }
+                    if report_dir.is_some() && ui.button("×").clicked() {
+                        *report_dir = None;
+                    }
+                });
 
-                if ui.button("convert folder/s").clicked() {
-                    if !is_busy {
-                        let dst_ops = self.destination_directory.clone();
-                        match dst_ops {
-                            Some(dir) => {
-                                for folder in &self.folder_directories {
-                                    self.thread_handler
-                                        .add_files(collect_files_in_folder(folder));
-                                }
-                                self.thread_handler.execute_threads();
+                self.profiles_ui(ui);
+
+                ui.horizontal(|ui| {
+                    ui.add_enabled_ui(!self.fit_to_budget, |ui| {
+                        ui.label("bitrate:");
+                        bitrate_combo_box(ui, "bitrate", &mut self.encoder_settings.bitrate_kbps);
+                    });
+                    ui.checkbox(&mut self.encoder_settings.gapless_albums, "gapless albums")
+                        .on_hover_text("encode every album in one go, for live and concept albums");
+                });
+
+                ui.horizontal(|ui| {
+                    ui.label("sample rate:");
+                    let sample_rate = &mut self.encoder_settings.sample_rate;
+                    egui::ComboBox::from_id_salt("sample rate")
+                        .selected_text(match sample_rate {
+                            Some(rate) => format!("{} Hz", rate),
+                            None => "as source".to_string(),
+                        })
+                        .show_ui(ui, |ui| {
+                            ui.selectable_value(sample_rate, None, "as source");
+                            for rate in [44_100, 48_000] {
+                                ui.selectable_value(sample_rate, Some(rate), format!("{} Hz", rate));
                             }
-                            None => println!("You forgot to put the destination man!"),
+                        });
+                    ui.label("covers up to");
+                    ui.add(
+                        egui::DragValue::new(&mut self.encoder_settings.cover_max_px)
+                            .range(100..=1500)
+                            .suffix(" px"),
+                    );
+                    ui.checkbox(&mut self.encoder_settings.fat_safe_names, "FAT-safe names")
+                        .on_hover_text("leave out characters memory sticks and car stereos reject");
+                });
+                ui.horizontal(|ui| {
+                    ui.label("file names:");
+                    let placeholders: Vec<String> =
+                        PLACEHOLDERS.iter().map(|name| format!("{{{}}}", name)).collect();
+                    ui.text_edit_singleline(&mut self.encoder_settings.path_template)
+                        .on_hover_text(format!(
+                            "folders are split by /, .mp3 gets added. Fills in {}",
+                            placeholders.join(" ")
+                        ));
+                    if ui.button("default").clicked() {
+                        self.encoder_settings.path_template = DEFAULT_PATH_TEMPLATE.to_string();
+                    }
+                });
+
+                ui.horizontal(|ui| {
+                    ui.label("volume:");
+                    let gain_mode = &mut self.encoder_settings.gain_mode;
+                    ui.radio_value(gain_mode, GainMode::Off, "as is");
+                    ui.radio_value(gain_mode, GainMode::Track, "level tracks");
+                    ui.radio_value(gain_mode, GainMode::Album, "level albums");
+                    ui.checkbox(&mut self.encoder_settings.replaygain_tags, "ReplayGain tags");
+                    ui.checkbox(&mut self.encoder_settings.prevent_clipping, "prevent clipping");
+                });
+
+                ui.horizontal(|ui| {
+                    let audiobook = &mut self.encoder_settings.audiobook;
+                    ui.checkbox(&mut audiobook.enabled, "audiobook/podcast")
+                        .on_hover_text(format!(
+                            "split into chapters and encode speech in mono, \
+                             at {} kbps at most",
+                            AUDIOBOOK_BITRATE_KBPS
+                        ));
+                    ui.add_enabled_ui(audiobook.enabled, |ui| {
+                        ui.label("parts without chapters:");
+                        ui.add(
+                            egui::DragValue::new(&mut audiobook.segment_minutes)
+                                .range(1..=120)
+                                .suffix(" min"),
+                        );
+                    });
+                });
+
+                ui.horizontal(|ui| {
+                    let silence_trim = &mut self.encoder_settings.silence_trim;
+                    ui.checkbox(&mut silence_trim.enabled, "trim silence")
+                        .on_hover_text("cut long silences off the start and end of tracks");
+                    ui.add_enabled_ui(silence_trim.enabled, |ui| {
+                        ui.label("below");
+                        ui.add(
+                            egui::DragValue::new(&mut silence_trim.threshold_db)
+                                .range(-90.0..=-20.0)
+                                .suffix(" dB"),
+                        );
+                        ui.label("for");
+                        ui.add(
+                            egui::DragValue::new(&mut silence_trim.min_duration_secs)
+                                .range(0.5..=30.0)
+                                .speed(0.1)
+                                .suffix(" s"),
+                        );
+                        ui.checkbox(&mut silence_trim.fade, "fade");
+                    });
+                });
+
+                ui.horizontal(|ui| {
+                    ui.checkbox(&mut self.fit_to_budget, "fit to size:");
+                    ui.add_enabled_ui(self.fit_to_budget, |ui| {
+                        ui.add(egui::TextEdit::singleline(&mut self.size_budget).desired_width(80.0));
+                        ui.radio_value(&mut self.fit_mode, FitMode::Uniform, "uniform");
+                        ui.radio_value(&mut self.fit_mode, FitMode::PerAlbum, "per album");
+                    });
+                });
+
+                ui.horizontal(|ui| {
+                    ui.checkbox(&mut self.playlist_options.per_album, "album playlists");
+                    ui.checkbox(&mut self.playlist_options.library, "library playlist");
+                    ui.checkbox(&mut self.encoder_settings.copy_lrc, "copy .lrc");
+                    ui.radio_value(&mut self.playlist_options.format, PlaylistFormat::M3u, ".m3u");
+                    ui.radio_value(&mut self.playlist_options.format, PlaylistFormat::M3u8, ".m3u8");
+                });
+
+                ui.horizontal(|ui| {
+                    if ui.button("library").clicked() {
+                        self.scan_library();
+                    }
+                    if ui.button("edit tags").clicked() {
+                        self.scan_tags();
+                    }
+                    if ui.button("check sources").clicked() {
+                        self.scan_problems();
+                    }
+                    if ui.button("dry run").clicked() {
+                        self.plan_dry_run();
+                    }
+                    let errors = error_count();
+                    let log_button = if errors > 0 {
+                        egui::Button::new(
+                            egui::RichText::new(format!("log ({} error/s)", errors))
+                                .color(egui::Color32::RED),
+                        )
+                    } else {
+                        egui::Button::new("log")
+                    };
+                    if ui.add(log_button).clicked() {
+                        self.log_open = !self.log_open;
+                    }
+                });
+
+                let convert = ui.horizontal(|ui| {
+                    let convert = ui
+                        .add_enabled(!self.measuring, egui::Button::new("convert folder/s"))
+                        .clicked();
+                    if self.measuring {
+                        ui.spinner();
+                        ui.label("reading sources...");
+                    }
+                    convert
+                });
+                if convert.inner && !is_busy {
+                    let dst_ops = self.destination_directory.clone();
+                    match dst_ops {
+                        // writing there would make a folder where the stick should be mounted
+                        Some(dir) if !dir.is_dir() => {
+                            warn!("The destination {:?} isn't there, plug the drive in", dir)
+                        }
+                        Some(dir) => {
+                            let (files, source_playlists) =
+                                collect_sources(&self.sources);
+                            let mut files = included_files(&self.source_roots, files);
+                            files.retain(|file| !self.unticked_tracks.contains(file));
+                            self.thread_handler.source_playlists = source_playlists;
+                            self.measure(files, dir);
                         }
+                        None => warn!("You forgot to put the destination man!"),
                     }
                 }
 
@@ -143,7 +548,7 @@ impl eframe::App for TemplateApp {
                         // Add the top 'cell'
                         strip.cell(|ui| {
                             egui::ScrollArea::horizontal().show(ui, |ui| {
-                                table_ui(ui, &mut self.folder_directories);
+                                table_ui(ui, &mut self.sources);
                             });
                         });
                     });
@@ -152,6 +557,22 @@ impl eframe::App for TemplateApp {
                     egui::warn_if_debug_build(ui);
                 });
             });
+
+        self.convert_measured();
+        self.capacity_window(ctx);
+        self.tag_editor_window(ctx);
+        self.library_window(ctx);
+        self.preview_window(ctx);
+        self.dry_run_window(ctx);
+        egui::Window::new("Log")
+            .open(&mut self.log_open)
+            .default_size([640.0, 300.0])
+            .show(ctx, |ui| self.log_console.ui(ui));
+        self.handle_dropped_files(ctx);
+    }
+
+    fn save(&mut self, storage: &mut dyn eframe::Storage) {
+        self.settings().save(storage);
     }
 
     fn on_exit(&mut self, gl: Option<&glow::Context>) {
@@ -160,22 +581,874 @@ impl eframe::App for TemplateApp {
         }
     }
 }
+impl TemplateApp {
+    fn profiles_ui(&mut self, ui: &mut egui::Ui) {
+        ui.horizontal(|ui| {
+            ui.label("profile:");
+            let mut picked = None;
+            egui::ComboBox::from_id_salt("profile")
+                .selected_text(self.profile_name.as_str())
+                .show_ui(ui, |ui| {
+                    for profile in &self.profiles {
+                        let selected = profile.name == self.profile_name;
+                        if ui.selectable_label(selected, &profile.name).clicked() {
+                            picked = Some(profile.clone());
+                        }
+                    }
+                });
+            if let Some(profile) = picked {
+                self.apply_profile(profile);
+            }
+
+            if ui.button("import…").clicked() {
+                if let Some(path) = FileDialog::new().add_filter("profile", &["toml"]).pick_file() {
+                    match Profile::import(&path) {
+                        Ok(profile) => {
+                            if let Err(e) = install_profile(&profile) {
+                                error!("Error keeping profile {:?}: {}", profile.name, e);
+                            }
+                            self.profiles = all_profiles();
+                            self.apply_profile(profile);
+                        }
+                        Err(e) => error!("Error importing profile {:?}: {}", path, e),
+                    }
+                }
+            }
+
+            if ui.button("export…").clicked() {
+                let file_name = format!("{}.toml", self.profile_name);
+                if let Some(path) = FileDialog::new()
+                    .add_filter("profile", &["toml"])
+                    .set_file_name(file_name)
+                    .save_file()
+                {
+                    // the file name names the profile, so "save as" doubles as "new profile"
+                    let name = path.file_stem().unwrap_or_default().to_string_lossy().to_string();
+                    let profile = self.current_profile(name);
+                    if let Err(e) = profile.export(&path) {
+                        error!("Error exporting profile {:?}: {}", path, e);
+                    }
+                    if let Err(e) = install_profile(&profile) {
+                        error!("Error keeping profile {:?}: {}", profile.name, e);
+                    }
+                    self.profiles = all_profiles();
+                    self.profile_name = profile.name;
+                }
+            }
+        });
+    }
+
+    // folders join the list, tracks and playlists get queued as they are
+    fn handle_dropped_files(&mut self, ctx: &egui::Context) {
+        let hovered = ctx.input(|i| i.raw.hovered_files.len());
+        if hovered > 0 {
+            let painter = ctx.layer_painter(egui::LayerId::new(
+                egui::Order::Foreground,
+                egui::Id::new("drop zone"),
+            ));
+            let screen_rect = ctx.screen_rect();
+            painter.rect_filled(screen_rect, 0.0, egui::Color32::from_black_alpha(192));
+            painter.rect_stroke(
+                screen_rect.shrink(12.0),
+                8.0,
+                egui::Stroke::new(2.0, egui::Color32::WHITE),
+            );
+            painter.text(
+                screen_rect.center(),
+                egui::Align2::CENTER_CENTER,
+                format!("drop {} folder/s, track/s or playlist/s", hovered),
+                egui::TextStyle::Heading.resolve(&ctx.style()),
+                egui::Color32::WHITE,
+            );
+        }
+
+        let dropped = ctx.input(|i| i.raw.dropped_files.clone());
+        for path in dropped.into_iter().filter_map(|file| file.path) {
+            self.add_source(path);
+        }
+    }
+
+    // folders, tracks and playlists alike, each only once
+    fn add_source(&mut self, path: PathBuf) {
+        match SourceEntry::from_path(path.clone()) {
+            Some(entry) => {
+                if !self.sources.iter().any(|source| source.path() == entry.path()) {
+                    self.sources.push(entry);
+                }
+            }
+            None => warn!("Not a folder, track or playlist, left out : {:?}", path),
+        }
+    }
+
+    fn open_job(&mut self, job: Job) {
+        if !job.profile.is_empty() && !self.use_profile(&job.profile) {
+            warn!("No profile called {:?}, keeping the current settings", job.profile);
+        }
+        if let Some(encoder_settings) = job.encoder_settings {
+            self.encoder_settings = encoder_settings;
+        }
+        if let Some(playlists) = job.playlists {
+            self.playlist_options = playlists;
+        }
+        if !job.destination.as_os_str().is_empty() {
+            self.set_destination(job.destination);
+        }
+        self.thread_handler.report_dir = job.report_dir;
+        self.sources = job.sources.iter().filter_map(SourceRoot::entry).collect();
+        self.source_roots = job.sources;
+    }
+
+    // what the window is set up to do, as a job file would put it
+    fn job(&self) -> Job {
+        let sources = self
+            .sources
+            .iter()
+            .map(|entry| {
+                let root = self.source_roots.iter().find(|root| root.path == entry.path());
+                SourceRoot {
+                    recursive: matches!(entry, SourceEntry::RecursiveFolder(_)),
+                    ..root.cloned().unwrap_or_else(|| SourceRoot::new(entry.path().into()))
+                }
+            })
+            .collect();
+        // only spelled out when they differ from the profile, so editing the profile carries over
+        let profile = self.profiles.iter().find(|profile| profile.name == self.profile_name);
+        let encoder_settings = Some(self.encoder_settings.clone())
+            .filter(|settings| profile.map_or(true, |p| &p.encoder_settings != settings));
+        let playlists = Some(self.playlist_options)
+            .filter(|options| profile.map_or(true, |p| &p.playlist_options != options));
+        Job {
+            destination: self.destination_directory.clone().unwrap_or_default(),
+            report_dir: self.thread_handler.report_dir.clone(),
+            profile: self.profile_name.clone(),
+            encoder_settings,
+            playlists,
+            sources,
+        }
+    }
+
+    // leftover temp files get swept at startup and when a conversion starts, never on this thread
+    fn set_destination(&mut self, destination: PathBuf) {
+        self.thread_handler.destination = destination.clone();
+        self.destination_directory = Some(destination);
+    }
+
+    fn start_conversion(&mut self, files: Vec<PathBuf>, bitrate_overrides: HashMap<PathBuf, u16>) {
+        self.thread_handler.encoder_settings = self.encoder_settings.clone();
+        self.thread_handler.bitrate_overrides = bitrate_overrides;
+        self.thread_handler.tag_overrides = self.tag_overrides.clone();
+        self.thread_handler.source_roots = self.source_roots.clone();
+        self.thread_handler.playlist_options = self.playlist_options;
+        self.thread_handler.add_files(files);
+        self.thread_handler.execute_threads();
+    }
+
+    fn start_fitted_conversion(&mut self, plan: CapacityPlan) {
+        let Some(budget) = parse_size(&self.size_budget) else {
+            warn!("Can't make sense of the size {:?}", self.size_budget);
+            return;
+        };
+        // never plan for more than what is actually left on the destination
+        let budget = plan.free_bytes.map_or(budget, |free| free.min(budget));
+
+        match plan.fit_to_budget(budget, self.fit_mode) {
+            Some(bitrate_plan) => {
+                let bitrate_overrides = plan
+                    .selected_tracks()
+                    .map(|track| {
+                        let bitrate_kbps = bitrate_plan.album_bitrates[&track.album_key()];
+                        (track.path.clone(), bitrate_kbps)
+                    })
+                    .collect();
+                self.predicted_bytes = Some(bitrate_plan.predicted_bytes);
+                self.start_conversion(plan.selected_files(), bitrate_overrides);
+            }
+            // doesn't fit even at the lowest bitrate, let the user deselect albums
+            None => self.capacity_plan = Some(plan),
+        }
+    }
+
+    // Probes the batch in the background, a library of thousands of files takes a while
+    fn measure(&mut self, files: Vec<PathBuf>, destination: PathBuf) {
+        let measured_plan = Arc::clone(&self.measured_plan);
+        *measured_plan.lock() = None;
+        self.measuring = true;
+
+        std::thread::spawn(move || {
+            let plan = CapacityPlan::new(&files, &destination);
+            *measured_plan.lock() = Some(plan);
+        });
+    }
+
+    // Starts the batch once it's been measured, or asks what to leave out when it doesn't fit
+    fn convert_measured(&mut self) {
+        if !self.measuring {
+            return;
+        }
+        let Some(plan) = self.measured_plan.lock().take() else {
+            return;
+        };
+        self.measuring = false;
+
+        if self.fit_to_budget {
+            self.start_fitted_conversion(plan);
+        } else if plan.fits(&self.encoder_settings) {
+            self.predicted_bytes = Some(plan.estimated_bytes(&self.encoder_settings));
+            self.start_conversion(plan.selected_files(), HashMap::new());
+        } else {
+            self.capacity_plan = Some(plan);
+        }
+    }
+
+    // Shown when the batch is bigger than the free space on the destination
+    fn capacity_window(&mut self, ctx: &egui::Context) {
+        let Some(plan) = &mut self.capacity_plan else {
+            return;
+        };
+
+        let mut open = true;
+        let mut start = false;
+        egui::Window::new("Not enough space")
+            .collapsible(false)
+            .open(&mut open)
+            .show(ctx, |ui| {
+                let needed = plan.estimated_bytes(&self.encoder_settings);
+                let free = plan.free_bytes.map(format_bytes).unwrap_or_default();
+                ui.label(format!("needed: {} / free: {}", format_bytes(needed), free));
+
+                match plan.highest_fitting_bitrate(&self.encoder_settings) {
+                    Some(bitrate_kbps) if bitrate_kbps < self.encoder_settings.bitrate_kbps => {
+                        if ui
+                            .button(format!("lower bitrate to {} kbps", bitrate_kbps))
+                            .clicked()
+                        {
+                            self.encoder_settings.bitrate_kbps = bitrate_kbps;
+                        }
+                    }
+                    Some(_) => {}
+                    None => {
+                        ui.label("doesn't fit even at the lowest bitrate, deselect some albums");
+                    }
+                }
+
+                ui.separator();
+                egui::ScrollArea::vertical()
+                    .max_height(200.0)
+                    .show(ui, |ui| {
+                        for (album, size) in plan.album_sizes(&self.encoder_settings) {
+                            let mut selected = !plan.excluded_albums.contains(&album);
+                            let (folder, name) = &album;
+                            let name = if name.is_empty() { "(no album)" } else { name };
+                            // the folder tells apart albums that go by the same name
+                            let folder = folder.file_name().unwrap_or_default().to_string_lossy();
+                            let label = format!("{} in {} ({})", name, folder, format_bytes(size));
+                            if ui.checkbox(&mut selected, label).changed()
+                            {
+                                if selected {
+                                    plan.excluded_albums.remove(&album);
+                                } else {
+                                    plan.excluded_albums.insert(album.clone());
+                                }
+                            }
+                        }
+                    });
+
+                ui.separator();
+                ui.add_enabled_ui(plan.fits(&self.encoder_settings), |ui| {
+                    if ui.button("convert").clicked() {
+                        start = true;
+                    }
+                });
+            });
+
+        if start {
+            let files = plan.selected_files();
+            self.predicted_bytes = Some(plan.estimated_bytes(&self.encoder_settings));
+            self.capacity_plan = None;
+            self.start_conversion(files, HashMap::new());
+        } else if !open {
+            self.capacity_plan = None;
+        }
+    }
+}
+
+impl TemplateApp {
+    // Reads the tags of everything queued in the background and opens the editor
+    fn scan_tags(&mut self) {
+        let (files, _) = collect_sources(&self.sources);
+        let scanned_tags = Arc::clone(&self.scanned_tags);
+        *scanned_tags.lock() = None;
+        self.tag_editor_open = true;
+
+        std::thread::spawn(move || {
+            let mut tracks: ScannedTags = files
+                .par_iter()
+                .filter_map(|file| {
+                    let read_tags = || {
+                        AudioConverter::new(file.clone(), AudioFiletype::MP3)
+                            .and_then(|converter| converter.read_tags())
+                    };
+                    // a panic is already logged, the file just stays out of the editor
+                    match catch_panic(file, read_tags)? {
+                        Ok(tags) => Some((file.clone(), tags)),
+                        Err(e) => {
+                            error!(
+                                file:% = file.display();
+                                "Error reading tags of {:?}: {}", file, e
+                            );
+                            None
+                        }
+                    }
+                })
+                .collect();
+            tracks.sort_by(|(a, _), (b, _)| a.cmp(b));
+            *scanned_tags.lock() = Some(tracks);
+        });
+    }
+
+    // Reads every queued file as the conversion would, short of decoding, and lists what's off
+    fn scan_problems(&mut self) {
+        let (files, _) = collect_sources(&self.sources);
+        let mut files = included_files(&self.source_roots, files);
+        files.retain(|file| !self.unticked_tracks.contains(file));
+        let tag_overrides = self.tag_overrides.clone();
+        let findings = Arc::clone(&self.findings);
+        *findings.lock() = None;
+        self.preview_open = true;
+
+        std::thread::spawn(move || {
+            let found = scan_problems(&files, &tag_overrides);
+            *findings.lock() = Some(found);
+        });
+    }
+
+    // The problems of the last scan, each with a button that fixes it
+    fn preview_window(&mut self, ctx: &egui::Context) {
+        if !self.preview_open {
+            return;
+        }
+
+        let findings = Arc::clone(&self.findings);
+        let mut findings = findings.lock();
+        let mut fixed: Vec<usize> = Vec::new();
+        let mut rescan = false;
+        let tag_overrides = &mut self.tag_overrides;
+        let unticked_tracks = &mut self.unticked_tracks;
+        let sample_rate = &mut self.encoder_settings.sample_rate;
+        egui::Window::new("Scan preview")
+            .open(&mut self.preview_open)
+            .default_size([640.0, 400.0])
+            .show(ctx, |ui| {
+                let Some(findings) = findings.as_mut() else {
+                    ui.horizontal(|ui| {
+                        ui.spinner();
+                        ui.label("reading tags...");
+                    });
+                    return;
+                };
+                ui.horizontal(|ui| {
+                    if findings.is_empty() {
+                        ui.label("nothing to fix");
+                    } else {
+                        ui.label(format!("{} problem/s", findings.len()));
+                    }
+                    rescan = ui.button("scan again").clicked();
+                });
+                ui.separator();
+
+                egui::ScrollArea::vertical().show(ui, |ui| {
+                    egui::Grid::new("findings").striped(true).show(ui, |ui| {
+                        for (i, finding) in findings.iter().enumerate() {
+                            let file_name = finding.path.file_name().unwrap_or_default();
+                            ui.label(file_name.to_string_lossy())
+                                .on_hover_text(finding.path.to_string_lossy());
+                            ui.colored_label(egui::Color32::YELLOW, finding.problem.describe());
+                            fixed.extend(fix_finding_ui(
+                                ui,
+                                findings,
+                                i,
+                                tag_overrides,
+                                unticked_tracks,
+                                sample_rate,
+                            ));
+                            ui.end_row();
+                        }
+                    });
+                });
+
+                fixed.sort_unstable();
+                fixed.dedup();
+                for &i in fixed.iter().rev() {
+                    findings.remove(i);
+                }
+            });
+
+        drop(findings);
+        if rescan {
+            self.scan_problems();
+        }
+    }
+
+    // Works out what "convert" would write with the current settings, without writing it
+    fn plan_dry_run(&mut self) {
+        let Some(destination) = self.destination_directory.clone() else {
+            warn!("You forgot to put the destination man!");
+            return;
+        };
+        let (files, _) = collect_sources(&self.sources);
+        let excluded = excluded_files(&self.source_roots, &files);
+        let (unticked, files): (Vec<PathBuf>, Vec<PathBuf>) =
+            included_files(&self.source_roots, files)
+                .into_iter()
+                .partition(|file| self.unticked_tracks.contains(file));
+
+        let mut thread_handler = ThreadHandler::new();
+        thread_handler.destination = destination;
+        thread_handler.encoder_settings = self.encoder_settings.clone();
+        thread_handler.tag_overrides = self.tag_overrides.clone();
+        thread_handler.source_roots = self.source_roots.clone();
+        thread_handler.add_files(files);
+        let dry_run = Arc::clone(&self.dry_run);
+        *dry_run.lock() = None;
+        self.dry_run_open = true;
+
+        std::thread::spawn(move || {
+            let mut plan = thread_handler.plan();
+            for file in excluded {
+                plan.skip(file, "excluded by the job");
+            }
+            for file in unticked {
+                plan.skip(file, "left out in the library");
+            }
+            *dry_run.lock() = Some(plan);
+        });
+    }
+
+    fn dry_run_window(&mut self, ctx: &egui::Context) {
+        if !self.dry_run_open {
+            return;
+        }
+
+        let dry_run = Arc::clone(&self.dry_run);
+        let dry_run = dry_run.lock();
+        let mut replan = false;
+        egui::Window::new("Dry run")
+            .open(&mut self.dry_run_open)
+            .default_size([720.0, 400.0])
+            .show(ctx, |ui| {
+                let Some(plan) = dry_run.as_ref() else {
+                    ui.horizontal(|ui| {
+                        ui.spinner();
+                        ui.label("reading sources...");
+                    });
+                    return;
+                };
+                ui.label(plan.summary());
+                if self.fit_to_budget {
+                    ui.label("sizes are at the picked bitrate, before fitting to size");
+                }
+                ui.horizontal(|ui| {
+                    replan = ui.button("plan again").clicked();
+                    if ui.button("export JSON…").clicked() {
+                        let path = FileDialog::new()
+                            .add_filter("JSON", &["json"])
+                            .set_file_name("m2psp-plan.json")
+                            .save_file();
+                        if let Some(path) = path {
+                            if let Err(e) = write_atomically(&path, plan.to_json().as_bytes()) {
+                                error!("Error exporting plan {:?}: {}", path, e);
+                            }
+                        }
+                    }
+                });
+                ui.separator();
+
+                egui::ScrollArea::vertical().show(ui, |ui| {
+                    egui::Grid::new("dry run").striped(true).show(ui, |ui| {
+                        for entry in &plan.entries {
+                            let action = format!("{:?}", entry.action).to_lowercase();
+                            match entry.action {
+                                PlanAction::Convert | PlanAction::Copy => ui.label(action),
+                                PlanAction::Skip => ui.weak(action),
+                                PlanAction::Fail => ui.colored_label(egui::Color32::RED, action),
+                            };
+                            let file_name = entry.source.file_name().unwrap_or_default();
+                            ui.label(file_name.to_string_lossy())
+                                .on_hover_text(entry.source.to_string_lossy());
+                            match (&entry.output, &entry.reason) {
+                                (Some(output), _) => {
+                                    let relative =
+                                        output.strip_prefix(&plan.destination).unwrap_or(output);
+                                    ui.label(relative.to_string_lossy());
+                                    ui.label(format_bytes(entry.estimated_bytes));
+                                }
+                                (None, reason) => {
+                                    ui.label(reason.as_deref().unwrap_or_default());
+                                    ui.label("");
+                                }
+                            }
+                            ui.end_row();
+                        }
+                    });
+                });
+            });
+
+        drop(dry_run);
+        if replan {
+            self.plan_dry_run();
+        }
+    }
+
+    // A quick scan of the queued albums for the library window
+    fn scan_library(&mut self) {
+        let (files, _) = collect_sources(&self.sources);
+        let files = included_files(&self.source_roots, files);
+        let library = Arc::clone(&self.library);
+        *library.lock() = None;
+        self.library_textures.clear();
+        self.library_open = true;
+
+        std::thread::spawn(move || {
+            let albums = scan_library(&files);
+            *library.lock() = Some(albums);
+        });
+    }
+
+    // The queued albums as a grid of covers, like the XMB music menu
+    fn library_window(&mut self, ctx: &egui::Context) {
+        if !self.library_open {
+            return;
+        }
+
+        let library = Arc::clone(&self.library);
+        let library = library.lock();
+        if let Some(albums) = library.as_ref() {
+            if self.library_textures.len() != albums.len() {
+                self.library_textures = albums
+                    .iter()
+                    .map(|album| {
+                        let thumbnail = album.thumbnail.clone()?;
+                        let name = format!("cover {}", album.folder.to_string_lossy());
+                        Some(ctx.load_texture(name, thumbnail, egui::TextureOptions::LINEAR))
+                    })
+                    .collect();
+            }
+        }
+
+        let textures = &self.library_textures;
+        let unticked_tracks = &mut self.unticked_tracks;
+        egui::Window::new("Library")
+            .open(&mut self.library_open)
+            .default_size([640.0, 480.0])
+            .show(ctx, |ui| {
+                let Some(albums) = library.as_ref() else {
+                    ui.horizontal(|ui| {
+                        ui.spinner();
+                        ui.label("scanning...");
+                    });
+                    return;
+                };
+                let ticked = albums
+                    .iter()
+                    .filter(|album| !album.tracks.iter().all(|t| unticked_tracks.contains(t)))
+                    .count();
+                ui.label(format!("{} of {} album/s will be converted", ticked, albums.len()));
+                ui.separator();
+
+                egui::ScrollArea::vertical().show(ui, |ui| {
+                    ui.horizontal_wrapped(|ui| {
+                        for (album, texture) in albums.iter().zip(textures) {
+                            album_tile_ui(ui, album, texture.as_ref(), unticked_tracks);
+                        }
+                    });
+                });
+            });
+    }
+
+    // Album-wide and per track tag edits, kept until the next conversion uses them
+    fn tag_editor_window(&mut self, ctx: &egui::Context) {
+        if !self.tag_editor_open {
+            return;
+        }
+
+        let scanned_tags = Arc::clone(&self.scanned_tags);
+        let scanned_tags = scanned_tags.lock();
+        let tag_overrides = &mut self.tag_overrides;
+        egui::Window::new("Tags")
+            .open(&mut self.tag_editor_open)
+            .default_height(400.0)
+            .show(ctx, |ui| {
+                let Some(tracks) = scanned_tags.as_ref() else {
+                    ui.horizontal(|ui| {
+                        ui.spinner();
+                        ui.label("reading tags...");
+                    });
+                    return;
+                };
+                ui.label("changes only go into the converted files, the sources stay as they are");
+                ui.separator();
+
+                // (folder, album) as read, so renaming an album doesn't regroup it
+                let mut albums: BTreeMap<(&Path, &str), Vec<&(PathBuf, TrackTags)>> =
+                    BTreeMap::new();
+                for track in tracks {
+                    let folder = track.0.parent().unwrap_or(Path::new(""));
+                    albums.entry((folder, &track.1.album)).or_default().push(track);
+                }
+
+                egui::ScrollArea::vertical().show(ui, |ui| {
+                    for ((folder, album), tracks) in albums {
+                        let id = format!("{}/{}", folder.to_string_lossy(), album);
+                        album_tags_ui(ui, &id, &tracks, tag_overrides);
+                    }
+                });
+            });
+    }
+}
+
+type ScannedTags = Vec<(PathBuf, TrackTags)>;
+
+// the button that fixes `findings[i]`, returns the findings it took care of
+fn fix_finding_ui(
+    ui: &mut egui::Ui,
+    findings: &[Finding],
+    i: usize,
+    tag_overrides: &mut HashMap<PathBuf, TagOverrides>,
+    unticked_tracks: &mut HashSet<PathBuf>,
+    sample_rate: &mut Option<u32>,
+) -> Vec<usize> {
+    let finding = &findings[i];
+    let mut fixed = Vec::new();
+    match &finding.problem {
+        Problem::Unreadable(_) | Problem::UnsupportedCodec(_) => {
+            if ui.button("leave out").clicked() {
+                unticked_tracks.insert(finding.path.clone());
+                fixed.push(i);
+            }
+        }
+        Problem::HighSampleRate(_) => {
+            if ui.button("resample to 44100 Hz").clicked() {
+                *sample_rate = Some(44_100);
+                // that's every file taken care of
+                fixed.extend(findings.iter().enumerate().filter_map(|(j, other)| {
+                    matches!(other.problem, Problem::HighSampleRate(_)).then_some(j)
+                }));
+            }
+        }
+        Problem::MissingTitle { suggested } => {
+            if ui.button(format!("title {:?}", suggested)).clicked() {
+                let overrides = tag_overrides.entry(finding.path.clone()).or_default();
+                overrides.title = Some(suggested.clone());
+                fixed.push(i);
+            }
+        }
+        Problem::MissingTrackNumber { suggested }
+        | Problem::DuplicateTrackNumber { suggested, .. } => {
+            if ui.button(format!("number {}", suggested)).clicked() {
+                let overrides = tag_overrides.entry(finding.path.clone()).or_default();
+                overrides.track_number = Some(suggested.clone());
+                fixed.push(i);
+            }
+        }
+        Problem::MissingCover => {
+            if ui.button("pick cover…").clicked() {
+                if let Some(cover) = FileDialog::new()
+                    .add_filter("image", &["jpg", "jpeg", "png"])
+                    .pick_file()
+                {
+                    // the rest of the album goes without as well
+                    let folder = finding.path.parent();
+                    for (j, other) in findings.iter().enumerate() {
+                        if other.problem == Problem::MissingCover && other.path.parent() == folder {
+                            tag_overrides.entry(other.path.clone()).or_default().cover =
+                                Some(cover.clone());
+                            fixed.push(j);
+                        }
+                    }
+                }
+            }
+        }
+        Problem::AlbumArtistMismatch { expected, .. } => {
+            if ui.button(format!("use {:?}", expected)).clicked() {
+                let overrides = tag_overrides.entry(finding.path.clone()).or_default();
+                overrides.album_artist = Some(expected.clone());
+                fixed.push(i);
+            }
+        }
+    }
+    fixed
+}
+
+fn album_tile_ui(
+    ui: &mut egui::Ui,
+    album: &LibraryAlbum,
+    texture: Option<&egui::TextureHandle>,
+    unticked_tracks: &mut HashSet<PathBuf>,
+) {
+    let size = egui::Vec2::splat(THUMBNAIL_PX as f32);
+    let mut ticked = !album.tracks.iter().all(|track| unticked_tracks.contains(track));
+
+    egui::Frame::group(ui.style())
+        .fill(egui::Color32::from_black_alpha(if ticked { 96 } else { 32 }))
+        .show(ui, |ui| {
+            ui.set_width(size.x + 24.0);
+            ui.vertical_centered(|ui| {
+                match texture {
+                    Some(texture) => {
+                        let tint = if ticked {
+                            egui::Color32::WHITE
+                        } else {
+                            egui::Color32::from_gray(80)
+                        };
+                        ui.add(egui::Image::new((texture.id(), size)).tint(tint));
+                    }
+                    None => {
+                        let (rect, _) = ui.allocate_exact_size(size, egui::Sense::hover());
+                        ui.painter().rect_filled(rect, 4.0, egui::Color32::from_gray(40));
+                        ui.painter().text(
+                            rect.center(),
+                            egui::Align2::CENTER_CENTER,
+                            "♪",
+                            egui::FontId::proportional(32.0),
+                            egui::Color32::from_gray(160),
+                        );
+                    }
+                }
+                let album_name = if album.album.is_empty() {
+                    album.folder.file_name().unwrap_or_default().to_string_lossy().to_string()
+                } else {
+                    album.album.clone()
+                };
+                ui.add(egui::Label::new(egui::RichText::new(album_name).strong()).truncate())
+                    .on_hover_text(album.folder.to_string_lossy());
+                ui.add(egui::Label::new(&album.artist).truncate());
+                ui.label(format!(
+                    "{} track/s · {}",
+                    album.tracks.len(),
+                    format_duration(album.duration_secs)
+                ));
+                if ui.checkbox(&mut ticked, "convert").changed() {
+                    for track in &album.tracks {
+                        if ticked {
+                            unticked_tracks.remove(track);
+                        } else {
+                            unticked_tracks.insert(track.clone());
+                        }
+                    }
+                }
+            });
+        });
+}
+
+type OverrideField = fn(&mut TagOverrides) -> &mut Option<String>;
+
+fn album_tags_ui(
+    ui: &mut egui::Ui,
+    id: &str,
+    tracks: &[&(PathBuf, TrackTags)],
+    tag_overrides: &mut HashMap<PathBuf, TagOverrides>,
+) {
+    let (first_path, first_tags) = tracks[0];
+    // what the album shows is what its first track has, edits go to all of them
+    let mut first = tag_overrides.get(first_path).cloned().unwrap_or_default();
+    let album = first.album.clone().unwrap_or_else(|| first_tags.album.clone());
+    let heading = if album.is_empty() { "(no album)".to_string() } else { album };
+
+    egui::CollapsingHeader::new(heading).id_salt(id).show(ui, |ui| {
+        let album_fields: [(&str, &str, OverrideField); 4] = [
+            ("album", &first_tags.album, |o| &mut o.album),
+            ("album artist", &first_tags.album_artist, |o| &mut o.album_artist),
+            ("year", &first_tags.year, |o| &mut o.year),
+            ("genre", &first_tags.genre, |o| &mut o.genre),
+        ];
+        egui::Grid::new(format!("{}-album", id)).num_columns(2).show(ui, |ui| {
+            for (label, read, field) in album_fields {
+                ui.label(label);
+                let mut value = field(&mut first).clone().unwrap_or_else(|| read.to_string());
+                if ui.text_edit_singleline(&mut value).changed() {
+                    for (path, _) in tracks {
+                        let overrides = tag_overrides.entry(path.clone()).or_default();
+                        *field(overrides) = Some(value.clone());
+                    }
+                }
+                ui.end_row();
+            }
+
+            ui.label("cover");
+            ui.horizontal(|ui| {
+                let cover = match &first.cover {
+                    Some(cover) => cover.file_name().unwrap_or_default().to_string_lossy(),
+                    None if first_tags.has_cover => "from the source".into(),
+                    None => "none".into(),
+                };
+                ui.label(cover);
+                if ui.button("change").clicked() {
+                    if let Some(image) = FileDialog::new()
+                        .add_filter("image", &["jpg", "jpeg", "png"])
+                        .pick_file()
+                    {
+                        for (path, _) in tracks {
+                            let overrides = tag_overrides.entry(path.clone()).or_default();
+                            overrides.cover = Some(image.clone());
+                        }
+                    }
+                }
+                if ui.button("reset album").clicked() {
+                    for (path, _) in tracks {
+                        tag_overrides.remove(path);
+                    }
+                }
+            });
+            ui.end_row();
+        });
+
+        ui.separator();
+        egui::Grid::new(format!("{}-tracks", id)).num_columns(3).striped(true).show(ui, |ui| {
+            for (path, tags) in tracks {
+                let overrides = tag_overrides.get(path);
+                let mut number = overrides
+                    .and_then(|o| o.track_number.clone())
+                    .unwrap_or_else(|| tags.track_number.clone());
+                let mut title = overrides
+                    .and_then(|o| o.title.clone())
+                    .unwrap_or_else(|| tags.title.clone());
+
+                if ui.add(egui::TextEdit::singleline(&mut number).desired_width(30.0)).changed() {
+                    tag_overrides.entry(path.clone()).or_default().track_number = Some(number);
+                }
+                if ui.text_edit_singleline(&mut title).changed() {
+                    tag_overrides.entry(path.clone()).or_default().title = Some(title);
+                }
+                ui.label(&tags.artist);
+                ui.end_row();
+            }
+        });
+    });
+}
+
+fn bitrate_combo_box(ui: &mut egui::Ui, id_salt: &str, bitrate_kbps: &mut u16) {
+    egui::ComboBox::from_id_salt(id_salt)
+        .selected_text(format!("{} kbps", bitrate_kbps))
+        .show_ui(ui, |ui| {
+            for bitrate in SUPPORTED_BITRATES {
+                ui.selectable_value(bitrate_kbps, bitrate, format!("{} kbps", bitrate));
+            }
+        });
+}
+
 fn lerp(a: f32, b: f32, t: f32) -> f32 {
     a + (b - a) * t
 }
 impl TemplateApp {
     fn paint_on_window_background(&mut self, ctx: &egui::Context, is_busy: &bool) {
         let screen_rect = ctx.screen_rect();
-        let mut start_time: f32 = 0.0;
         if *is_busy {
             self.acc = lerp(self.acc, 0.25, 0.005);
-            self.t += self.acc;
-            start_time = self.t;
         } else {
             self.acc = lerp(self.acc, 0.01, 0.01);
-            self.t += self.acc;
-            start_time = self.t;
         }
+        self.t += self.acc;
+        let start_time = self.t;
 
         let xmbwaveshader = self.xmbwaveshader.clone();
 
@@ -189,28 +1462,11 @@ impl TemplateApp {
     }
 }
 
-// Collects all the files that have an acceptable extension
-fn collect_files_in_folder(dir: &PathBuf) -> Vec<PathBuf> {
-    let valid_extensions = ["flac", "ogg", "mp3", "aac"];
-
-    let files: Vec<PathBuf> = std::fs::read_dir(dir)
-        .unwrap()
-        .filter(|entry_res| match entry_res {
-            Ok(entry) => match entry.path().extension() {
-                Some(str) => valid_extensions.contains(&str.to_str().unwrap()),
-                None => false,
-            },
-            Err(_) => {
-                panic!("Error at reading entries!")
-            }
-        })
-        .map(|entry_res| entry_res.unwrap().path())
-        .collect();
-
-    files
+fn job_dialog() -> FileDialog {
+    FileDialog::new().add_filter("job", &["toml"])
 }
 
-fn table_ui(ui: &mut egui::Ui, data: &mut HashSet<PathBuf>) {
+fn table_ui(ui: &mut egui::Ui, sources: &mut Vec<SourceEntry>) {
     use egui_extras::{Column, TableBuilder};
 
     let mut to_remove = Vec::new();
@@ -221,30 +1477,50 @@ fn table_ui(ui: &mut egui::Ui, data: &mut HashSet<PathBuf>) {
         .column(Column::remainder())
         .header(20.0, |mut header| {
             header.col(|ui| {
-                ui.heading("Directory");
+                ui.heading("Source");
             });
             header.col(|ui| {
                 ui.heading("Modify");
             });
         })
         .body(|mut body| {
-            for entry in data.iter() {
+            for entry in sources.iter_mut() {
                 body.row(15.0, |mut row| {
                     row.col(|ui| {
-                        ui.label(entry.to_string_lossy());
+                        let path = entry.path().to_string_lossy();
+                        let label = format!("{} : {}", entry.kind(), path);
+                        if entry.exists() {
+                            ui.label(label);
+                        } else {
+                            ui.colored_label(egui::Color32::YELLOW, label + " (missing)")
+                                .on_hover_text("not found, maybe the drive isn't plugged in");
+                        }
                     });
                     row.col(|ui| {
-                        if ui.button("remove").clicked() {
-                            to_remove.push(entry.clone())
-                        }
+                        ui.horizontal(|ui| {
+                            if ui.button("remove").clicked() {
+                                to_remove.push(entry.clone())
+                            }
+                            let mut recursive = matches!(entry, SourceEntry::RecursiveFolder(_));
+                            if let SourceEntry::Folder(path) | SourceEntry::RecursiveFolder(path) =
+                                entry
+                            {
+                                if ui.checkbox(&mut recursive, "subfolders").changed() {
+                                    let path = std::mem::take(path);
+                                    *entry = if recursive {
+                                        SourceEntry::RecursiveFolder(path)
+                                    } else {
+                                        SourceEntry::Folder(path)
+                                    };
+                                }
+                            }
+                        });
                     });
                 });
             }
         });
 
-    for entry in to_remove {
-        data.remove(&entry);
-    }
+    sources.retain(|entry| !to_remove.contains(entry));
 }
 
 struct XmbWaveShader {
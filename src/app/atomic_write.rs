@@ -0,0 +1,150 @@
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use log::warn;
+
+// Every temp file we create ends with this, so that a later run can tell them apart
+// from the user's own files and clean up after a crash or an unplugged Memory Stick
+pub const TEMP_SUFFIX: &str = ".m2psp-tmp";
+
+/// Writes `bytes` to `path` without ever exposing a half written file.
+///
+/// The data goes into a hidden sibling temp file first, gets fsynced and is then renamed
+/// over `path`. Since the temp file lives in the same directory the rename stays on the
+/// same filesystem, so it either happens completely or not at all.
+pub fn write_atomically(path: &Path, bytes: &[u8]) -> io::Result<()> {
+    let temp_path = temp_path_for(path);
+
+    let res = write_and_sync(&temp_path, bytes).and_then(|()| fs::rename(&temp_path, path));
+    if res.is_err() {
+        let _ = fs::remove_file(&temp_path);
+        return res;
+    }
+
+    // the rename itself lives in the directory entry, so flush that as well where we can
+    #[cfg(unix)]
+    if let Some(parent) = path.parent() {
+        if let Ok(dir) = File::open(parent) {
+            let _ = dir.sync_all();
+        }
+    }
+
+    Ok(())
+}
+
+fn write_and_sync(path: &Path, bytes: &[u8]) -> io::Result<()> {
+    let mut file = File::create(path)?;
+    file.write_all(bytes)?;
+    file.sync_all()
+}
+
+// Two writers of the same file, in this process or another one, each get a temp file of their own
+static NEXT_TEMP_FILE: AtomicUsize = AtomicUsize::new(0);
+
+pub fn temp_path_for(path: &Path) -> PathBuf {
+    let mut file_name = std::ffi::OsString::from(".");
+    file_name.push(path.file_name().unwrap_or_default());
+    file_name.push(format!(
+        ".{}-{}",
+        std::process::id(),
+        NEXT_TEMP_FILE.fetch_add(1, Ordering::Relaxed)
+    ));
+    file_name.push(TEMP_SUFFIX);
+    path.with_file_name(file_name)
+}
+
+/// Recursively removes temp files left behind by interrupted runs.
+///
+/// Returns how many files were deleted. Unreadable directories are skipped, this is a
+/// best-effort cleanup and should never stop a conversion from starting. The temp files of
+/// this process are still being written and are left alone.
+pub fn sweep_stale_temp_files(dir: &Path) -> usize {
+    let Ok(entries) = fs::read_dir(dir) else {
+        return 0;
+    };
+
+    let mut removed = 0;
+    for entry in entries.flatten() {
+        let path = entry.path();
+        let Ok(file_type) = entry.file_type() else {
+            continue;
+        };
+
+        if file_type.is_dir() {
+            removed += sweep_stale_temp_files(&path);
+        } else if file_type.is_file()
+            && path
+                .file_name()
+                .and_then(|name| name.to_str())
+                .is_some_and(is_stale_temp_file)
+        {
+            match fs::remove_file(&path) {
+                Ok(()) => removed += 1,
+                Err(e) => warn!("Could not remove stale temp file {:?}: {}", path, e),
+            }
+        }
+    }
+
+    removed
+}
+
+// ".01 - Track.mp3.<pid>-<n>.m2psp-tmp", from any process but this one
+fn is_stale_temp_file(name: &str) -> bool {
+    let Some(name) = name.strip_suffix(TEMP_SUFFIX) else {
+        return false;
+    };
+    let writer = name.rsplit('.').next().unwrap_or_default();
+    writer.split('-').next() != Some(std::process::id().to_string().as_str())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn scratch_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("m2psp-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn test_write_atomically_replaces_file() {
+        let dir = scratch_dir("atomic");
+        let target = dir.join("01 - Track.mp3");
+
+        fs::write(&target, b"old").unwrap();
+        write_atomically(&target, b"new contents").unwrap();
+
+        assert_eq!(fs::read(&target).unwrap(), b"new contents");
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 1);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_sweep_removes_only_temp_files() {
+        let dir = scratch_dir("sweep");
+        let album = dir.join("Album");
+        fs::create_dir_all(&album).unwrap();
+
+        let kept = album.join("01 - Track.mp3");
+        fs::write(&kept, b"mp3").unwrap();
+        // a run that crashed, and one that wasn't even this program's
+        fs::write(album.join(".02 - Track.mp3.1-0.m2psp-tmp"), b"trunc").unwrap();
+        fs::write(dir.join(".03 - Track.mp3.m2psp-tmp"), b"trunc").unwrap();
+        // still being written by this run
+        let writing = temp_path_for(&album.join("04 - Track.mp3"));
+        assert_ne!(writing, temp_path_for(&album.join("04 - Track.mp3")));
+        fs::write(&writing, b"half").unwrap();
+
+        assert_eq!(sweep_stale_temp_files(&dir), 2);
+        assert!(kept.exists());
+        assert!(writing.exists());
+        assert_eq!(fs::read_dir(&album).unwrap().count(), 2);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
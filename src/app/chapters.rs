@@ -0,0 +1,494 @@
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom};
+use std::path::Path;
+
+use crate::app::cue::{CueSheet, CueTime, CueTrack};
+
+// Nero chapter times count in units of 100 ns
+const CHPL_UNITS_PER_MILLI: u64 = 10_000;
+
+// more samples than any book has chapters, a count above that is a broken table
+const MAX_CHAPTER_SAMPLES: usize = 10_000;
+
+/// Reads the chapters of an M4B/M4A file, from the QuickTime chapter track iTunes and Audible
+/// write or from a Nero `chpl` list.
+pub fn mp4_chapters(path: &Path) -> Option<CueSheet> {
+    let mut file = File::open(path).ok()?;
+    let file_len = file.metadata().ok()?.len();
+
+    // audiobooks run into the hundreds of megabytes, only `moov` gets read into memory
+    let mut pos = 0;
+    while pos + 8 <= file_len {
+        file.seek(SeekFrom::Start(pos)).ok()?;
+        let mut header = [0u8; 16];
+        file.read_exact(&mut header[..8]).ok()?;
+        let (header_len, box_len) = match u32::from_be_bytes(header[..4].try_into().unwrap()) {
+            0 => (8, file_len - pos),
+            1 => {
+                file.read_exact(&mut header[8..]).ok()?;
+                (16, u64::from_be_bytes(header[8..].try_into().unwrap()))
+            }
+            len => (8, len as u64),
+        };
+        if box_len < header_len {
+            return None;
+        }
+        if &header[4..8] == b"moov" {
+            // a broken length mustn't have us allocate more than the file holds
+            if box_len > file_len - pos {
+                return None;
+            }
+            let mut moov = vec![0; (box_len - header_len) as usize];
+            file.read_exact(&mut moov).ok()?;
+            return quicktime_chapters(&mut file, &moov).or_else(|| {
+                let udta = find_box(&moov, b"udta")?;
+                parse_chpl(find_box(udta, b"chpl")?)
+            });
+        }
+        pos += box_len;
+    }
+    None
+}
+
+/// Reads the CHAP frames of the ID3v2 tag at the start of an MP3, as podcasts carry them.
+pub fn id3_chapters(path: &Path) -> Option<CueSheet> {
+    let mut file = File::open(path).ok()?;
+    let mut header = [0u8; 10];
+    file.read_exact(&mut header).ok()?;
+    if &header[..3] != b"ID3" {
+        return None;
+    }
+    let mut tag = vec![0; synchsafe(&header[6..10]) as usize];
+    file.read_exact(&mut tag).ok()?;
+    parse_id3_chapters(header[3], header[5], &tag)
+}
+
+/// Cuts a long file without chapters into parts of `segment_secs`.
+pub fn fixed_segments(total_samples: usize, sample_rate: u32, segment_secs: u64) -> CueSheet {
+    let segment_samples = (segment_secs * sample_rate as u64).max(1);
+    let count = (total_samples as u64).div_ceil(segment_samples).max(1);
+    let tracks = (0..count)
+        .map(|i| CueTrack {
+            number: i as u32 + 1,
+            title: format!("Part {}", i + 1),
+            performer: String::new(),
+            start: CueTime::Samples(i * segment_samples),
+        })
+        .collect();
+
+    CueSheet {
+        tracks,
+        ..Default::default()
+    }
+}
+
+// (type, body) of the boxes in `parent`, up to the first broken one
+fn boxes(parent: &[u8]) -> impl Iterator<Item = (&[u8], &[u8])> {
+    let mut pos = 0;
+    std::iter::from_fn(move || {
+        let header = parent.get(pos..pos + 8)?;
+        let len = be_u32(&header[..4]) as usize;
+        let len = if len == 0 { parent.len() - pos } else { len };
+        let body = parent.get(pos + 8..pos.checked_add(len)?)?;
+        pos += len;
+        Some((&header[4..], body))
+    })
+}
+
+// body of the first child box of type `box_type` in `parent`
+fn find_box<'a>(parent: &'a [u8], box_type: &[u8; 4]) -> Option<&'a [u8]> {
+    boxes(parent)
+        .find(|(found, _)| found == box_type)
+        .map(|(_, body)| body)
+}
+
+fn be_u32(bytes: &[u8]) -> u32 {
+    u32::from_be_bytes(bytes.try_into().unwrap())
+}
+
+// The audio track points at a text track with `tref/chap`, each sample of which is one chapter:
+// its title and, through the sample durations, where it starts.
+fn quicktime_chapters(file: &mut File, moov: &[u8]) -> Option<CueSheet> {
+    let traks: Vec<&[u8]> = boxes(moov)
+        .filter(|(box_type, _)| box_type == b"trak")
+        .map(|(_, body)| body)
+        .collect();
+    let chapter_ids: Vec<u32> = traks
+        .iter()
+        .find_map(|trak| find_box(find_box(trak, b"tref")?, b"chap"))?
+        .chunks_exact(4)
+        .map(be_u32)
+        .collect();
+    let chapter_trak = traks
+        .iter()
+        .find(|trak| track_id(trak).is_some_and(|id| chapter_ids.contains(&id)))?;
+
+    let mdia = find_box(chapter_trak, b"mdia")?;
+    let timescale = full_box_field(find_box(mdia, b"mdhd")?, 12, 20).filter(|&t| t > 0)? as u64;
+    let stbl = find_box(find_box(mdia, b"minf")?, b"stbl")?;
+    let samples = sample_locations(stbl)?;
+
+    let mut durations = Vec::new();
+    for entry in table(find_box(stbl, b"stts")?, 8)?.chunks_exact(8) {
+        let count = be_u32(&entry[..4]) as usize;
+        if durations.len() + count > MAX_CHAPTER_SAMPLES {
+            return None;
+        }
+        durations.extend(std::iter::repeat(be_u32(&entry[4..]) as u64).take(count));
+    }
+
+    let mut start = 0;
+    let mut tracks = Vec::with_capacity(samples.len());
+    for (number, ((offset, size), duration)) in (1..).zip(samples.into_iter().zip(durations)) {
+        let title = read_text_sample(file, offset, size).unwrap_or_default();
+        tracks.push(chapter(number, title, start * 1000 / timescale));
+        start += duration;
+    }
+    sheet_of(tracks)
+}
+
+fn track_id(trak: &[u8]) -> Option<u32> {
+    full_box_field(find_box(trak, b"tkhd")?, 12, 20)
+}
+
+// a u32 of a box that has 64 bit times from version 1 on, which moves what follows them
+fn full_box_field(body: &[u8], v0_offset: usize, v1_offset: usize) -> Option<u32> {
+    let offset = if *body.first()? == 1 {
+        v1_offset
+    } else {
+        v0_offset
+    };
+    body.get(offset..offset + 4).map(be_u32)
+}
+
+// the entries of a sample table, which all start with version, flags and the entry count
+fn table(body: &[u8], entry_len: usize) -> Option<&[u8]> {
+    let count = be_u32(body.get(4..8)?) as usize;
+    body.get(8..8 + count.checked_mul(entry_len)?)
+}
+
+// (file offset, size) of every sample, found chunk by chunk
+fn sample_locations(stbl: &[u8]) -> Option<Vec<(u64, u32)>> {
+    let stsz = find_box(stbl, b"stsz")?;
+    let fixed_size = be_u32(stsz.get(4..8)?);
+    let count = be_u32(stsz.get(8..12)?) as usize;
+    if count > MAX_CHAPTER_SAMPLES {
+        return None;
+    }
+    let mut sizes: Vec<u32> = if fixed_size != 0 {
+        vec![fixed_size; count]
+    } else {
+        stsz.get(12..12 + 4 * count)?
+            .chunks_exact(4)
+            .map(be_u32)
+            .collect()
+    };
+    sizes.reverse();
+
+    let chunk_offsets: Vec<u64> = match find_box(stbl, b"stco") {
+        Some(stco) => table(stco, 4)?
+            .chunks_exact(4)
+            .map(|o| be_u32(o) as u64)
+            .collect(),
+        None => table(find_box(stbl, b"co64")?, 8)?
+            .chunks_exact(8)
+            .map(|o| u64::from_be_bytes(o.try_into().unwrap()))
+            .collect(),
+    };
+    // (first chunk, samples per chunk), each run lasting until the next one's first chunk
+    let runs: Vec<(u32, u32)> = table(find_box(stbl, b"stsc")?, 12)?
+        .chunks_exact(12)
+        .map(|run| (be_u32(&run[..4]), be_u32(&run[4..8])))
+        .collect();
+
+    let mut samples = Vec::with_capacity(count);
+    for (chunk, mut offset) in (1..).zip(chunk_offsets) {
+        let per_chunk = runs
+            .iter()
+            .rev()
+            .find(|(first_chunk, _)| *first_chunk <= chunk)
+            .map_or(0, |(_, per_chunk)| *per_chunk);
+        for _ in 0..per_chunk {
+            let Some(size) = sizes.pop() else {
+                return Some(samples);
+            };
+            samples.push((offset, size));
+            offset += size as u64;
+        }
+    }
+    Some(samples)
+}
+
+// a text sample is the length of the text and the text, UTF-8 or UTF-16 with a byte order mark
+fn read_text_sample(file: &mut File, offset: u64, size: u32) -> Option<String> {
+    let mut sample = vec![0; size.min(u16::MAX as u32 + 2) as usize];
+    file.seek(SeekFrom::Start(offset)).ok()?;
+    file.read_exact(&mut sample).ok()?;
+    let len = u16::from_be_bytes(sample.get(..2)?.try_into().unwrap()) as usize;
+    let text = sample.get(2..2 + len)?;
+    Some(match text {
+        [0xFE, 0xFF, ..] | [0xFF, 0xFE, ..] => decode_text_frame(&[&[1], text].concat()),
+        _ => String::from_utf8_lossy(text).to_string(),
+    })
+}
+
+fn parse_chpl(chpl: &[u8]) -> Option<CueSheet> {
+    // version 1 has 4 more bytes nobody knows the meaning of in front of the count
+    let mut pos = if *chpl.first()? == 0 { 4 } else { 8 };
+    let count = *chpl.get(pos)?;
+    pos += 1;
+
+    let mut tracks = Vec::with_capacity(count as usize);
+    for number in 1..=count as u32 {
+        let start = u64::from_be_bytes(chpl.get(pos..pos + 8)?.try_into().unwrap());
+        let title_len = *chpl.get(pos + 8)? as usize;
+        let title = chpl.get(pos + 9..pos + 9 + title_len)?;
+        pos += 9 + title_len;
+        tracks.push(chapter(
+            number,
+            String::from_utf8_lossy(title).to_string(),
+            start / CHPL_UNITS_PER_MILLI,
+        ));
+    }
+    sheet_of(tracks)
+}
+
+fn parse_id3_chapters(version: u8, flags: u8, tag: &[u8]) -> Option<CueSheet> {
+    // v2.2 has three letter frame ids and no chapters anyway
+    if version < 3 {
+        return None;
+    }
+    let mut pos = 0;
+    if flags & 0x40 != 0 {
+        // the extended header counts itself in v2.4, but not in v2.3
+        pos = match version {
+            3 => 4 + u32::from_be_bytes(tag.get(..4)?.try_into().unwrap()) as usize,
+            _ => synchsafe(tag.get(..4)?) as usize,
+        };
+    }
+
+    let mut chapters: Vec<(u64, String)> = Vec::new();
+    for (frame_id, body) in frames(version, tag.get(pos..)?) {
+        if frame_id != b"CHAP" {
+            continue;
+        }
+        // element id, then start and end in milliseconds and byte offsets nobody uses
+        let Some(id_end) = body.iter().position(|&b| b == 0) else {
+            continue;
+        };
+        let Some(times) = body.get(id_end + 1..id_end + 17) else {
+            continue;
+        };
+        let start_ms = u32::from_be_bytes(times[..4].try_into().unwrap()) as u64;
+        let title = frames(version, &body[id_end + 17..])
+            .find(|(frame_id, _)| frame_id == b"TIT2")
+            .map(|(_, body)| decode_text_frame(body))
+            .unwrap_or_default();
+        chapters.push((start_ms, title));
+    }
+    // the frames can come in any order, CTOC says which but the start times do as well
+    chapters.sort_by_key(|(start_ms, _)| *start_ms);
+
+    let tracks = chapters
+        .into_iter()
+        .zip(1..)
+        .map(|((start_ms, title), number)| chapter(number, title, start_ms))
+        .collect();
+    sheet_of(tracks)
+}
+
+// (frame id, body) of the frames in `bytes`, up to the padding
+fn frames(version: u8, bytes: &[u8]) -> impl Iterator<Item = (&[u8], &[u8])> {
+    let mut pos = 0;
+    std::iter::from_fn(move || {
+        let header = bytes.get(pos..pos + 10)?;
+        if header[0] == 0 {
+            return None;
+        }
+        let len = match version {
+            3 => u32::from_be_bytes(header[4..8].try_into().unwrap()),
+            _ => synchsafe(&header[4..8]),
+        } as usize;
+        let body = bytes.get(pos + 10..pos + 10 + len)?;
+        pos += 10 + len;
+        Some((&header[..4], body))
+    })
+}
+
+fn decode_text_frame(body: &[u8]) -> String {
+    let Some((&encoding, text)) = body.split_first() else {
+        return String::new();
+    };
+    let utf16 = |text: &[u8], big_endian: bool| {
+        let units: Vec<u16> = text
+            .chunks_exact(2)
+            .map(|pair| {
+                let pair = [pair[0], pair[1]];
+                if big_endian {
+                    u16::from_be_bytes(pair)
+                } else {
+                    u16::from_le_bytes(pair)
+                }
+            })
+            .collect();
+        String::from_utf16_lossy(&units)
+    };
+    let text = match encoding {
+        0 => text.iter().map(|&b| b as char).collect(),
+        1 => match text {
+            [0xFE, 0xFF, rest @ ..] => utf16(rest, true),
+            [0xFF, 0xFE, rest @ ..] => utf16(rest, false),
+            _ => utf16(text, false),
+        },
+        2 => utf16(text, true),
+        _ => String::from_utf8_lossy(text).to_string(),
+    };
+    text.trim_end_matches('\0').to_string()
+}
+
+fn chapter(number: u32, title: String, start_ms: u64) -> CueTrack {
+    CueTrack {
+        number,
+        title: if title.trim().is_empty() {
+            format!("Chapter {}", number)
+        } else {
+            title.trim().to_string()
+        },
+        performer: String::new(),
+        start: CueTime::Millis(start_ms),
+    }
+}
+
+// a single chapter is no reason to split anything
+fn sheet_of(tracks: Vec<CueTrack>) -> Option<CueSheet> {
+    (tracks.len() > 1).then(|| CueSheet {
+        tracks,
+        ..Default::default()
+    })
+}
+
+fn synchsafe(bytes: &[u8]) -> u32 {
+    bytes
+        .iter()
+        .fold(0, |size, &byte| (size << 7) | (byte & 0x7F) as u32)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mp4_box(box_type: &[u8; 4], body: &[u8]) -> Vec<u8> {
+        let mut bytes = ((body.len() + 8) as u32).to_be_bytes().to_vec();
+        bytes.extend_from_slice(box_type);
+        bytes.extend_from_slice(body);
+        bytes
+    }
+
+    fn id3_frame(frame_id: &[u8; 4], body: &[u8]) -> Vec<u8> {
+        let mut bytes = frame_id.to_vec();
+        bytes.extend_from_slice(&(body.len() as u32).to_be_bytes());
+        bytes.extend_from_slice(&[0, 0]);
+        bytes.extend_from_slice(body);
+        bytes
+    }
+
+    #[test]
+    fn test_read_chapters() {
+        let mut chpl = vec![1, 0, 0, 0, 0, 0, 0, 0, 2];
+        for (start_secs, title) in [(0u64, "Opening Credits"), (95, "")] {
+            chpl.extend_from_slice(&(start_secs * 1000 * CHPL_UNITS_PER_MILLI).to_be_bytes());
+            chpl.push(title.len() as u8);
+            chpl.extend_from_slice(title.as_bytes());
+        }
+        let mut file = mp4_box(b"ftyp", b"M4B \0\0\0\0");
+        file.extend(mp4_box(
+            b"moov",
+            &mp4_box(b"udta", &mp4_box(b"chpl", &chpl)),
+        ));
+        file.extend(mp4_box(b"mdat", &[0; 64]));
+
+        let path = std::env::temp_dir().join(format!("m2psp-chapters-{}.m4b", std::process::id()));
+        std::fs::write(&path, &file).unwrap();
+        let sheet = mp4_chapters(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(sheet.tracks.len(), 2);
+        assert_eq!(sheet.tracks[0].title, "Opening Credits");
+        assert_eq!(sheet.tracks[1].title, "Chapter 2");
+        assert_eq!(sheet.tracks[1].start, CueTime::Millis(95_000));
+
+        // podcast chapters, stored out of order
+        let chap = |id: &[u8], start_ms: u32, title: &str| {
+            let mut body = id.to_vec();
+            body.push(0);
+            body.extend_from_slice(&start_ms.to_be_bytes());
+            body.extend_from_slice(&[0; 4]);
+            body.extend_from_slice(&[0xFF; 8]);
+            body.extend(id3_frame(b"TIT2", &[&[3], title.as_bytes()].concat()));
+            id3_frame(b"CHAP", &body)
+        };
+        let mut tag = id3_frame(b"TIT2", b"\0Episode 12");
+        tag.extend(chap(b"chp1", 600_000, "News"));
+        tag.extend(chap(b"chp0", 0, "Intro"));
+        tag.resize(tag.len() + 32, 0);
+
+        let sheet = parse_id3_chapters(3, 0, &tag).unwrap();
+        let titles: Vec<&str> = sheet.tracks.iter().map(|t| t.title.as_str()).collect();
+        assert_eq!(titles, ["Intro", "News"]);
+        assert_eq!(sheet.tracks[1].number, 2);
+        assert_eq!(sheet.tracks[1].start, CueTime::Millis(600_000));
+
+        let sheet = fixed_segments(25 * 60 * 1000, 1000, 10 * 60);
+        assert_eq!(sheet.tracks.len(), 3);
+        assert_eq!(sheet.tracks[2].title, "Part 3");
+    }
+
+    #[test]
+    fn test_read_chapter_track() {
+        let full_box = |box_type: &[u8; 4], fields: &[u32]| {
+            let body: Vec<u8> = fields
+                .iter()
+                .flat_map(|field| field.to_be_bytes())
+                .collect();
+            mp4_box(box_type, &body)
+        };
+        let tkhd = |id| full_box(b"tkhd", &[0, 0, 0, id, 0]);
+        let moov = |samples_offset: u32| {
+            let mut audio = tkhd(1);
+            audio.extend(mp4_box(b"tref", &full_box(b"chap", &[2])));
+            let mut stbl = full_box(b"stts", &[0, 2, 1, 95_000, 1, 5_000]);
+            stbl.extend(full_box(b"stsz", &[0, 0, 2, 13, 8]));
+            stbl.extend(full_box(b"stsc", &[0, 1, 1, 2, 1]));
+            stbl.extend(full_box(b"stco", &[0, 1, samples_offset]));
+            let mut mdia = full_box(b"mdhd", &[0, 0, 0, 1000, 100_000]);
+            mdia.extend(mp4_box(b"minf", &mp4_box(b"stbl", &stbl)));
+            let mut text = tkhd(2);
+            text.extend(mp4_box(b"mdia", &mdia));
+
+            let mut moov = mp4_box(b"trak", &audio);
+            moov.extend(mp4_box(b"trak", &text));
+            mp4_box(b"moov", &moov)
+        };
+        let mut samples = b"\0\x0bOpening Day".to_vec();
+        samples.extend_from_slice(b"\0\x06\xfe\xff\0E\0x");
+
+        let mut file = mp4_box(b"ftyp", b"M4B \0\0\0\0");
+        let samples_offset = file.len() + moov(0).len() + 8;
+        file.extend(moov(samples_offset as u32));
+        file.extend(mp4_box(b"mdat", &samples));
+
+        let path =
+            std::env::temp_dir().join(format!("m2psp-chap-track-{}.m4b", std::process::id()));
+        std::fs::write(&path, &file).unwrap();
+        let sheet = mp4_chapters(&path).unwrap();
+
+        // a moov claiming to run past the end of the file
+        file.truncate(samples_offset - 8 - 100);
+        std::fs::write(&path, &file).unwrap();
+        assert!(mp4_chapters(&path).is_none());
+        std::fs::remove_file(&path).unwrap();
+
+        let titles: Vec<&str> = sheet.tracks.iter().map(|t| t.title.as_str()).collect();
+        assert_eq!(titles, ["Opening Day", "Ex"]);
+        assert_eq!(sheet.tracks[1].start, CueTime::Millis(95_000));
+    }
+}
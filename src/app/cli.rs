@@ -0,0 +1,153 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::Ordering;
+
+use log::warn;
+
+use crate::app::dry_run::PlanAction;
+use crate::app::job::{excluded_files, included_files, Job};
+use crate::app::planner::format_bytes;
+use crate::app::profiles::{all_profiles, find_profile, install_profile, Profile};
+use crate::app::sources::collect_sources;
+use crate::app::thread_handler::ThreadHandler;
+
+pub const USAGE: &str = "\
+usage: m2psp [--profile NAME]
+       m2psp sync JOB_FILE [--dry-run [--json]]
+       m2psp profiles
+       m2psp profiles show NAME
+       m2psp profiles import FILE
+       m2psp profiles export NAME FILE";
+
+/// Runs a command given on the command line, for everything that doesn't need the window.
+pub fn run(args: &[String]) -> Result<(), String> {
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+    match args.as_slice() {
+        ["sync", file] => sync(Path::new(file)),
+        ["sync", file, "--dry-run"] => dry_run(Path::new(file), false),
+        ["sync", file, "--dry-run", "--json"] => dry_run(Path::new(file), true),
+        ["profiles"] | ["profiles", "list"] => {
+            for profile in all_profiles() {
+                println!("{}", profile.name);
+            }
+            Ok(())
+        }
+        ["profiles", "show", name] => {
+            print!("{}", profile_named(name)?.to_toml());
+            Ok(())
+        }
+        ["profiles", "import", file] => {
+            let profile = Profile::import(Path::new(file))
+                .map_err(|e| format!("can't import {}: {}", file, e))?;
+            let path = install_profile(&profile)
+                .map_err(|e| format!("can't keep profile {:?}: {}", profile.name, e))?;
+            println!("Imported {:?} into {:?}", profile.name, path);
+            Ok(())
+        }
+        ["profiles", "export", name, file] => profile_named(name)?
+            .export(Path::new(file))
+            .map_err(|e| format!("can't export to {}: {}", file, e)),
+        ["help" | "--help" | "-h"] => {
+            println!("{}", USAGE);
+            Ok(())
+        }
+        _ => Err(USAGE.to_string()),
+    }
+}
+
+// converts what the job file lists and waits until it's done
+fn sync(path: &Path) -> Result<(), String> {
+    let (mut thread_handler, _) = load_job(path)?;
+    let destination = &thread_handler.destination;
+    fs::create_dir_all(destination)
+        .map_err(|e| format!("can't create {}: {}", destination.display(), e))?;
+    let report = thread_handler
+        .execute_threads()
+        .join()
+        .map_err(|_| "the conversion stopped halfway through".to_string())?;
+
+    println!(
+        "Synced {} file/s, {} written",
+        thread_handler.num_finished.load(Ordering::Relaxed),
+        format_bytes(thread_handler.bytes_written.load(Ordering::Relaxed))
+    );
+    // scripts and schedulers only see the exit status
+    match report.failures() {
+        0 => Ok(()),
+        failures => Err(format!(
+            "{} track/s failed, the report has the details",
+            failures
+        )),
+    }
+}
+
+// what `sync` would do, without touching the destination
+fn dry_run(path: &Path, json: bool) -> Result<(), String> {
+    let (thread_handler, excluded) = load_job(path)?;
+    let mut plan = thread_handler.plan();
+    for file in excluded {
+        plan.skip(file, "excluded by the job");
+    }
+
+    if json {
+        println!("{}", plan.to_json());
+        return Ok(());
+    }
+    for entry in &plan.entries {
+        let target = match (&entry.output, &entry.reason) {
+            (Some(output), _) => output.strip_prefix(&plan.destination).unwrap_or(output),
+            (None, Some(reason)) => Path::new(reason),
+            (None, None) => Path::new(""),
+        };
+        let size = match entry.action {
+            PlanAction::Convert | PlanAction::Copy => format_bytes(entry.estimated_bytes),
+            PlanAction::Skip | PlanAction::Fail => String::new(),
+        };
+        println!(
+            "{:<8} {:>10}  {}  ->  {}",
+            format!("{:?}", entry.action).to_lowercase(),
+            size,
+            entry.source.display(),
+            target.display()
+        );
+    }
+    println!("{}", plan.summary());
+    Ok(())
+}
+
+// a thread handler set up for the job, and the files its exclude patterns leave out
+fn load_job(path: &Path) -> Result<(ThreadHandler, Vec<PathBuf>), String> {
+    let job = Job::load(path).map_err(|e| format!("can't read job {}: {}", path.display(), e))?;
+    let (encoder_settings, playlist_options) = job.resolve()?;
+    if job.destination.as_os_str().is_empty() {
+        return Err(format!("{} has no destination", path.display()));
+    }
+
+    let mut entries = Vec::new();
+    for root in &job.sources {
+        match root.entry() {
+            Some(entry) => entries.push(entry),
+            None => warn!(
+                "Skipping {:?}, it's gone or not something to convert",
+                root.path
+            ),
+        }
+    }
+    let (files, source_playlists) = collect_sources(&entries);
+    let excluded = excluded_files(&job.sources, &files);
+    let files = included_files(&job.sources, files);
+
+    let mut thread_handler = ThreadHandler::new();
+    thread_handler.destination = job.destination;
+    thread_handler.report_dir = job.report_dir;
+    thread_handler.encoder_settings = encoder_settings;
+    thread_handler.playlist_options = playlist_options;
+    thread_handler.source_roots = job.sources;
+    thread_handler.source_playlists = source_playlists;
+    thread_handler.add_files(files);
+    Ok((thread_handler, excluded))
+}
+
+fn profile_named(name: &str) -> Result<Profile, String> {
+    find_profile(name).ok_or_else(|| format!("no profile called {:?}, see `m2psp profiles`", name))
+}
@@ -1,29 +1,52 @@
 use egui::ahash::HashMap;
 use glob::glob;
 use image::imageops::FilterType;
-use image::{ImageFormat, ImageReader};
-use mp3lame_encoder::*;
+use image::{DynamicImage, ImageError, ImageFormat, ImageReader};
+use log::{debug, error, info, warn};
+use mp3lame_encoder::Bitrate;
+use rayon::prelude::*;
 use std::borrow::Cow;
 use std::default::Default;
 use std::fmt::Formatter;
 use std::fs::File;
-use std::io::{Cursor, Write};
-use std::path::PathBuf;
-use std::{fmt, fs, thread};
+use std::io::Cursor;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Instant;
+use std::{fmt, fs};
 use symphonia::core::audio::{AudioBuffer, AudioBufferRef, Signal};
-use symphonia::core::codecs::{DecoderOptions, CODEC_TYPE_NULL};
+use symphonia::core::codecs::{
+    CodecType, Decoder, DecoderOptions, CODEC_TYPE_AAC, CODEC_TYPE_MP1, CODEC_TYPE_MP2,
+    CODEC_TYPE_MP3, CODEC_TYPE_NULL, CODEC_TYPE_OPUS, CODEC_TYPE_VORBIS,
+};
 use symphonia::core::conv::IntoSample;
 use symphonia::core::errors::Error;
-use symphonia::core::formats::FormatOptions;
+use symphonia::core::formats::{FormatOptions, FormatReader};
 use symphonia::core::io::MediaSourceStream;
-use symphonia::core::meta::{Metadata, MetadataOptions, StandardTagKey};
+use symphonia::core::meta::{Metadata, MetadataOptions, MetadataRevision, StandardTagKey};
 use symphonia::core::probe::Hint;
-use symphonia::core::sample::{u24, Sample};
+use symphonia::core::sample::Sample;
+
+use crate::app::atomic_write::write_atomically;
+use crate::app::chapters::{fixed_segments, id3_chapters, mp4_chapters};
+use crate::app::cue::{CueSheet, CueTrack};
+use crate::app::dry_run::OutputOwners;
+use crate::app::id3::Id3v2Tag;
+use crate::app::lame::LameSession;
+use crate::app::loudness::{
+    apply_gain, db_to_linear, peak_after_gain, scale, GainMode, Loudness, PeakLevels,
+};
+use crate::app::lyrics::Lyrics;
+use crate::app::passthrough::Mp3Stream;
+use crate::app::path_template::{render_path, DEFAULT_PATH_TEMPLATE};
+use crate::app::planner::estimate_track_bytes;
+use crate::app::playlist::leading_number;
+use crate::app::silence::SilenceTrim;
 
 // TODO a hashset thingy maybe that will store the images
 // so that I don't have to regenerate the images continuously
 #[derive(serde::Deserialize, serde::Serialize, Debug)]
-
+#[allow(dead_code)]
 struct AlbumArtCache {
     album_art: HashMap<u64, Vec<u8>>,
 }
@@ -34,10 +57,13 @@ impl Default for AlbumArtCache {
     }
 }
 
+#[allow(clippy::upper_case_acronyms)]
 pub enum AudioFiletype {
     MP3,
     FLAC,
     OGG,
+    // M4A music and M4B audiobooks
+    MP4,
 
 }
 pub struct AudioConverter {
@@ -45,18 +71,462 @@ pub struct AudioConverter {
     to_type: AudioFiletype,
     src_path: PathBuf,
     output_based_on_metadata: bool,
+    encoder_settings: EncoderSettings,
+    tag_overrides: TagOverrides,
+    // settles who writes a file several tracks would end up in
+    output_owners: Arc<OutputOwners>,
+}
+
+// Re-encoding any of these into MP3 stacks the artifacts of two lossy codecs
+const LOSSY_CODECS: [CodecType; 6] = [
+    CODEC_TYPE_MP1,
+    CODEC_TYPE_MP2,
+    CODEC_TYPE_MP3,
+    CODEC_TYPE_AAC,
+    CODEC_TYPE_VORBIS,
+    CODEC_TYPE_OPUS,
+];
+
+// Constant bitrates LAME accepts that the PSP is also able to play back
+pub const SUPPORTED_BITRATES: [u16; 12] = [32, 48, 64, 80, 96, 112, 128, 160, 192, 224, 256, 320];
+
+#[derive(Clone, Debug, PartialEq, serde::Deserialize, serde::Serialize)]
+#[serde(default)]
+pub struct EncoderSettings {
+    pub bitrate_kbps: u16,
+    // encode every album in one go, so its tracks flow into each other without a gap
+    pub gapless_albums: bool,
+    pub gain_mode: GainMode,
+    pub replaygain_tags: bool,
+    pub prevent_clipping: bool,
+    pub silence_trim: SilenceTrim,
+    pub audiobook: AudiobookSettings,
+    // put the source's .lrc next to the MP3, for players that read those
+    pub copy_lrc: bool,
+    // resample to this rate, the source's rate is kept when unset
+    pub sample_rate: Option<u32>,
+    // covers get scaled down to fit into a square of this size
+    pub cover_max_px: u32,
+    // keep file names to what FAT32 allows, not just what the OS writing them does
+    pub fat_safe_names: bool,
+    // where a track goes under the destination, see `render_path`
+    pub path_template: String,
+}
+
+impl Default for EncoderSettings {
+    fn default() -> Self {
+        Self {
+            bitrate_kbps: 192,
+            gapless_albums: false,
+            gain_mode: GainMode::Off,
+            replaygain_tags: false,
+            prevent_clipping: false,
+            silence_trim: SilenceTrim::default(),
+            audiobook: AudiobookSettings::default(),
+            copy_lrc: false,
+            sample_rate: None,
+            cover_max_px: 500,
+            fat_safe_names: true,
+            path_template: DEFAULT_PATH_TEMPLATE.to_string(),
+        }
+    }
+}
+
+/// Where audiobook mode takes the bitrate down to, plenty for speech in mono.
+pub const AUDIOBOOK_BITRATE_KBPS: u16 = 64;
+
+/// Long books and podcast episodes, split into chapters and encoded in mono.
+#[derive(Clone, Copy, Debug, PartialEq, serde::Deserialize, serde::Serialize)]
+#[serde(default)]
+pub struct AudiobookSettings {
+    pub enabled: bool,
+    // how long the parts of a file without chapter markers get
+    pub segment_minutes: u32,
+}
+
+impl Default for AudiobookSettings {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            segment_minutes: 10,
+        }
+    }
+}
+
+impl EncoderSettings {
+    /// The bitrate encoding actually uses, audiobook mode caps it however the settings came about.
+    pub fn effective_bitrate_kbps(&self) -> u16 {
+        if self.audiobook.enabled {
+            self.bitrate_kbps.min(AUDIOBOOK_BITRATE_KBPS)
+        } else {
+            self.bitrate_kbps
+        }
+    }
+
+    fn bitrate(&self) -> Bitrate {
+        match self.effective_bitrate_kbps() {
+            0..=32 => Bitrate::Kbps32,
+            33..=48 => Bitrate::Kbps48,
+            49..=64 => Bitrate::Kbps64,
+            65..=80 => Bitrate::Kbps80,
+            81..=96 => Bitrate::Kbps96,
+            97..=112 => Bitrate::Kbps112,
+            113..=128 => Bitrate::Kbps128,
+            129..=160 => Bitrate::Kbps160,
+            161..=192 => Bitrate::Kbps192,
+            193..=224 => Bitrate::Kbps224,
+            225..=256 => Bitrate::Kbps256,
+            _ => Bitrate::Kbps320,
+        }
+    }
+}
+
+/// A file a conversion is going to write.
+#[derive(Clone, Debug)]
+pub struct PlannedOutput {
+    pub output_path: PathBuf,
+    // the source gets copied, not re-encoded
+    pub passthrough: bool,
+    pub estimated_bytes: u64,
+}
+
+/// What became of one input: one of its outputs, or the error it failed with and the seconds
+/// spent on it until then.
+pub type ConversionResult = Result<ConversionOutput, (PathBuf, String, f64)>;
+
+pub struct ConversionOutput {
+    pub source_path: PathBuf,
+    pub output_path: PathBuf,
+    pub bytes_written: u64,
+    // the MP3 frames alone, without the tag, cover and lyrics in front of them
+    pub audio_bytes: u64,
+    pub duration_secs: f64,
+    // decoding its share of the source, encoding and writing it
+    pub elapsed_secs: f64,
+    pub title: String,
+    pub artist: String,
+    pub album: String,
+    pub track_number: String,
+    pub disc_number: String,
+    // levels of the source, before any attenuation
+    pub levels: PeakLevels,
+    pub attenuation_db: f64,
+    // copied over without re-encoding, only the tags were rewritten
+    pub passthrough: bool,
+    // the source was lossy already, so this is a copy of a copy
+    pub lossy_transcode: bool,
+}
+
+impl ConversionOutput {
+    fn new(
+        source_path: PathBuf,
+        output_path: PathBuf,
+        bytes_written: u64,
+        duration_secs: f64,
+        track_metadata: &TrackMetadata,
+    ) -> Self {
+        Self {
+            source_path,
+            output_path,
+            bytes_written,
+            audio_bytes: 0,
+            duration_secs,
+            elapsed_secs: 0.0,
+            title: track_metadata.title.clone(),
+            artist: track_metadata.artist.join(", "),
+            album: track_metadata.album.clone(),
+            track_number: track_metadata.track_number.clone(),
+            disc_number: track_metadata.disc_number.clone(),
+            levels: PeakLevels::default(),
+            attenuation_db: 0.0,
+            passthrough: false,
+            lossy_transcode: false,
+        }
+    }
+
+    fn encoded(
+        source_path: PathBuf,
+        output_path: PathBuf,
+        bytes_written: u64,
+        audio_bytes: u64,
+        track: &DecodedTrack,
+        elapsed_secs: f64,
+    ) -> Self {
+        Self {
+            audio_bytes,
+            elapsed_secs,
+            levels: track.levels,
+            attenuation_db: track.attenuation_db,
+            lossy_transcode: track.track_metadata.lossy_source,
+            ..ConversionOutput::new(
+                source_path,
+                output_path,
+                bytes_written,
+                track.duration_secs(),
+                &track.track_metadata,
+            )
+        }
+    }
+}
+
+/// Tag changes made in the editor. They only go into the output, sources are never written to.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct TagOverrides {
+    pub title: Option<String>,
+    pub track_number: Option<String>,
+    pub album: Option<String>,
+    pub album_artist: Option<String>,
+    pub year: Option<String>,
+    pub genre: Option<String>,
+    // an image file that replaces the cover
+    pub cover: Option<PathBuf>,
+}
+
+/// The tags of a source as the converter reads them, for showing in the editor.
+#[derive(Clone, Debug, Default)]
+pub struct TrackTags {
+    pub title: String,
+    pub track_number: String,
+    pub disc_number: String,
+    pub artist: String,
+    pub album: String,
+    pub album_artist: String,
+    pub year: String,
+    pub genre: String,
+    pub has_cover: bool,
 }
 
+/// What can be learned about a file from its headers alone, without decoding any audio.
+#[derive(Clone, Debug)]
+pub struct ProbedTrack {
+    pub album: String,
+    // the album artist, or the track artist when there is none
+    pub artist: String,
+    pub duration_secs: Option<f64>,
+    pub sample_rate: Option<u32>,
+    // short name of the codec, None when there is no decoder for it
+    pub codec: Option<&'static str>,
+}
+
+#[derive(Clone, Default)]
 struct TrackMetadata {
     title: String,
     track_number: String,
+    disc_number: String,
     artist: Vec<String>,
     album: String,
+    album_artist: String,
     album_art: Box<[u8]>,
     year: String,
+    genre: String,
     comment: String,
     sample_rate: u32,
+    lossy_source: bool,
+    // set when the file is a whole album rip that has to be split into tracks
+    cue_sheet: Option<CueSheet>,
+    replay_gain: Option<ReplayGainTags>,
+    lyrics: Option<Lyrics>,
+}
+
+// The REPLAYGAIN_* tags, relative to the audio as it ends up in the MP3
+#[derive(Clone, Copy, Debug)]
+struct ReplayGainTags {
+    track_gain_db: f64,
+    track_peak: f32,
+    album_gain_db: f64,
+    album_peak: f32,
+}
+
+impl ReplayGainTags {
+    // `gain_db` is what was applied to the track already, the tags only carry what's left
+    fn new(
+        track: &Loudness,
+        album: &Loudness,
+        gain_db: f64,
+        track_peak: f32,
+        album_peak: f32,
+    ) -> Self {
+        Self {
+            track_gain_db: track.gain_db() - gain_db,
+            track_peak,
+            album_gain_db: album.gain_db() - gain_db,
+            album_peak,
+        }
+    }
+}
+
+impl TrackMetadata {
+    fn for_cue_track(&self, cue_sheet: &CueSheet, cue_track: &CueTrack) -> TrackMetadata {
+        let mut track_metadata = self.clone();
+        track_metadata.cue_sheet = None;
+        // whatever lyrics came with the rip are for all of it
+        track_metadata.lyrics = None;
+        track_metadata.track_number = cue_track.number.to_string();
+
+        if !cue_track.title.is_empty() {
+            track_metadata.title = cue_track.title.clone();
+        }
+        if !cue_track.performer.is_empty() {
+            track_metadata.artist = vec![cue_track.performer.clone()];
+        } else if track_metadata.artist.is_empty() && !cue_sheet.performer.is_empty() {
+            track_metadata.artist = vec![cue_sheet.performer.clone()];
+        }
+        if track_metadata.album.is_empty() {
+            track_metadata.album = cue_sheet.title.clone();
+        }
+        if track_metadata.year.is_empty() {
+            track_metadata.year = cue_sheet.year.clone();
+        }
+
+        track_metadata
+    }
+
+    fn id3_tag(&self) -> Vec<u8> {
+        let mut tag = Id3v2Tag::new();
+        tag.text("TIT2", &self.title)
+            .text("TPE1", &self.artist.join(", "))
+            .text("TALB", &self.album)
+            .text("TPE2", &self.album_artist)
+            .text("TYER", &self.year)
+            .text("TCON", &self.genre)
+            .text("TRCK", &self.track_number)
+            .text("TPOS", &self.disc_number)
+            .comment(&self.comment)
+            .picture(&self.album_art);
+        if let Some(lyrics) = &self.lyrics {
+            tag.lyrics(&lyrics.text).synced_lyrics(&lyrics.synced);
+        }
+
+        if let Some(replay_gain) = self.replay_gain {
+            tag.user_text(
+                "REPLAYGAIN_TRACK_GAIN",
+                &format!("{:.2} dB", replay_gain.track_gain_db),
+            )
+            .user_text(
+                "REPLAYGAIN_TRACK_PEAK",
+                &format!("{:.6}", replay_gain.track_peak),
+            )
+            .user_text(
+                "REPLAYGAIN_ALBUM_GAIN",
+                &format!("{:.2} dB", replay_gain.album_gain_db),
+            )
+            .user_text(
+                "REPLAYGAIN_ALBUM_PEAK",
+                &format!("{:.6}", replay_gain.album_peak),
+            );
+        }
+        tag.to_bytes()
+    }
+
+    // what `placeholder` of a path template stands for
+    fn path_value(&self, placeholder: &str) -> Option<String> {
+        let value = match placeholder {
+            "album" => self.album.clone(),
+            "album_artist" => self.album_artist.clone(),
+            "artist" => self.artist.join(", "),
+            // "1/2" is disc 1 of 2
+            "disc" => self.disc_number.split('/').next()?.trim().to_string(),
+            "track" => format_track_number(&self.track_number),
+            "title" => self.title.clone(),
+            "year" => self.year.clone(),
+            "genre" => self.genre.clone(),
+            _ => return None,
+        };
+        Some(value)
+    }
+
+    fn apply_overrides(&mut self, overrides: &TagOverrides, cover_max_px: u32) {
+        let fields = [
+            (&mut self.title, &overrides.title),
+            (&mut self.track_number, &overrides.track_number),
+            (&mut self.album, &overrides.album),
+            (&mut self.album_artist, &overrides.album_artist),
+            (&mut self.year, &overrides.year),
+            (&mut self.genre, &overrides.genre),
+        ];
+        for (field, value) in fields {
+            if let Some(value) = value {
+                field.clone_from(value);
+            }
+        }
+
+        if let Some(cover) = &overrides.cover {
+            let image = ImageReader::open(cover)
+                .map_err(ImageError::from)
+                .and_then(|reader| reader.decode())
+                .and_then(|image| cover_art(image, cover_max_px));
+            match image {
+                Ok(album_art) => self.album_art = album_art,
+                Err(e) => error!("Error reading cover {:?}: {}", cover, e),
+            }
+        }
+    }
+}
+
+// a book opened for reading, ready to go out a chapter at a time
+struct Audiobook {
+    reader: PcmReader,
+    chapters: Vec<(TrackMetadata, (usize, usize))>,
+    // every chapter's loudness, the whole book's and its peak after gain, when anything needs them
+    loudness: Option<(Vec<Loudness>, Loudness, f32)>,
+    // all the chapters go through this one
+    session: LameSession,
+}
+
+// one track's worth of audio, what the encoder gets fed
+struct DecodedTrack {
+    pcm_data: Vec<Vec<f32>>,
+    track_metadata: TrackMetadata,
+    // measured right before encoding
+    levels: PeakLevels,
+    attenuation_db: f64,
+    // its share of the time spent decoding the source
+    elapsed_secs: f64,
+}
+
+impl DecodedTrack {
+    fn new(pcm_data: Vec<Vec<f32>>, track_metadata: TrackMetadata) -> Self {
+        Self {
+            pcm_data,
+            track_metadata,
+            levels: PeakLevels::default(),
+            attenuation_db: 0.0,
+            elapsed_secs: 0.0,
+        }
+    }
+
+    fn duration_secs(&self) -> f64 {
+        self.pcm_data[0].len() as f64 / self.track_metadata.sample_rate as f64
+    }
+
+    // Brings the track to the reference loudness the way `gain_mode` says, returns the gain
+    // and the true peak it ends up with
+    fn apply_gain(
+        &mut self,
+        gain_mode: GainMode,
+        analysis: &Loudness,
+        album: &Loudness,
+    ) -> (f64, f32) {
+        if gain_mode == GainMode::Off {
+            return (0.0, analysis.true_peak);
+        }
+        let gain_db = gain_mode.gain_db(analysis, album);
+        let sample_rate = self.track_metadata.sample_rate;
+        (gain_db, apply_gain(&mut self.pcm_data, gain_db, analysis.true_peak, sample_rate))
+    }
+
+    // Lossy sources decode to samples past full scale all the time, those get counted and,
+    // if `prevent_clipping` is set, turned down just enough to stay clean
+    fn stage_gain(&mut self, prevent_clipping: bool) {
+        self.levels = PeakLevels::measure(&self.pcm_data);
+        let gain_db = self.levels.safe_gain_db();
+        if prevent_clipping && gain_db < 0.0 {
+            scale(&mut self.pcm_data, db_to_linear(gain_db) as f32);
+            self.attenuation_db = gain_db;
+        }
+    }
 }
+
 impl fmt::Debug for TrackMetadata {
     fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
         f.debug_struct("TrackMetadata")
@@ -71,51 +541,92 @@ impl fmt::Debug for TrackMetadata {
 }
 
 impl AudioConverter {
-    pub(crate) fn new(src_path: PathBuf, to_type: AudioFiletype) -> Self {
-        let from_type = src_path
+    /// Fails for files whose extension isn't one we can decode, in whatever case it's written.
+    pub(crate) fn new(src_path: PathBuf, to_type: AudioFiletype) -> Result<Self, Error> {
+        let extension = src_path
             .extension()
             .and_then(|ext| ext.to_str())
-            .and_then(|ext_str| match ext_str {
-                "flac" => Some(AudioFiletype::FLAC),
-                "mp3" => Some(AudioFiletype::MP3),
-                "ogg" => Some(AudioFiletype::OGG),
-                _ => None,
-            })
-            .unwrap_or_else(|| panic!("woah owah no file extension for {:?}", src_path));
+            .map(str::to_ascii_lowercase);
+        let from_type = match extension.as_deref() {
+            Some("flac") => AudioFiletype::FLAC,
+            Some("mp3") => AudioFiletype::MP3,
+            Some("ogg") => AudioFiletype::OGG,
+            Some("m4a" | "m4b") => AudioFiletype::MP4,
+            _ => return Err(Error::Unsupported("no decoder for the file extension")),
+        };
 
-        AudioConverter {
+        Ok(AudioConverter {
             src_path,
             from_type,
             to_type,
             output_based_on_metadata: true,
-        }
+            encoder_settings: EncoderSettings::default(),
+            tag_overrides: TagOverrides::default(),
+            output_owners: Arc::default(),
+        })
+    }
+
+    pub fn with_encoder_settings(mut self, encoder_settings: EncoderSettings) -> Self {
+        self.encoder_settings = encoder_settings;
+        self
+    }
+
+    pub fn with_tag_overrides(mut self, tag_overrides: TagOverrides) -> Self {
+        self.tag_overrides = tag_overrides;
+        self
+    }
+
+    /// Which source writes a file several tracks would end up in, as the dry run settled it.
+    /// The other tracks fail instead of overwriting it.
+    pub fn with_output_owners(mut self, output_owners: Arc<OutputOwners>) -> Self {
+        self.output_owners = output_owners;
+        self
+    }
+
+    /// The tags the output gets, overrides included.
+    pub fn read_tags(&self) -> Result<TrackTags, Error> {
+        let track_metadata = self.__extract_metadata(self.src_path.clone())?;
+        Ok(TrackTags {
+            title: track_metadata.title,
+            track_number: track_metadata.track_number,
+            disc_number: track_metadata.disc_number,
+            artist: track_metadata.artist.join(", "),
+            album: track_metadata.album,
+            album_artist: track_metadata.album_artist,
+            year: track_metadata.year,
+            genre: track_metadata.genre,
+            has_cover: !track_metadata.album_art.is_empty(),
+        })
     }
 
     fn __extract_metadata(&self, input_path: PathBuf) -> Result<TrackMetadata, Error> {
         let mut hint = Hint::new();
         if let Some(extension) = input_path.extension() {
             if let Some(extension_str) = extension.to_str() {
-                println!("do I come here");
                 hint.with_extension(extension_str);
             }
         }
-        let src = File::open(input_path).expect("failed to open .flac file");
+        let src = File::open(input_path)?;
 
         let mss_src = MediaSourceStream::new(Box::new(src), Default::default());
 
         let meta_opts: MetadataOptions = Default::default();
         let fmt_opts: FormatOptions = Default::default();
 
-        let mut probed = symphonia::default::get_probe()
-            .format(&hint, mss_src, &fmt_opts, &meta_opts)
-            .expect("unsupported format");
+        let mut probed =
+            symphonia::default::get_probe().format(&hint, mss_src, &fmt_opts, &meta_opts)?;
 
         let mut format = probed.format;
 
-        if let Some(metadata_rev) = format.metadata().current() {
+        if format.metadata().current().is_some() {
             let binding = format.metadata();
             self._extract_metadata(binding)
-        } else if let Some(metadata_rev) = probed.metadata.get().as_ref().and_then(|m| m.current())
+        } else if probed
+            .metadata
+            .get()
+            .as_ref()
+            .and_then(|m| m.current())
+            .is_some()
         {
             let binding = probed.metadata.get().unwrap();
             self._extract_metadata(binding)
@@ -125,17 +636,26 @@ impl AudioConverter {
     }
 
     fn _extract_metadata(&self, binding: Metadata<'_>) -> Result<TrackMetadata, Error> {
-        let metadata = binding.current().unwrap();
+        let metadata = binding
+            .current()
+            .ok_or(Error::Unsupported("no metadata found"))?;
 
         let mut track_metadata: TrackMetadata = TrackMetadata {
             title: "".to_string(),
             track_number: "".to_string(),
+            disc_number: "".to_string(),
             artist: vec![],
             album: "".to_string(),
+            album_artist: "".to_string(),
             album_art: Box::new([]),
             year: "".to_string(),
+            genre: "".to_string(),
             comment: "".to_string(),
             sample_rate: 44_100,
+            lossy_source: false,
+            cue_sheet: None,
+            replay_gain: None,
+            lyrics: None,
         };
 
         // estraggo i metadati
@@ -146,154 +666,709 @@ impl AudioConverter {
                     StandardTagKey::TrackNumber => {
                         track_metadata.track_number = tag.value.to_string()
                     }
+                    StandardTagKey::DiscNumber => {
+                        track_metadata.disc_number = tag.value.to_string()
+                    }
                     StandardTagKey::Album => track_metadata.album = tag.value.to_string(),
+                    StandardTagKey::AlbumArtist => {
+                        track_metadata.album_artist = tag.value.to_string()
+                    }
+                    StandardTagKey::Genre => track_metadata.genre = tag.value.to_string(),
                     StandardTagKey::Artist => track_metadata.artist = vec![tag.value.to_string()],
                     StandardTagKey::Date => {
-                        track_metadata.year = (&tag.value.to_string()[..4]).to_string()
+                        // "2004-05-17" and co., a short or odd date is kept whole
+                        track_metadata.year = tag.value.to_string().chars().take(4).collect()
                     }
                     StandardTagKey::Comment => track_metadata.comment = tag.value.to_string(),
+                    StandardTagKey::Lyrics => {
+                        track_metadata.lyrics = Some(Lyrics::parse(&tag.value.to_string()))
+                    }
 
                     _ => continue,
                 },
+                // FLAC rips made by EAC & co. carry a copy of their .cue in here
+                None if tag.key.eq_ignore_ascii_case("CUESHEET") => {
+                    let cue_sheet = CueSheet::parse(&tag.value.to_string(), None);
+                    if cue_sheet.tracks.len() > 1 {
+                        track_metadata.cue_sheet = Some(cue_sheet);
+                    }
+                }
+                None if tag.key.eq_ignore_ascii_case("UNSYNCEDLYRICS")
+                    || tag.key.eq_ignore_ascii_case("LYRICS") =>
+                {
+                    track_metadata.lyrics = Some(Lyrics::parse(&tag.value.to_string()))
+                }
                 None => continue,
             }
         }
 
+        // an .lrc next to the file has the timing that tags mostly lack
+        let from_tags = track_metadata.lyrics.take().filter(|lyrics| !lyrics.text.is_empty());
+        track_metadata.lyrics = match (Lyrics::find_for(&self.src_path), from_tags) {
+            (Some(sidecar), Some(from_tags)) if sidecar.synced.is_empty() => Some(Lyrics {
+                sidecar: sidecar.sidecar,
+                ..from_tags
+            }),
+            (Some(sidecar), _) => Some(sidecar),
+            (None, from_tags) => from_tags,
+        };
+
         let mut album_art_raw: Box<[u8]> = Box::new([]);
+        let cover_max_px = self.encoder_settings.cover_max_px;
 
         // tiriamoci fuori il raw album data
         for visual in metadata.visuals().iter() {
             album_art_raw = visual.data.clone();
         }
 
-        if album_art_raw.len() != 0 {
-            // se la immagine è presente allora esegui il seguente blocco di codice
-            // cerchiamo di capire se la immagine è troppo grande o no, se no,
-            // allora track_metadata.album_art ottiene lo stesso, altrimenti, track_metadata.album_art ha una immagine nuova
-            // TODO skippa questa sezione se abbiamo già salvato in cache la immagine già processata
-            let reader = ImageReader::new(Cursor::new(album_art_raw.clone()))
-                .with_guessed_format()
-                .expect("Apparently Cursor io never fails?");
+        // TODO skippa questa sezione se abbiamo già salvato in cache la immagine già processata
+        let album_art = if !album_art_raw.is_empty() {
+            // a cover small enough goes in untouched, otherwise it gets shrunk
+            image::load_from_memory(&album_art_raw).and_then(|image| {
+                if image.width() > cover_max_px || image.height() > cover_max_px {
+                    cover_art(image, cover_max_px)
+                } else {
+                    Ok(album_art_raw)
+                }
+            })
+        } else {
+            match self.find_folder_cover() {
+                Some(path) => ImageReader::open(path)
+                    .map_err(ImageError::from)
+                    .and_then(|reader| reader.decode())
+                    .and_then(|image| cover_art(image, cover_max_px)),
+                None => Ok(Box::new([]) as Box<[u8]>),
+            }
+        };
+        // a broken cover leaves the track without one, the scan flags that
+        track_metadata.album_art = album_art.unwrap_or_else(|e| {
+            warn!(file:% = self.src_path.display(); "Skipping an unreadable cover: {}", e);
+            Box::new([])
+        });
 
-            let image = reader.decode().unwrap();
-            if image.width() > 500 || image.height() > 500 {
-                let new_image = image.resize(500, 500, FilterType::Gaussian);
+        track_metadata.apply_overrides(&self.tag_overrides, cover_max_px);
+        Ok(track_metadata)
+    }
+    // the first picture in the source's folder, jpg before jpeg before png
+    //TODO what to do if there is more than one image file??
+    fn find_folder_cover(&self) -> Option<PathBuf> {
+        let parent = self.src_path.parent()?;
+        // a folder named like "Best of [2004]" would read as a pattern otherwise
+        let folder = glob::Pattern::escape(&parent.to_string_lossy());
+        ["jpg", "jpeg", "png"]
+            .iter()
+            .filter_map(|extension| glob(&format!("{}/*.{}", folder, extension)).ok())
+            .flatten()
+            .find_map(Result::ok)
+    }
 
-                let mut buffer = Cursor::new(Vec::new());
-                new_image.write_to(&mut buffer, ImageFormat::Jpeg).unwrap();
-                track_metadata.album_art = buffer.into_inner().into_boxed_slice();
-            } else {
-                track_metadata.album_art = album_art_raw;
-            }
-        } else {
-            let parent = self.src_path.parent().unwrap(); // TODO to check, can the parent be just root?
+    /// Converts the source into one MP3, or into one per track when it is an album rip
+    /// described by a CUE sheet.
+    ///
+    /// Every track written is in there even when a later one failed.
+    pub fn convert_file_to_mp3(&self, output_path: PathBuf) -> Vec<ConversionResult> {
+        let started = Instant::now();
+        if self.encoder_settings.audiobook.enabled {
+            return self.convert_audiobook(output_path, started);
+        }
+        if let Some((mp3_bytes, stream)) = self.passthrough_stream() {
+            let copied = self.copy_mp3(output_path, &mp3_bytes, &stream, started);
+            return vec![copied.map_err(|e| self.failed(e, started))];
+        }
 
-            let mut cover_image_path: Option<PathBuf> = None;
+        let tracks = match self.decode_tracks(true) {
+            Ok(tracks) => tracks.into_iter().map(|track| (self, track)).collect(),
+            Err(e) => return vec![Err(self.failed(e, started))],
+        };
+        let continuous = self.encoder_settings.gapless_albums;
+        AudioConverter::encode_tracks(tracks, output_path, continuous)
+    }
 
-            let search_jpg = parent.to_string_lossy().to_string() + "/*.jpg";
-            let search_jpeg = parent.to_string_lossy().to_string() + "/*.jpeg";
-            let search_png = parent.to_string_lossy().to_string() + "/*.png";
+    // the error `self` failed with, after the time since `started`
+    fn failed(&self, e: Error, started: Instant) -> (PathBuf, String, f64) {
+        let elapsed_secs = started.elapsed().as_secs_f64();
+        (self.src_path.clone(), e.to_string(), elapsed_secs)
+    }
 
-            //TODO what to do if there is more than one image file??
-            // for now it just selects the first one that it finds
-            for file in glob(&search_jpg)
-                .unwrap()
-                .chain(glob(&search_jpeg).unwrap())
-                .chain(glob(&search_png).unwrap())
-            {
-                cover_image_path = Some(file.unwrap());
+    // Books run for hours, so they get decoded, encoded and written a chapter at a time and
+    // never sit in memory whole. The chapters share one encoder session, they carry on
+    // mid-sentence and mustn't have gaps put in between
+    fn convert_audiobook(&self, output_path: PathBuf, started: Instant) -> Vec<ConversionResult> {
+        let Audiobook {
+            mut reader,
+            chapters,
+            loudness,
+            mut session,
+        } = match self.open_audiobook() {
+            Ok(audiobook) => audiobook,
+            Err(e) => return vec![Err(self.failed(e, started))],
+        };
+        let settings = &self.encoder_settings;
+
+        (0..chapters.len())
+            .map(|index| {
+                let started = Instant::now();
+                let mut track = self.read_chapter(&mut reader, &chapters, index);
+                if let Some((analyses, album, album_peak)) = &loudness {
+                    let analysis = &analyses[index];
+                    let (gain_db, track_peak) =
+                        track.apply_gain(settings.gain_mode, analysis, album);
+                    if settings.replaygain_tags {
+                        track.track_metadata.replay_gain = Some(ReplayGainTags::new(
+                            analysis,
+                            album,
+                            gain_db,
+                            track_peak,
+                            *album_peak,
+                        ));
+                    }
+                }
+                track.elapsed_secs = started.elapsed().as_secs_f64();
+                self.write_track(&mut session, &track, index + 1 == chapters.len(), &output_path)
+            })
+            .collect()
+    }
+
+    fn open_audiobook(&self) -> Result<Audiobook, Error> {
+        let (reader, mut track_metadata) = self.open_input()?;
+        let frame_count = match reader.n_frames {
+            Some(n_frames) => n_frames as usize,
+            None => self.open_input()?.0.count_frames(),
+        };
+        let chapters = self
+            .split_tracks(&mut track_metadata, frame_count)
+            .unwrap_or_else(|| vec![(track_metadata.clone(), (0, frame_count))]);
+
+        // album gain and the album tags need all of the book measured before any of it goes out
+        let settings = &self.encoder_settings;
+        let loudness = if settings.gain_mode != GainMode::Off || settings.replaygain_tags {
+            let (mut reader, _) = self.open_input()?;
+            let analyses: Vec<Loudness> = (0..chapters.len())
+                .map(|index| {
+                    let track = self.read_chapter(&mut reader, &chapters, index);
+                    Loudness::analyze(&track.pcm_data, track.track_metadata.sample_rate)
+                })
+                .collect();
+            let album = Loudness::album(&analyses);
+            let album_peak = analyses
+                .iter()
+                .map(|analysis| {
+                    let gain_db = settings.gain_mode.gain_db(analysis, &album);
+                    peak_after_gain(analysis.true_peak, gain_db)
+                })
+                .fold(0.0, f32::max);
+            Some((analyses, album, album_peak))
+        } else {
+            None
+        };
+
+        let sample_rate = track_metadata.sample_rate;
+        let session = LameSession::new(
+            sample_rate,
+            settings.sample_rate.unwrap_or(sample_rate),
+            settings.bitrate(),
+            chapters.len(),
+            true,
+        )
+        .map_err(|_| Error::Unsupported("LAME refused the encoder settings"))?;
+        Ok(Audiobook {
+            reader,
+            chapters,
+            loudness,
+            session,
+        })
+    }
+
+    // Chapter `index` of `chapters`, read on from where `reader` is, trimmed and staged like
+    // `encode_tracks` does with whole tracks. Only the very start and end of the book get trimmed
+    fn read_chapter(
+        &self,
+        reader: &mut PcmReader,
+        chapters: &[(TrackMetadata, (usize, usize))],
+        index: usize,
+    ) -> DecodedTrack {
+        let (track_metadata, (start, end)) = &chapters[index];
+        let is_last = index + 1 == chapters.len();
+        // the container's length can be off a little, the last chapter takes whatever is left
+        let frames = if is_last { usize::MAX } else { end - start };
+        let mut track = DecodedTrack::new(reader.read(frames), track_metadata.clone());
+        self.trim_silence(&mut track, index == 0, is_last);
+        track.stage_gain(self.encoder_settings.prevent_clipping);
+        track
+    }
 
-                break;
+    /// Converts the files of one album in disc and track order, so album gain can be worked
+    /// out over all of them and gapless albums can go through a single encoder session.
+    ///
+    /// Every input gets its outputs or its error, one failing file doesn't take the album down.
+    pub fn convert_album_to_mp3(
+        converters: &[AudioConverter],
+        output_path: PathBuf,
+    ) -> Vec<ConversionResult> {
+        let mut results = Vec::new();
+        let mut tracks = Vec::new();
+        for converter in converters {
+            let started = Instant::now();
+            // a book is too long to be held in memory next to the rest of the album
+            if converter.encoder_settings.audiobook.enabled {
+                results.extend(converter.convert_file_to_mp3(output_path.clone()));
+                continue;
+            }
+            if let Some((mp3_bytes, stream)) = converter.passthrough_stream() {
+                let copied = converter.copy_mp3(output_path.clone(), &mp3_bytes, &stream, started);
+                results.push(copied.map_err(|e| converter.failed(e, started)));
+                continue;
+            }
+            // trimmed below as one, the gaps between the files are part of the album
+            match converter.decode_tracks(false) {
+                Ok(decoded) => tracks.extend(decoded.into_iter().map(|track| (converter, track))),
+                Err(e) => results.push(Err(converter.failed(e, started))),
             }
+        }
+        tracks.sort_by_key(|(_, track)| {
+            (
+                leading_number(&track.track_metadata.disc_number),
+                leading_number(&track.track_metadata.track_number),
+            )
+        });
+        if let Some((converter, first)) = tracks.first_mut() {
+            converter.trim_silence(first, true, false);
+        }
+        if let Some((converter, last)) = tracks.last_mut() {
+            converter.trim_silence(last, false, true);
+        }
+        let continuous = converters
+            .first()
+            .is_some_and(|converter| converter.encoder_settings.gapless_albums);
+        results.extend(AudioConverter::encode_tracks(tracks, output_path, continuous));
+        results
+    }
+
+    // The source itself and its frames, when it's an MP3 the PSP plays as it is and nothing
+    // asks for the audio to be touched. Re-encoding those only loses quality
+    fn passthrough_stream(&self) -> Option<(Vec<u8>, Mp3Stream)> {
+        let settings = &self.encoder_settings;
+        if !matches!(self.from_type, AudioFiletype::MP3)
+            || settings.gain_mode != GainMode::Off
+            || settings.replaygain_tags
+            || settings.silence_trim.enabled
+            || settings.audiobook.enabled
+            || CueSheet::find_for(&self.src_path).is_some()
+        {
+            return None;
+        }
+
+        let mp3_bytes = fs::read(&self.src_path).ok()?;
+        let stream = Mp3Stream::parse(&mp3_bytes)?;
+        // a bigger source still has to come down to the bitrate that was asked for
+        let fits_bitrate = stream.average_bitrate_kbps <= settings.effective_bitrate_kbps();
+        let fits_rate = settings.sample_rate.map_or(true, |rate| rate == stream.sample_rate);
+        (stream.playable_on_psp() && fits_bitrate && fits_rate).then_some((mp3_bytes, stream))
+    }
 
-            match cover_image_path {
-                Some(path) => {
-                    let image = ImageReader::open(path).unwrap().decode().unwrap();
-                    let mut buffer = Cursor::new(Vec::new());
+    fn copy_mp3(
+        &self,
+        output_path: PathBuf,
+        mp3_bytes: &[u8],
+        stream: &Mp3Stream,
+        started: Instant,
+    ) -> Result<ConversionOutput, Error> {
+        let track_metadata = self.__extract_metadata(self.src_path.clone())?;
+        let (path, bytes_written) =
+            self.write_mp3(output_path, &mp3_bytes[stream.frames.clone()], &track_metadata)?;
+        Ok(ConversionOutput {
+            passthrough: true,
+            audio_bytes: stream.frames.len() as u64,
+            elapsed_secs: started.elapsed().as_secs_f64(),
+            ..ConversionOutput::new(
+                self.src_path.clone(),
+                path,
+                bytes_written,
+                stream.duration_secs,
+                &track_metadata,
+            )
+        })
+    }
+
+    // the decoded source, split up when a CUE sheet or chapter markers say it holds more than
+    // one track. With `trim` the silence at its start and end is cut off
+    fn decode_tracks(&self, trim: bool) -> Result<Vec<DecodedTrack>, Error> {
+        let started = Instant::now();
+        let (mut pcm_data, mut track_metadata) = self.decode_input()?;
+
+        let Some(split) = self.split_tracks(&mut track_metadata, pcm_data[0].len()) else {
+            let mut track = DecodedTrack::new(pcm_data, track_metadata);
+            self.trim_silence(&mut track, trim, trim);
+            track.elapsed_secs = started.elapsed().as_secs_f64();
+            return Ok(vec![track]);
+        };
+
+        // cut off from the end and given back as we go, a book of many hours mustn't be held
+        // twice over
+        let mut tracks: Vec<DecodedTrack> = split
+            .into_iter()
+            .rev()
+            .map(|(track_metadata, (start, end))| {
+                let pcm_data = pcm_data
+                    .iter_mut()
+                    .map(|channel| {
+                        let mut part = channel.split_off(start.min(channel.len()));
+                        part.truncate(end - start);
+                        channel.shrink_to_fit();
+                        part
+                    })
+                    .collect();
+                DecodedTrack::new(pcm_data, track_metadata)
+            })
+            .collect();
+        tracks.reverse();
+
+        let track_count = tracks.len();
+        for (index, track) in tracks.iter_mut().enumerate() {
+            // the gaps between the tracks of a rip are part of the album, only its very start
+            // and end get trimmed
+            self.trim_silence(track, trim && index == 0, trim && index + 1 == track_count);
+        }
+        let share_secs = started.elapsed().as_secs_f64() / track_count as f64;
+        for track in &mut tracks {
+            track.elapsed_secs = share_secs;
+        }
+        Ok(tracks)
+    }
 
-                    if image.width() > 500 || image.height() > 500 {
-                        let new_image = image.resize(500, 500, FilterType::Gaussian);
+    // The tracks a source with `frame_count` frames splits into, with the frames each one
+    // spans. None when it stays one track
+    fn split_tracks(
+        &self,
+        track_metadata: &mut TrackMetadata,
+        frame_count: usize,
+    ) -> Option<Vec<(TrackMetadata, (usize, usize))>> {
+        let audiobook = &self.encoder_settings.audiobook;
+        if audiobook.enabled {
+            if track_metadata.album.is_empty() {
+                track_metadata.album = if track_metadata.title.is_empty() {
+                    self.src_path.file_stem().unwrap_or_default().to_string_lossy().to_string()
+                } else {
+                    track_metadata.title.clone()
+                };
+            }
+            if track_metadata.cue_sheet.is_none() {
+                let segment_secs = audiobook.segment_minutes.max(1) as u64 * 60;
+                let sample_rate = track_metadata.sample_rate;
+                let segments = fixed_segments(frame_count, sample_rate, segment_secs);
+                track_metadata.cue_sheet = Some(segments).filter(|sheet| sheet.tracks.len() > 1);
+            }
+        }
 
-                        new_image.write_to(&mut buffer, ImageFormat::Jpeg).unwrap();
-                        track_metadata.album_art = buffer.into_inner().into_boxed_slice();
-                    } else {
-                        image.write_to(&mut buffer, ImageFormat::Jpeg).unwrap();
-                        track_metadata.album_art = buffer.into_inner().into_boxed_slice();
+        let cue_sheet = track_metadata.cue_sheet.as_ref()?;
+        let ranges = cue_sheet.track_ranges(track_metadata.sample_rate, frame_count);
+        // the XMB sorts by name, so "010" has to come after "009"
+        let number_width = ranges.len().to_string().len().max(2);
+        Some(
+            cue_sheet
+                .tracks
+                .iter()
+                .zip(ranges)
+                .map(|(cue_track, range)| {
+                    let mut cue_track_metadata = track_metadata.for_cue_track(cue_sheet, cue_track);
+                    if audiobook.enabled {
+                        cue_track_metadata.track_number =
+                            format!("{:0width$}", cue_track.number, width = number_width);
                     }
-                }
-                None => track_metadata.album_art = album_art_raw,
+                    (cue_track_metadata, range)
+                })
+                .collect(),
+        )
+    }
+
+    fn trim_silence(&self, track: &mut DecodedTrack, trim_start: bool, trim_end: bool) {
+        let silence_trim = &self.encoder_settings.silence_trim;
+        if !silence_trim.enabled {
+            return;
+        }
+        let sample_rate = track.track_metadata.sample_rate;
+        let trimmed = silence_trim.trim(&mut track.pcm_data, sample_rate, trim_start, trim_end);
+        if trimmed > 0 {
+            info!(
+                file:% = self.src_path.display();
+                "Trimmed {:.1}s of silence off {:?}",
+                trimmed as f64 / sample_rate as f64,
+                self.src_path
+            );
+        }
+    }
+
+    // With `continuous` set, consecutive tracks share one encoder session, only a change of
+    // sample rate forces a new one. A track that can't be written doesn't stop the others
+    fn encode_tracks(
+        mut tracks: Vec<(&AudioConverter, DecodedTrack)>,
+        output_path: PathBuf,
+        continuous: bool,
+    ) -> Vec<ConversionResult> {
+        tracks.par_iter_mut().for_each(|(converter, track)| {
+            track.stage_gain(converter.encoder_settings.prevent_clipping);
+        });
+        let encoder_settings = tracks.first().map(|(converter, _)| &converter.encoder_settings);
+        if let Some(encoder_settings) = encoder_settings {
+            if encoder_settings.gain_mode != GainMode::Off || encoder_settings.replaygain_tags {
+                AudioConverter::apply_replay_gain(&mut tracks, encoder_settings);
             }
         }
 
-        Ok(track_metadata)
+        let mut results = Vec::with_capacity(tracks.len());
+
+        let mut start = 0;
+        while start < tracks.len() {
+            let (first_converter, first_track) = &tracks[start];
+            let sample_rate = first_track.track_metadata.sample_rate;
+            let session_len = if continuous {
+                tracks[start..]
+                    .iter()
+                    .take_while(|(_, track)| track.track_metadata.sample_rate == sample_rate)
+                    .count()
+            } else {
+                1
+            };
+
+            let encoder_settings = &first_converter.encoder_settings;
+            let session_tracks = &tracks[start..start + session_len];
+            start += session_len;
+            let session = LameSession::new(
+                sample_rate,
+                encoder_settings.sample_rate.unwrap_or(sample_rate),
+                encoder_settings.bitrate(),
+                session_len,
+                encoder_settings.audiobook.enabled,
+            );
+            let Ok(mut session) = session else {
+                // nothing of this session can be encoded
+                let e = "LAME refused the encoder settings".to_string();
+                results.extend(session_tracks.iter().map(|(converter, track)| {
+                    Err((converter.src_path.clone(), e.clone(), track.elapsed_secs))
+                }));
+                continue;
+            };
+
+            for (i, (converter, track)) in session_tracks.iter().enumerate() {
+                let is_last = i + 1 == session_len;
+                results.push(converter.write_track(&mut session, track, is_last, &output_path));
+            }
+        }
+
+        results
     }
-    pub fn convert_file_to_mp3(&self, output_path: PathBuf) -> Result<(), Error> {
-        let (pcm_data, track_metadata) = self.decode_input().unwrap();
 
-        // TODO maybe allow to export in more formats
-        let mp3_bytes;
-        match &self.to_type {
-            AudioFiletype::MP3 => {
-                mp3_bytes = AudioConverter::encode_to_mp3(pcm_data, &track_metadata)
+    // Encodes `track` and writes it out, `elapsed_secs` of the output adds the time that took
+    // to the track's own
+    fn write_track(
+        &self,
+        session: &mut LameSession,
+        track: &DecodedTrack,
+        is_last: bool,
+        output_path: &Path,
+    ) -> ConversionResult {
+        let started = Instant::now();
+        let mp3_frames = session.encode_track(&track.pcm_data, is_last);
+        let written = self.write_mp3(output_path.to_path_buf(), &mp3_frames, &track.track_metadata);
+        let elapsed_secs = track.elapsed_secs + started.elapsed().as_secs_f64();
+        match written {
+            Ok((path, bytes_written)) => Ok(ConversionOutput::encoded(
+                self.src_path.clone(),
+                path,
+                bytes_written,
+                mp3_frames.len() as u64,
+                track,
+                elapsed_secs,
+            )),
+            Err(e) => Err((self.src_path.clone(), e.to_string(), elapsed_secs)),
+        }
+    }
+
+    // Brings the tracks to the reference loudness, and/or tags them with what it would take.
+    // The tracks are treated as one album
+    fn apply_replay_gain(
+        tracks: &mut [(&AudioConverter, DecodedTrack)],
+        encoder_settings: &EncoderSettings,
+    ) {
+        let analyses: Vec<Loudness> = tracks
+            .par_iter()
+            .map(|(_, track)| {
+                Loudness::analyze(&track.pcm_data, track.track_metadata.sample_rate)
+            })
+            .collect();
+        let album = Loudness::album(&analyses);
+
+        let applied: Vec<(f64, f32)> = tracks
+            .par_iter_mut()
+            .zip(&analyses)
+            .map(|((_, track), analysis)| {
+                track.apply_gain(encoder_settings.gain_mode, analysis, &album)
+            })
+            .collect();
+
+        if encoder_settings.replaygain_tags {
+            let album_peak = applied.iter().map(|&(_, peak)| peak).fold(0.0, f32::max);
+            for (((_, track), analysis), &(gain_db, track_peak)) in
+                tracks.iter_mut().zip(&analyses).zip(&applied)
+            {
+                track.track_metadata.replay_gain = Some(ReplayGainTags::new(
+                    analysis, &album, gain_db, track_peak, album_peak,
+                ));
             }
-            _ => panic!("not implemented"),
         }
+    }
 
-        if self.output_based_on_metadata {
-            let second_half_of_path: String = "/".to_string() + &track_metadata.album + "/";
-            let dir_path = append_to_path(output_path, &second_half_of_path);
+    // Puts the tag in front of the frames and writes them where the metadata says, returns
+    // the path and size of the file
+    fn write_mp3(
+        &self,
+        output_path: PathBuf,
+        mp3_frames: &[u8],
+        track_metadata: &TrackMetadata,
+    ) -> Result<(PathBuf, u64), Error> {
+        // TODO maybe allow to export in more formats
+        let mut mp3_bytes = match &self.to_type {
+            AudioFiletype::MP3 => track_metadata.id3_tag(),
+            _ => panic!("not implemented"),
+        };
+        mp3_bytes.extend_from_slice(mp3_frames);
 
-            if !dir_path.exists() {
-                if let Err(e) = fs::create_dir_all(&dir_path) {
-                    eprintln!("Error creating directory: {}", e);
-                } else {
-                    println!("Directory created: {}", dir_path.display());
-                }
+        let full_path = self.output_file_path(output_path, track_metadata);
+        if let Some(owner) = self.output_owners.get(&full_path) {
+            if owner != &self.src_path {
+                return Err(Error::Unsupported("another track is written to the same file"));
+            }
+        }
+        if let Some(dir_path) = full_path.parent().filter(|dir| !dir.exists()) {
+            if let Err(e) = fs::create_dir_all(dir_path) {
+                error!("Error creating directory: {}", e);
+            } else {
+                debug!("Directory created: {}", dir_path.display());
+            }
+        }
+        write_atomically(&full_path, &mp3_bytes)?;
+
+        let sidecar = track_metadata.lyrics.as_ref().and_then(|lyrics| lyrics.sidecar.as_ref());
+        if let Some(sidecar) = sidecar.filter(|_| self.encoder_settings.copy_lrc) {
+            let copied = fs::read(sidecar)
+                .and_then(|lrc| write_atomically(&full_path.with_extension("lrc"), &lrc));
+            if let Err(e) = copied {
+                error!("Error copying {:?}: {}", sidecar, e);
             }
+        }
 
-            let filename = format_track_number(&track_metadata.track_number)
-                + " - "
-                + &track_metadata.title
-                + ".mp3";
-            let sanitized_filename = filename.replace(":", "_").replace("/", "_");
+        Ok((full_path, mp3_bytes.len() as u64))
+    }
 
-            let full_path = append_to_path(dir_path, &sanitized_filename);
-            let mut file = File::create(full_path).unwrap();
-            file.write_all(&mp3_bytes).unwrap();
-        } else {
-            let mut file = File::create(output_path).unwrap();
-            file.write_all(&mp3_bytes).unwrap();
+    // Where a track goes under `output_path`
+    fn output_file_path(&self, output_path: PathBuf, track_metadata: &TrackMetadata) -> PathBuf {
+        if !self.output_based_on_metadata {
+            return output_path;
+        }
+        let settings = &self.encoder_settings;
+        let value_of = |placeholder: &str| track_metadata.path_value(placeholder);
+        output_path.join(render_path(&settings.path_template, value_of, settings.fat_safe_names))
+    }
+
+    /// The files converting the source would write under `output_path`, worked out from its
+    /// headers and tags without decoding or writing anything.
+    pub fn plan_outputs(&self, output_path: &Path) -> Result<Vec<PlannedOutput>, Error> {
+        let probed = probe_track(&self.src_path)?;
+        if probed.codec.is_none() {
+            return Err(Error::Unsupported("no decoder for the codec"));
+        }
+        let mut track_metadata = self.__extract_metadata(self.src_path.clone())?;
+
+        if let Some((_, stream)) = self.passthrough_stream() {
+            let tag_bytes = track_metadata.id3_tag().len();
+            return Ok(vec![PlannedOutput {
+                output_path: self.output_file_path(output_path.to_path_buf(), &track_metadata),
+                passthrough: true,
+                estimated_bytes: (tag_bytes + stream.frames.len()) as u64,
+            }]);
+        }
+
+        self.attach_cue_sheet(&mut track_metadata);
+        if let Some(sample_rate) = probed.sample_rate {
+            track_metadata.sample_rate = sample_rate;
+        }
+        let sample_rate = track_metadata.sample_rate as f64;
+        let duration_secs = probed.duration_secs.unwrap_or(0.0);
+        let frame_count = (duration_secs * sample_rate) as usize;
+        let bitrate_kbps = self.encoder_settings.effective_bitrate_kbps();
+
+        let planned = |track_metadata: &TrackMetadata, duration_secs: f64| PlannedOutput {
+            output_path: self.output_file_path(output_path.to_path_buf(), track_metadata),
+            passthrough: false,
+            estimated_bytes: estimate_track_bytes(duration_secs, bitrate_kbps),
+        };
+        Ok(match self.split_tracks(&mut track_metadata, frame_count) {
+            Some(split) => split
+                .iter()
+                .map(|(track, (start, end))| planned(track, (end - start) as f64 / sample_rate))
+                .collect(),
+            None => vec![planned(&track_metadata, duration_secs)],
+        })
+    }
+
+    // A .cue next to the file wins over the one embedded in it, it's the one users edit.
+    // Audiobooks without either fall back on their chapters
+    fn attach_cue_sheet(&self, track_metadata: &mut TrackMetadata) {
+        if let Some(cue_sheet) = CueSheet::find_for(&self.src_path) {
+            track_metadata.cue_sheet = Some(cue_sheet);
+        } else if track_metadata.cue_sheet.is_none() {
+            if let AudioFiletype::FLAC = self.from_type {
+                track_metadata.cue_sheet = CueSheet::from_flac(&self.src_path);
+            }
+        }
+        if self.encoder_settings.audiobook.enabled && track_metadata.cue_sheet.is_none() {
+            track_metadata.cue_sheet = match self.from_type {
+                AudioFiletype::MP4 => mp4_chapters(&self.src_path),
+                AudioFiletype::MP3 => id3_chapters(&self.src_path),
+                _ => None,
+            };
         }
-        Ok(())
     }
 
+    // The whole of the source, decoded
     fn decode_input(&self) -> Result<(Vec<Vec<f32>>, TrackMetadata), Error> {
+        let (mut reader, track_metadata) = self.open_input()?;
+        Ok((reader.read(usize::MAX), track_metadata))
+    }
+
+    // The source's tags and a reader for its audio, which comes out in mono for audiobooks
+    fn open_input(&self) -> Result<(PcmReader, TrackMetadata), Error> {
         let mut hint = Hint::new();
 
         match self.from_type {
             AudioFiletype::FLAC => hint.with_extension("flac"),
             AudioFiletype::MP3 => hint.with_extension("mp3"),
             AudioFiletype::OGG => hint.with_extension("ogg"),
+            AudioFiletype::MP4 => hint.with_extension("mp4"),
 
         };
 
-        let src = File::open(self.src_path.clone()).expect("failed to open .flac file");
+        let src = File::open(self.src_path.clone())?;
         let mss_src = MediaSourceStream::new(Box::new(src), Default::default());
 
         let meta_opts: MetadataOptions = Default::default();
         let fmt_opts: FormatOptions = Default::default();
 
         let mut probed = symphonia::default::get_probe()
-            .format(&hint, mss_src, &fmt_opts, &meta_opts)
-            .expect("unsupported format");
+            .format(&hint, mss_src, &fmt_opts, &meta_opts)?;
 
         let mut format = probed.format;
 
         let track_metadata_res;
-        if let Some(metadata_rev) = format.metadata().current() {
+        if format.metadata().current().is_some() {
             let binding = format.metadata();
             track_metadata_res = self._extract_metadata(binding)
-        } else if let Some(metadata_rev) = probed.metadata.get().as_ref().and_then(|m| m.current())
+        } else if probed
+            .metadata
+            .get()
+            .as_ref()
+            .and_then(|m| m.current())
+            .is_some()
         {
             let binding = probed.metadata.get().unwrap();
             track_metadata_res = self._extract_metadata(binding)
@@ -301,7 +1376,9 @@ impl AudioConverter {
             return Err(Error::Unsupported("no metadata found"));
         }
 
-        let mut track_metadata = track_metadata_res.unwrap();
+        let mut track_metadata = track_metadata_res?;
+
+        self.attach_cue_sheet(&mut track_metadata);
 
         ////////////////////////////////////////////////////
 
@@ -309,7 +1386,7 @@ impl AudioConverter {
             .tracks()
             .iter()
             .find(|t| t.codec_params.codec != CODEC_TYPE_NULL)
-            .expect("no supported audio tracks");
+            .ok_or(Error::Unsupported("no supported audio tracks"))?;
 
         let params = &track.codec_params;
         let dec_opts: DecoderOptions = Default::default();
@@ -317,122 +1394,212 @@ impl AudioConverter {
         if let Some(sample_rate) = params.sample_rate {
             track_metadata.sample_rate = sample_rate
         } else {
-            println!("Sample rate information is not available.");
+            warn!(file:% = self.src_path.display(); "Sample rate information is not available.");
         }
-
-        let mut decoder = symphonia::default::get_codecs()
-            .make(params, &dec_opts)
-            .expect("unsupported codec");
-
-        let track_id = track.id;
-
-        let mut pcm_data: Vec<Vec<f32>> = vec![Vec::new(); 2];
-
-        let loop_result = loop {
-            let packet = match format.next_packet() {
-                Ok(packet) => packet,
-                Err(err) => break Err(err),
-            };
-
-            while !format.metadata().is_latest() {
-                // Pop the old head of the metadata queue.
-                format.metadata().pop();
-                // Consume the new metadata at the head of the metadata queue.
-            }
-
-            if packet.track_id() != track_id {
-                continue;
-            }
-
-            let decoded = match decoder.decode(&packet) {
-                Ok(decoded) => decoded,
-                Err(err) => break Err(err),
-            };
-
-            match decoded {
-                AudioBufferRef::U8(input) => convert_samples(input, &mut pcm_data),
-                AudioBufferRef::U16(input) => convert_samples(input, &mut pcm_data),
-                AudioBufferRef::U24(input) => convert_samples(input, &mut pcm_data),
-                AudioBufferRef::U32(input) => convert_samples(input, &mut pcm_data),
-                AudioBufferRef::S8(input) => convert_samples(input, &mut pcm_data),
-                AudioBufferRef::S16(input) => convert_samples(input, &mut pcm_data),
-                AudioBufferRef::S24(input) => convert_samples(input, &mut pcm_data),
-                AudioBufferRef::S32(input) => convert_samples(input, &mut pcm_data),
-                AudioBufferRef::F32(input) => convert_samples(input, &mut pcm_data),
-                AudioBufferRef::F64(input) => convert_samples(input, &mut pcm_data),
-            }
+        track_metadata.lossy_source = LOSSY_CODECS.contains(&params.codec);
+
+        let decoder = symphonia::default::get_codecs()
+            .make(params, &dec_opts)?;
+
+        // speech loses nothing in mono, and a book mixed down as it's decoded takes half the
+        // memory
+        let channels = if self.encoder_settings.audiobook.enabled { 1 } else { 2 };
+        let reader = PcmReader {
+            track_id: track.id,
+            n_frames: params.n_frames,
+            format,
+            decoder,
+            pending: vec![Vec::new(); channels],
+            ended: false,
         };
-
-        let _res = self.ignore_end_of_stream_error(loop_result);
-
-        Ok((pcm_data, track_metadata))
+        Ok((reader, track_metadata))
     }
+}
 
-    fn encode_to_mp3(pcm_data: Vec<Vec<f32>>, track_metadata: &TrackMetadata) -> Vec<u8> {
-        let mut mp3_encoder = Builder::new().expect("Create LAME builder");
-        mp3_encoder.set_num_channels(2).expect("set channels");
-        mp3_encoder
-            .set_sample_rate(track_metadata.sample_rate)
-            .expect("set sample rate");
-        mp3_encoder
-            .set_brate(Bitrate::Kbps192)
-            .expect("set bitrate");
-        mp3_encoder.set_quality(Quality::Best).expect("set quality");
-
-        let byte_slice: Vec<u8> = track_metadata.artist.concat().into_bytes();
+// Decodes a source a stretch at a time, so that long ones don't have to be held whole
+struct PcmReader {
+    format: Box<dyn FormatReader>,
+    decoder: Box<dyn Decoder>,
+    track_id: u32,
+    // how long the container says the track is, not every one does
+    n_frames: Option<u64>,
+    // decoded and not handed out yet
+    pending: Vec<Vec<f32>>,
+    ended: bool,
+}
 
-        mp3_encoder
-            .set_id3_tag(Id3Tag {
-                title: track_metadata.title.as_ref(),
-                artist: &byte_slice,
-                album: track_metadata.album.as_ref(),
-                album_art: &*track_metadata.album_art,
-                year: track_metadata.year.as_ref(),
-                comment: track_metadata.comment.as_ref(),
+impl PcmReader {
+    // The next `frames` frames, fewer once the source runs out
+    fn read(&mut self, frames: usize) -> Vec<Vec<f32>> {
+        while !self.ended && self.pending[0].len() < frames {
+            self.decode_packet();
+        }
+        let frames = frames.min(self.pending[0].len());
+        self.pending
+            .iter_mut()
+            .map(|channel| {
+                let rest = channel.split_off(frames);
+                std::mem::replace(channel, rest)
             })
-            .expect("TODO: panic message");
-        let mut mp3_encoder = mp3_encoder.build().expect("To initialize LAME encoder");
+            .collect()
+    }
+
+    // Walks the rest of the packets without decoding them, for containers that don't say how
+    // long they are
+    fn count_frames(mut self) -> usize {
+        let mut frames = 0;
+        while let Ok(packet) = self.format.next_packet() {
+            if packet.track_id() == self.track_id {
+                frames += packet.dur as usize;
+            }
+        }
+        frames
+    }
 
-        //use actual PCM data
-        let input = DualPcm {
-            left: &pcm_data[0],
-            right: &pcm_data[1],
+    fn decode_packet(&mut self) {
+        let packet = match self.format.next_packet() {
+            Ok(packet) => packet,
+            Err(err) => return self.end(err),
         };
 
-        let mut mp3_out_buffer = Vec::new();
-        mp3_out_buffer.reserve(max_required_buffer_size(input.left.len()));
-        let encoded_size = mp3_encoder
-            .encode(input, mp3_out_buffer.spare_capacity_mut())
-            .expect("To encode");
-        unsafe {
-            mp3_out_buffer.set_len(mp3_out_buffer.len().wrapping_add(encoded_size));
+        while !self.format.metadata().is_latest() {
+            // Pop the old head of the metadata queue.
+            self.format.metadata().pop();
+            // Consume the new metadata at the head of the metadata queue.
         }
 
-        let encoded_size = mp3_encoder
-            .flush::<FlushNoGap>(mp3_out_buffer.spare_capacity_mut())
-            .expect("to flush");
-        unsafe {
-            mp3_out_buffer.set_len(mp3_out_buffer.len().wrapping_add(encoded_size));
+        if packet.track_id() != self.track_id {
+            return;
         }
 
-        mp3_out_buffer
+        let decoded = match self.decoder.decode(&packet) {
+            Ok(decoded) => decoded,
+            Err(err) => return self.end(err),
+        };
+
+        let pcm_data = &mut self.pending;
+        match decoded {
+            AudioBufferRef::U8(input) => convert_samples(input, pcm_data),
+            AudioBufferRef::U16(input) => convert_samples(input, pcm_data),
+            AudioBufferRef::U24(input) => convert_samples(input, pcm_data),
+            AudioBufferRef::U32(input) => convert_samples(input, pcm_data),
+            AudioBufferRef::S8(input) => convert_samples(input, pcm_data),
+            AudioBufferRef::S16(input) => convert_samples(input, pcm_data),
+            AudioBufferRef::S24(input) => convert_samples(input, pcm_data),
+            AudioBufferRef::S32(input) => convert_samples(input, pcm_data),
+            AudioBufferRef::F32(input) => convert_samples(input, pcm_data),
+            AudioBufferRef::F64(input) => convert_samples(input, pcm_data),
+        }
     }
 
-    fn ignore_end_of_stream_error(&self, result: Result<(), Error>) -> Result<(), Error> {
-        match result {
-            Err(Error::IoError(err))
+    // Whatever was decoded up to the error is kept, like it always was
+    fn end(&mut self, err: Error) {
+        self.ended = true;
+        match err {
+            Error::IoError(err)
                 if err.kind() == std::io::ErrorKind::UnexpectedEof
                     && err.to_string() == "end of stream" =>
             {
                 // Do not treat "end of stream" as a fatal error. It's the currently only way a
                 // format reader can indicate the media is complete.
-                Ok(())
             }
-            _ => result,
+            err => debug!("Decoding stopped early: {}", err),
         }
     }
 }
 
+pub fn probe_track(path: &Path) -> Result<ProbedTrack, Error> {
+    let mut hint = Hint::new();
+    if let Some(extension_str) = path.extension().and_then(|ext| ext.to_str()) {
+        hint.with_extension(extension_str);
+    }
+
+    let src = File::open(path)?;
+    let mss_src = MediaSourceStream::new(Box::new(src), Default::default());
+
+    let meta_opts: MetadataOptions = Default::default();
+    let fmt_opts: FormatOptions = Default::default();
+
+    let mut probed = symphonia::default::get_probe().format(&hint, mss_src, &fmt_opts, &meta_opts)?;
+    let mut format = probed.format;
+
+    let find_tags = |revision: &MetadataRevision| {
+        let find = |key| {
+            revision
+                .tags()
+                .iter()
+                .find(|tag| tag.std_key == Some(key))
+                .map(|tag| tag.value.to_string())
+        };
+        let artist = find(StandardTagKey::AlbumArtist).or_else(|| find(StandardTagKey::Artist));
+        (find(StandardTagKey::Album), artist)
+    };
+
+    let (album, artist) = match format.metadata().current() {
+        Some(revision) => find_tags(revision),
+        None => probed
+            .metadata
+            .get()
+            .as_ref()
+            .and_then(|m| m.current())
+            .map(find_tags)
+            .unwrap_or_default(),
+    };
+
+    let track = format
+        .tracks()
+        .iter()
+        .find(|t| t.codec_params.codec != CODEC_TYPE_NULL)
+        .ok_or(Error::Unsupported("no supported audio tracks"))?;
+
+    let params = &track.codec_params;
+    let duration_secs = match (params.n_frames, params.sample_rate) {
+        (Some(n_frames), Some(sample_rate)) if sample_rate > 0 => {
+            Some(n_frames as f64 / sample_rate as f64)
+        }
+        _ => None,
+    };
+
+    let codec = symphonia::default::get_codecs()
+        .get_codec(params.codec)
+        .map(|descriptor| descriptor.short_name);
+
+    Ok(ProbedTrack {
+        album: album.unwrap_or_default(),
+        artist: artist.unwrap_or_default(),
+        duration_secs,
+        sample_rate: params.sample_rate,
+        codec,
+    })
+}
+
+// big covers make the XMB slow to scroll, and aren't any sharper on its screen
+fn cover_art(image: DynamicImage, max_px: u32) -> Result<Box<[u8]>, ImageError> {
+    let image = if image.width() > max_px || image.height() > max_px {
+        image.resize(max_px, max_px, FilterType::Gaussian)
+    } else {
+        image
+    };
+    let mut buffer = Cursor::new(Vec::new());
+    // JPEG has no alpha channel, a transparent PNG would fail to encode
+    DynamicImage::ImageRgb8(image.to_rgb8())
+        .write_to(&mut buffer, ImageFormat::Jpeg)?;
+    Ok(buffer.into_inner().into_boxed_slice())
+}
+
+pub(crate) fn sanitize_file_name(file_name: &str, fat_safe: bool) -> String {
+    // what FAT32, and so every PSP memory stick, refuses in a name
+    const FAT_RESERVED: [char; 7] = ['\\', '*', '?', '"', '<', '>', '|'];
+    file_name
+        .chars()
+        .map(|c| match c {
+            ':' | '/' => '_',
+            c if fat_safe && (FAT_RESERVED.contains(&c) || c.is_control()) => '_',
+            c => c,
+        })
+        .collect()
+}
+
 fn format_track_number(str: &str) -> String {
     if str.len() > 1 {
         str.to_string()
@@ -441,25 +1608,80 @@ fn format_track_number(str: &str) -> String {
     }
 }
 
-fn append_to_path(p: PathBuf, s: &str) -> PathBuf {
-    let mut p = p.into_os_string();
-    p.push(s);
-    p.into()
-}
-
-fn convert_samples<S>(input: Cow<AudioBuffer<S>>, output: &mut Vec<Vec<f32>>)
+fn convert_samples<S>(input: Cow<'_, AudioBuffer<S>>, output: &mut [Vec<f32>])
 where
     S: Sample + IntoSample<f32>,
 {
+    let channel_count = input.spec().channels.count();
+    if let [mono] = output {
+        let channels: Vec<&[S]> = (0..channel_count).map(|channel| input.chan(channel)).collect();
+        mono.extend((0..input.frames()).map(|i| {
+            let sum: f32 = channels.iter().map(|channel| channel[i].into_sample()).sum();
+            sum / channel_count as f32
+        }));
+        return;
+    }
+    // mono sources, podcasts mostly, go out the same on both sides
     for (channel, dest) in output.iter_mut().enumerate() {
-        let src = input.chan(channel);
+        let src = input.chan(channel.min(channel_count - 1));
         dest.extend(src.iter().map(|&s| s.into_sample()));
     }
 }
 
+#[cfg(test)]
+pub(crate) mod fixtures {
+    use std::fs;
+    use std::path::Path;
+
+    use mp3lame_encoder::Bitrate;
+
+    use crate::app::converter::TrackMetadata;
+    use crate::app::lame::LameSession;
+
+    // `secs` of a tone at `sample_rate`, tagged with `track_metadata`
+    pub(super) fn write_tone_mp3(
+        path: &Path,
+        track_metadata: &TrackMetadata,
+        sample_rate: u32,
+        secs: usize,
+    ) {
+        let channel: Vec<f32> = (0..secs * sample_rate as usize)
+            .map(|i| (i as f32 * 440.0 * std::f32::consts::TAU / sample_rate as f32).sin() * 0.5)
+            .collect();
+        let mut session = LameSession::new(sample_rate, sample_rate, Bitrate::Kbps64, 1, false)
+            .unwrap();
+        let mut mp3_bytes = track_metadata.id3_tag();
+        mp3_bytes.extend(session.encode_track(&[channel.clone(), channel], true));
+        fs::write(path, mp3_bytes).unwrap();
+    }
+
+    /// `secs` of a tone, tagged as a track of `album`. 44.1 kHz at 64 kbps, the PSP plays it
+    /// as it is.
+    pub(crate) fn write_album_track(
+        path: &Path,
+        album: &str,
+        track_number: &str,
+        title: &str,
+        secs: usize,
+    ) {
+        let track_metadata = TrackMetadata {
+            title: title.to_string(),
+            track_number: track_number.to_string(),
+            album: album.to_string(),
+            ..Default::default()
+        };
+        write_tone_mp3(path, &track_metadata, 44_100, secs);
+    }
+}
+
 #[cfg(test)]
 mod tests {
-    use crate::app::converter::{AudioConverter, AudioFiletype};
+    use super::fixtures::write_tone_mp3;
+    use crate::app::converter::{
+        AudioConverter, AudioFiletype, EncoderSettings, TagOverrides, TrackMetadata,
+    };
+    use crate::app::loudness::GainMode;
+    use std::fs;
     use std::path::PathBuf;
 
     #[test]
@@ -480,7 +1702,202 @@ mod tests {
     fn test_mp3() {
         let input_path = PathBuf::from("test_media/test.mp3");
         let dest_path = PathBuf::from("test_media/");
-        let audio_converter = AudioConverter::new(input_path.clone(), AudioFiletype::MP3);
-        let res = audio_converter.convert_file_to_mp3(dest_path);
+        let audio_converter = AudioConverter::new(input_path.clone(), AudioFiletype::MP3).unwrap();
+        let _res = audio_converter.convert_file_to_mp3(dest_path);
+    }
+
+    #[test]
+    fn test_album_reports_every_input() {
+        let dir = std::env::temp_dir().join(format!("m2psp-album-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let junk = dir.join("01.flac");
+        fs::write(&junk, b"not audio at all").unwrap();
+        let gone = dir.join("02.flac");
+
+        let converters: Vec<AudioConverter> = [&junk, &gone]
+            .iter()
+            .map(|path| AudioConverter::new(path.to_path_buf(), AudioFiletype::MP3).unwrap())
+            .collect();
+        let results = AudioConverter::convert_album_to_mp3(&converters, dir.join("out"));
+        let failed: Vec<&PathBuf> = results
+            .iter()
+            .map(|result| &result.as_ref().err().unwrap().0)
+            .collect();
+        assert_eq!(failed, [&junk, &gone]);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_album_keeps_the_tracks_written_before_a_failure() {
+        let dir = std::env::temp_dir().join(format!("m2psp-written-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let encoder_settings = EncoderSettings {
+            bitrate_kbps: 64,
+            // the album goes through as one job
+            gain_mode: GainMode::Album,
+            ..Default::default()
+        };
+        let converters: Vec<AudioConverter> = ["One", "Two"]
+            .iter()
+            .enumerate()
+            .map(|(i, title)| {
+                let track_metadata = TrackMetadata {
+                    title: title.to_string(),
+                    track_number: (i + 1).to_string(),
+                    album: "Album".to_string(),
+                    ..Default::default()
+                };
+                let path = dir.join(format!("{}.mp3", title));
+                write_tone_mp3(&path, &track_metadata, 8_000, 5);
+                AudioConverter::new(path, AudioFiletype::MP3)
+                    .unwrap()
+                    .with_encoder_settings(encoder_settings.clone())
+            })
+            .collect();
+        // a folder in the way of the second track
+        fs::create_dir_all(dir.join("out/Album/02 - Two.mp3/in the way")).unwrap();
+
+        let results = AudioConverter::convert_album_to_mp3(&converters, dir.join("out"));
+        assert_eq!(results.len(), 2);
+        let written = results[0].as_ref().unwrap();
+        assert_eq!(written.output_path, dir.join("out/Album/01 - One.mp3"));
+        assert!(written.output_path.is_file());
+        // the tag doesn't count towards the bitrate
+        assert!(written.audio_bytes < written.bytes_written);
+        let kbps = written.audio_bytes as f64 * 8.0 / written.duration_secs / 1000.0;
+        assert!((kbps - 64.0).abs() < 4.0, "{} kbps", kbps);
+        assert_eq!(results[1].as_ref().err().unwrap().0, dir.join("Two.mp3"));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_audiobook_goes_out_in_mono_parts() {
+        let dir = std::env::temp_dir().join(format!("m2psp-book-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        // just long enough for two parts of a minute
+        let track_metadata = TrackMetadata {
+            title: "Book".to_string(),
+            ..Default::default()
+        };
+        let src_path = dir.join("book.mp3");
+        write_tone_mp3(&src_path, &track_metadata, 8_000, 70);
+
+        let mut encoder_settings = EncoderSettings::default();
+        encoder_settings.audiobook.enabled = true;
+        encoder_settings.audiobook.segment_minutes = 1;
+        // measured over the whole book before the first part goes out
+        encoder_settings.gain_mode = GainMode::Album;
+        encoder_settings.replaygain_tags = true;
+        let outputs = AudioConverter::new(src_path, AudioFiletype::MP3)
+            .unwrap()
+            .with_encoder_settings(encoder_settings)
+            .convert_file_to_mp3(dir.join("out"))
+            .into_iter()
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+
+        let parts: Vec<PathBuf> = outputs.iter().map(|output| output.output_path.clone()).collect();
+        assert_eq!(
+            parts,
+            [
+                dir.join("out/Book/01 - Part 1.mp3"),
+                dir.join("out/Book/02 - Part 2.mp3")
+            ]
+        );
+        assert!((outputs[0].duration_secs - 60.0).abs() < 0.01);
+        assert!((outputs[1].duration_secs - 10.0).abs() < 0.5);
+        for part in &parts {
+            let bytes = fs::read(part).unwrap();
+            let frame = bytes.windows(2).position(|w| w[0] == 0xFF && w[1] & 0xE0 == 0xE0);
+            // channel mode 3 is mono
+            assert_eq!(bytes[frame.unwrap() + 3] >> 6, 3);
+        }
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_read_tags_survives_odd_files() {
+        let root = std::env::temp_dir().join(format!("m2psp-odd-{}", std::process::id()));
+        let dir = root.join("Live [2004]");
+        fs::create_dir_all(&dir).unwrap();
+        let track_metadata = TrackMetadata {
+            title: "Song".to_string(),
+            year: "99".to_string(),
+            ..Default::default()
+        };
+        write_tone_mp3(&dir.join("song.mp3"), &track_metadata, 8_000, 1);
+        fs::write(dir.join("cover.jpg"), b"not a picture").unwrap();
+
+        let tags = AudioConverter::new(dir.join("song.mp3"), AudioFiletype::MP3)
+            .unwrap()
+            .read_tags()
+            .unwrap();
+        assert_eq!(tags.year, "99");
+        // the broken cover is left out rather than taking the scan down
+        assert!(!tags.has_cover);
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_converter_extensions() {
+        assert!(AudioConverter::new(PathBuf::from("/music/01.FLAC"), AudioFiletype::MP3).is_ok());
+        assert!(AudioConverter::new(PathBuf::from("/music/x.Mp3"), AudioFiletype::MP3).is_ok());
+        assert!(AudioConverter::new(PathBuf::from("/music/x.wav"), AudioFiletype::MP3).is_err());
+        assert!(AudioConverter::new(PathBuf::from("/music/README"), AudioFiletype::MP3).is_err());
+    }
+
+    #[test]
+    fn test_output_file_path() {
+        let converter = AudioConverter::new(PathBuf::from("/music/01.flac"), AudioFiletype::MP3);
+        let track_metadata = TrackMetadata {
+            title: "Who?".to_string(),
+            track_number: "1".to_string(),
+            album: "AC/DC: \"Live\"".to_string(),
+            ..Default::default()
+        };
+        let path = converter.unwrap().output_file_path(PathBuf::from("/psp"), &track_metadata);
+        assert_eq!(path, PathBuf::from("/psp/AC_DC_ _Live_/01 - Who_.mp3"));
+    }
+
+    #[test]
+    fn test_tag_overrides() {
+        let cover = std::env::temp_dir().join(format!("m2psp-cover-{}.png", std::process::id()));
+        image::RgbaImage::from_pixel(800, 600, image::Rgba([255, 0, 0, 128]))
+            .save(&cover)
+            .unwrap();
+
+        let mut track_metadata = TrackMetadata {
+            title: "Tarck 1".to_string(),
+            track_number: "1".to_string(),
+            album: "Album".to_string(),
+            year: "1999".to_string(),
+            ..Default::default()
+        };
+        track_metadata.apply_overrides(
+            &TagOverrides {
+                title: Some("Track 1".to_string()),
+                album_artist: Some("Various Artists".to_string()),
+                cover: Some(cover.clone()),
+                ..Default::default()
+            },
+            500,
+        );
+        std::fs::remove_file(&cover).unwrap();
+
+        assert_eq!(track_metadata.title, "Track 1");
+        assert_eq!(track_metadata.album_artist, "Various Artists");
+        // whatever wasn't edited stays as read
+        assert_eq!(track_metadata.album, "Album");
+        assert_eq!(track_metadata.year, "1999");
+
+        // a transparent PNG still ends up as a JPEG no bigger than 500x500
+        let art = image::load_from_memory(&track_metadata.album_art).unwrap();
+        let format = image::guess_format(&track_metadata.album_art).unwrap();
+        assert_eq!(format, image::ImageFormat::Jpeg);
+        assert_eq!((art.width(), art.height()), (500, 375));
     }
 }
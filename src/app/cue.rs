@@ -0,0 +1,388 @@
+use std::fs::{self, File};
+use std::io::{Read, Seek, SeekFrom};
+use std::path::Path;
+
+use crate::app::playlist::decode_text;
+
+// CUE times are given in CD frames, 75 of them per second
+const FRAMES_PER_SECOND: u64 = 75;
+
+const FLAC_CUESHEET_BLOCK: u8 = 5;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CueTime {
+    // mm:ss:ff from a .cue file
+    Frames(u64),
+    // straight from the CUESHEET block of a FLAC file
+    Samples(u64),
+    // chapter markers of audiobooks and podcasts
+    Millis(u64),
+}
+
+impl CueTime {
+    pub fn to_samples(self, sample_rate: u32) -> u64 {
+        match self {
+            // exact for 44.1 and 48 kHz, the only rates a CD rip comes in
+            CueTime::Frames(frames) => frames * sample_rate as u64 / FRAMES_PER_SECOND,
+            CueTime::Samples(samples) => samples,
+            CueTime::Millis(millis) => millis * sample_rate as u64 / 1000,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct CueTrack {
+    pub number: u32,
+    pub title: String,
+    pub performer: String,
+    // where INDEX 01 is, anything between the previous track and this one is its pregap
+    pub start: CueTime,
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct CueSheet {
+    pub title: String,
+    pub performer: String,
+    pub year: String,
+    pub tracks: Vec<CueTrack>,
+}
+
+impl CueSheet {
+    /// Parses the text of a .cue file, keeping only the tracks that live in `audio_file_name`.
+    ///
+    /// Sheets describing a single file don't need to match, which also covers the copy of the
+    /// sheet that gets embedded into FLAC files.
+    pub fn parse(text: &str, audio_file_name: Option<&str>) -> Self {
+        let mut sheet = CueSheet::default();
+        let mut files_seen = 0;
+        let mut in_matching_file = true;
+        let mut current: Option<CueTrack> = None;
+        let mut in_track = false;
+        let mut has_index = false;
+
+        for line in text.lines() {
+            let line = line.trim();
+            let (command, rest) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+            let rest = rest.trim();
+
+            match command.to_ascii_uppercase().as_str() {
+                "FILE" => {
+                    files_seen += 1;
+                    // FILE "name" WAVE, the name itself might contain spaces
+                    let file_name = match rest.rsplit_once(char::is_whitespace) {
+                        Some((name, _)) => unquote(name),
+                        None => unquote(rest),
+                    };
+                    in_matching_file = match audio_file_name {
+                        Some(name) => same_file_name(&file_name, name),
+                        None => true,
+                    };
+                }
+                "TRACK" => {
+                    if let Some(track) = current.take().filter(|_| has_index) {
+                        sheet.tracks.push(track);
+                    }
+                    in_track = true;
+                    has_index = false;
+                    if in_matching_file {
+                        let number = rest.split_whitespace().next().and_then(|n| n.parse().ok());
+                        current = number.map(|number| CueTrack {
+                            number,
+                            title: String::new(),
+                            performer: String::new(),
+                            start: CueTime::Frames(0),
+                        });
+                    }
+                }
+                "TITLE" if in_track => {
+                    if let Some(track) = current.as_mut() {
+                        track.title = unquote(rest);
+                    }
+                }
+                "TITLE" => sheet.title = unquote(rest),
+                "PERFORMER" if in_track => {
+                    if let Some(track) = current.as_mut() {
+                        track.performer = unquote(rest);
+                    }
+                }
+                "PERFORMER" => sheet.performer = unquote(rest),
+                "REM" => {
+                    if let Some(date) = rest.strip_prefix("DATE") {
+                        sheet.year = unquote(date.trim()).chars().take(4).collect();
+                    }
+                }
+                "INDEX" => {
+                    let mut parts = rest.split_whitespace();
+                    let (Some(index), Some(time)) = (parts.next(), parts.next()) else {
+                        continue;
+                    };
+                    if let (Some(track), Ok(1), Some(frames)) =
+                        (current.as_mut(), index.parse::<u32>(), parse_msf(time))
+                    {
+                        track.start = CueTime::Frames(frames);
+                        has_index = true;
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        if let Some(track) = current.filter(|_| has_index) {
+            sheet.tracks.push(track);
+        }
+
+        // without a file name to go by there is no telling which file a track belongs to
+        if files_seen > 1 && audio_file_name.is_none() {
+            sheet.tracks.clear();
+        }
+
+        sheet
+    }
+
+    /// Reads the CUESHEET metadata block of a FLAC file. Those carry no titles.
+    pub fn from_flac(path: &Path) -> Option<Self> {
+        let mut file = File::open(path).ok()?;
+        let mut header = [0u8; 4];
+        file.read_exact(&mut header).ok()?;
+        if &header != b"fLaC" {
+            return None;
+        }
+        loop {
+            file.read_exact(&mut header).ok()?;
+            let len = u32::from_be_bytes([0, header[1], header[2], header[3]]);
+            if header[0] & 0x7F == FLAC_CUESHEET_BLOCK {
+                let mut block = vec![0; len as usize];
+                file.read_exact(&mut block).ok()?;
+                return Self::parse_flac_cuesheet(&block);
+            }
+            // the last block before the audio
+            if header[0] & 0x80 != 0 {
+                return None;
+            }
+            file.seek(SeekFrom::Current(len as i64)).ok()?;
+        }
+    }
+
+    // symphonia hands the index points over without their numbers, which leaves no telling
+    // INDEX 00 and 01 apart from 01 and 02
+    fn parse_flac_cuesheet(block: &[u8]) -> Option<Self> {
+        // the catalog number, lead-in, flags and reserved bytes come first
+        let count = *block.get(395)?;
+        let mut pos = 396;
+
+        let mut tracks = Vec::new();
+        for _ in 0..count {
+            let offset = u64::from_be_bytes(block.get(pos..pos + 8)?.try_into().unwrap());
+            let number = *block.get(pos + 8)?;
+            let index_count = *block.get(pos + 35)? as usize;
+            let indices = block.get(pos + 36..pos + 36 + 12 * index_count)?;
+            pos += 36 + 12 * index_count;
+
+            // 170 is the lead-out of a CD, 255 the one of every other kind of sheet
+            if number == 170 || number == 255 {
+                continue;
+            }
+            // like in a .cue the track starts at INDEX 01, its pregap goes to the one before
+            let index_01 = indices
+                .chunks_exact(12)
+                .find(|index| index[8] == 1)
+                .or_else(|| indices.chunks_exact(12).next())
+                .map_or(0, |index| {
+                    u64::from_be_bytes(index[..8].try_into().unwrap())
+                });
+            tracks.push(CueTrack {
+                number: number as u32,
+                title: format!("Track {}", number),
+                performer: String::new(),
+                start: CueTime::Samples(offset + index_01),
+            });
+        }
+
+        (tracks.len() > 1).then(|| CueSheet {
+            tracks,
+            ..Default::default()
+        })
+    }
+
+    /// Looks for a .cue file next to `audio_path` that describes it.
+    pub fn find_for(audio_path: &Path) -> Option<Self> {
+        let parent = audio_path.parent()?;
+        let audio_file_name = audio_path.file_name()?.to_string_lossy().to_string();
+
+        let mut cue_paths: Vec<_> = fs::read_dir(parent)
+            .ok()?
+            .flatten()
+            .map(|entry| entry.path())
+            .filter(|path| {
+                path.extension()
+                    .is_some_and(|ext| ext.eq_ignore_ascii_case("cue"))
+            })
+            .collect();
+        cue_paths.sort();
+
+        cue_paths.into_iter().find_map(|cue_path| {
+            let text = decode_text(fs::read(&cue_path).ok()?);
+            let sheet = CueSheet::parse(&text, Some(&audio_file_name));
+            // one track is no reason to split anything
+            (sheet.tracks.len() > 1).then_some(sheet)
+        })
+    }
+
+    /// Sample ranges of every track in a stream of `total_samples` samples.
+    ///
+    /// The first track starts at the very beginning, so a hidden pregap stays in the output.
+    pub fn track_ranges(&self, sample_rate: u32, total_samples: usize) -> Vec<(usize, usize)> {
+        let starts: Vec<usize> = self
+            .tracks
+            .iter()
+            .enumerate()
+            .map(|(i, track)| {
+                if i == 0 {
+                    0
+                } else {
+                    (track.start.to_samples(sample_rate) as usize).min(total_samples)
+                }
+            })
+            .collect();
+
+        starts
+            .iter()
+            .enumerate()
+            .map(|(i, &start)| {
+                let end = starts.get(i + 1).copied().unwrap_or(total_samples);
+                (start, end.max(start))
+            })
+            .collect()
+    }
+}
+
+// Rippers often write the sheet for the .wav they started from and compress afterwards,
+// so "Album.wav" in the sheet still describes "Album.flac"
+fn same_file_name(in_sheet: &str, actual: &str) -> bool {
+    let in_sheet = Path::new(in_sheet);
+    let actual = Path::new(actual);
+    let eq = |a: Option<&std::ffi::OsStr>, b: Option<&std::ffi::OsStr>| match (a, b) {
+        (Some(a), Some(b)) => a
+            .to_string_lossy()
+            .eq_ignore_ascii_case(&b.to_string_lossy()),
+        _ => false,
+    };
+    eq(in_sheet.file_name(), actual.file_name()) || eq(in_sheet.file_stem(), actual.file_stem())
+}
+
+fn unquote(str: &str) -> String {
+    str.trim().trim_matches('"').to_string()
+}
+
+// "mm:ss:ff" -> CD frames
+fn parse_msf(time: &str) -> Option<u64> {
+    let mut parts = time.split(':').map(|part| part.parse::<u64>().ok());
+    let (Some(Some(minutes)), Some(Some(seconds)), Some(Some(frames))) =
+        (parts.next(), parts.next(), parts.next())
+    else {
+        return None;
+    };
+    Some((minutes * 60 + seconds) * FRAMES_PER_SECOND + frames)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SHEET: &str = r#"REM GENRE Rock
+REM DATE 1999
+PERFORMER "The Band"
+TITLE "Live Album"
+FILE "Live Album.flac" WAVE
+  TRACK 01 AUDIO
+    TITLE "Intro"
+    INDEX 01 00:00:00
+  TRACK 02 AUDIO
+    TITLE "Song"
+    PERFORMER "The Band feat. Guest"
+    INDEX 00 02:59:70
+    INDEX 01 03:00:05
+  TRACK 03 AUDIO
+    TITLE "Outro"
+    INDEX 01 07:30:00
+"#;
+
+    #[test]
+    fn test_parse_cue_sheet() {
+        let sheet = CueSheet::parse(SHEET, Some("live album.FLAC"));
+
+        assert_eq!(sheet.title, "Live Album");
+        assert_eq!(sheet.performer, "The Band");
+        assert_eq!(sheet.year, "1999");
+        assert_eq!(sheet.tracks.len(), 3);
+        assert_eq!(sheet.tracks[1].number, 2);
+        assert_eq!(sheet.tracks[1].title, "Song");
+        assert_eq!(sheet.tracks[1].performer, "The Band feat. Guest");
+        assert_eq!(sheet.tracks[1].start, CueTime::Frames(180 * 75 + 5));
+
+        assert_eq!(
+            CueSheet::parse(SHEET, Some("Live Album.wav")).tracks.len(),
+            3
+        );
+        assert!(CueSheet::parse(SHEET, Some("Other.flac")).tracks.is_empty());
+    }
+
+    #[test]
+    fn test_flac_cuesheet() {
+        let track = |offset: u64, number: u8, indices: &[(u64, u8)]| {
+            let mut bytes = offset.to_be_bytes().to_vec();
+            bytes.push(number);
+            bytes.extend_from_slice(&[0; 26]);
+            bytes.push(indices.len() as u8);
+            for &(offset, number) in indices {
+                bytes.extend_from_slice(&offset.to_be_bytes());
+                bytes.extend_from_slice(&[number, 0, 0, 0]);
+            }
+            bytes
+        };
+        let mut cuesheet = vec![0; 395];
+        cuesheet.push(4);
+        cuesheet.extend(track(0, 1, &[(0, 1)]));
+        // a pregap of ten CD frames
+        cuesheet.extend(track(180 * 44_100, 2, &[(0, 0), (5880, 1)]));
+        cuesheet.extend(track(400 * 44_100, 3, &[(0, 1), (588, 2)]));
+        cuesheet.extend(track(600 * 44_100, 170, &[]));
+
+        let mut flac = b"fLaC".to_vec();
+        flac.extend_from_slice(&[0, 0, 0, 34]);
+        flac.extend_from_slice(&[0; 34]);
+        flac.extend_from_slice(&[0x85, 0, (cuesheet.len() >> 8) as u8, cuesheet.len() as u8]);
+        flac.extend(cuesheet);
+
+        let path = std::env::temp_dir().join(format!("m2psp-cuesheet-{}.flac", std::process::id()));
+        fs::write(&path, &flac).unwrap();
+        let sheet = CueSheet::from_flac(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        let starts: Vec<CueTime> = sheet.tracks.iter().map(|track| track.start).collect();
+        assert_eq!(
+            starts,
+            [
+                CueTime::Samples(0),
+                CueTime::Samples(180 * 44_100 + 5880),
+                CueTime::Samples(400 * 44_100),
+            ]
+        );
+        assert_eq!(sheet.tracks[2].title, "Track 3");
+    }
+
+    #[test]
+    fn test_track_ranges_are_sample_accurate() {
+        let sheet = CueSheet::parse(SHEET, None);
+        let total_samples = 600 * 44_100;
+
+        assert_eq!(
+            sheet.track_ranges(44_100, total_samples),
+            vec![
+                (0, 180 * 44_100 + 5 * 588),
+                (180 * 44_100 + 5 * 588, 450 * 44_100),
+                (450 * 44_100, total_samples),
+            ]
+        );
+    }
+}
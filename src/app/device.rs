@@ -0,0 +1,148 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// A mounted volume that looks like a PSP Memory Stick (or the internal storage of a PSP Go).
+#[derive(Clone, Debug, PartialEq)]
+pub struct MemoryStick {
+    pub root: PathBuf,
+    // where the XMB looks for music, might not exist yet on a freshly formatted stick
+    pub music_dir: PathBuf,
+    pub has_music_dir: bool,
+}
+
+/// Every directory a removable drive could be mounted at on this OS.
+pub fn candidate_mount_points() -> Vec<PathBuf> {
+    let mut mount_points = Vec::new();
+
+    if cfg!(windows) {
+        for letter in b'A'..=b'Z' {
+            let root = PathBuf::from(format!("{}:\\", letter as char));
+            if root.exists() {
+                mount_points.push(root);
+            }
+        }
+    } else {
+        let mut parents = vec![PathBuf::from("/Volumes"), PathBuf::from("/media")];
+        if let Ok(user) = std::env::var("USER") {
+            parents.push(Path::new("/media").join(&user));
+            parents.push(Path::new("/run/media").join(&user));
+        }
+        parents.push(PathBuf::from("/mnt"));
+
+        for parent in parents {
+            let Ok(entries) = fs::read_dir(&parent) else {
+                continue;
+            };
+            mount_points.extend(
+                entries
+                    .flatten()
+                    .map(|entry| entry.path())
+                    .filter(|path| path.is_dir()),
+            );
+        }
+    }
+
+    mount_points
+}
+
+pub fn detect_memory_sticks() -> Vec<MemoryStick> {
+    find_memory_sticks(&candidate_mount_points())
+}
+
+/// Keeps the mount points with a `PSP/` folder in their root.
+pub fn find_memory_sticks(mount_points: &[PathBuf]) -> Vec<MemoryStick> {
+    mount_points
+        .iter()
+        .filter(|root| find_child_dir(root, "PSP").is_some())
+        .map(|root| {
+            let existing_music_dir = find_child_dir(root, "MUSIC");
+            MemoryStick {
+                root: root.clone(),
+                has_music_dir: existing_music_dir.is_some(),
+                music_dir: existing_music_dir.unwrap_or_else(|| root.join("MUSIC")),
+            }
+        })
+        .collect()
+}
+
+// FAT keeps the case it was written with, so a stick formatted elsewhere might say "Music"
+fn find_child_dir(parent: &Path, name: &str) -> Option<PathBuf> {
+    fs::read_dir(parent)
+        .ok()?
+        .flatten()
+        .find(|entry| {
+            entry
+                .file_name()
+                .to_string_lossy()
+                .eq_ignore_ascii_case(name)
+                && entry.file_type().is_ok_and(|t| t.is_dir())
+        })
+        .map(|entry| entry.path())
+}
+
+/// Explains what is wrong with `destination` if the PSP would not find music written there.
+pub fn destination_warning(destination: &Path, sticks: &[MemoryStick]) -> Option<String> {
+    let stick = sticks
+        .iter()
+        .find(|stick| destination.starts_with(&stick.root))
+        .cloned()
+        .or_else(|| find_memory_sticks(&[destination.to_path_buf()]).pop())?;
+
+    if destination.starts_with(&stick.music_dir) {
+        None
+    } else {
+        Some(format!(
+            "The PSP only finds music inside {}",
+            stick.music_dir.display()
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fake_mounts() -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("m2psp-mounts-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+
+        // a stick that was already used for music, one that never was, and a usb drive
+        fs::create_dir_all(dir.join("PSP_STICK/PSP/GAME")).unwrap();
+        fs::create_dir_all(dir.join("PSP_STICK/MUSIC")).unwrap();
+        fs::create_dir_all(dir.join("NEW_STICK/psp")).unwrap();
+        fs::create_dir_all(dir.join("USB_DRIVE/MUSIC")).unwrap();
+        dir
+    }
+
+    #[test]
+    fn test_find_memory_sticks() {
+        let dir = fake_mounts();
+        let mount_points: Vec<PathBuf> = ["PSP_STICK", "NEW_STICK", "USB_DRIVE"]
+            .iter()
+            .map(|name| dir.join(name))
+            .collect();
+
+        let sticks = find_memory_sticks(&mount_points);
+        assert_eq!(sticks.len(), 2);
+
+        assert_eq!(sticks[0].root, dir.join("PSP_STICK"));
+        assert!(sticks[0].has_music_dir);
+        assert_eq!(sticks[0].music_dir, dir.join("PSP_STICK/MUSIC"));
+
+        assert_eq!(sticks[1].root, dir.join("NEW_STICK"));
+        assert!(!sticks[1].has_music_dir);
+        assert_eq!(sticks[1].music_dir, dir.join("NEW_STICK/MUSIC"));
+
+        // the stick root, or any other folder on the stick, is the wrong place
+        assert!(destination_warning(&dir.join("PSP_STICK"), &sticks).is_some());
+        assert!(destination_warning(&dir.join("PSP_STICK/PSP/GAME"), &sticks).is_some());
+        assert!(destination_warning(&dir.join("PSP_STICK/MUSIC"), &sticks).is_none());
+        assert!(destination_warning(&dir.join("PSP_STICK/MUSIC/Album"), &sticks).is_none());
+        assert!(destination_warning(&dir.join("USB_DRIVE"), &sticks).is_none());
+
+        // a stick root that wasn't detected still gets caught by looking at it directly
+        assert!(destination_warning(&dir.join("NEW_STICK"), &[]).is_some());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,223 @@
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+
+use rayon::prelude::*;
+
+use crate::app::converter::{AudioConverter, AudioFiletype, EncoderSettings, TagOverrides};
+use crate::app::planner::format_bytes;
+
+#[derive(Clone, Copy, Debug, PartialEq, serde::Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PlanAction {
+    Convert,
+    Copy,
+    Skip,
+    Fail,
+}
+
+/// One file the conversion would write, or a source it wouldn't get anything out of.
+#[derive(Clone, Debug, PartialEq, serde::Serialize)]
+pub struct PlanEntry {
+    pub source: PathBuf,
+    pub action: PlanAction,
+    pub output: Option<PathBuf>,
+    pub estimated_bytes: u64,
+    // why it's skipped or fails
+    pub reason: Option<String>,
+}
+
+/// Output file -> the source that gets to write it.
+pub type OutputOwners = HashMap<PathBuf, PathBuf>;
+
+/// What a conversion would do, worked out without writing anything.
+#[derive(Clone, Debug, Default, serde::Serialize)]
+pub struct ConversionPlan {
+    pub destination: PathBuf,
+    pub entries: Vec<PlanEntry>,
+}
+
+impl ConversionPlan {
+    pub fn new(
+        files: &[PathBuf],
+        destination: &Path,
+        settings_for: impl Fn(&PathBuf) -> EncoderSettings + Sync,
+        tag_overrides: &HashMap<PathBuf, TagOverrides>,
+    ) -> Self {
+        let mut entries: Vec<PlanEntry> = files
+            .par_iter()
+            .flat_map_iter(|source| {
+                let outputs =
+                    AudioConverter::new(source.clone(), AudioFiletype::MP3).and_then(|converter| {
+                        converter
+                            .with_encoder_settings(settings_for(source))
+                            .with_tag_overrides(
+                                tag_overrides.get(source).cloned().unwrap_or_default(),
+                            )
+                            .plan_outputs(destination)
+                    });
+                match outputs {
+                    Ok(outputs) => outputs
+                        .into_iter()
+                        .map(|output| PlanEntry {
+                            source: source.clone(),
+                            action: if output.passthrough {
+                                PlanAction::Copy
+                            } else {
+                                PlanAction::Convert
+                            },
+                            output: Some(output.output_path),
+                            estimated_bytes: output.estimated_bytes,
+                            reason: None,
+                        })
+                        .collect(),
+                    Err(e) => vec![PlanEntry {
+                        source: source.clone(),
+                        action: PlanAction::Fail,
+                        output: None,
+                        estimated_bytes: 0,
+                        reason: Some(e.to_string()),
+                    }],
+                }
+            })
+            .collect();
+        entries.sort_by(|a, b| (&a.source, &a.output).cmp(&(&b.source, &b.output)));
+
+        // two tracks with the same album, number and title would overwrite each other
+        let mut taken = HashSet::new();
+        for entry in entries.iter_mut() {
+            if let Some(output) = &entry.output {
+                if !taken.insert(output.clone()) {
+                    entry.action = PlanAction::Fail;
+                    entry.reason = Some("another track is written to the same file".to_string());
+                    entry.estimated_bytes = 0;
+                }
+            }
+        }
+
+        ConversionPlan {
+            destination: destination.to_path_buf(),
+            entries,
+        }
+    }
+
+    /// Who writes each of the files that would be written, the first of the sources in
+    /// order when several tracks would end up in one.
+    pub fn output_owners(&self) -> OutputOwners {
+        self.entries
+            .iter()
+            .filter(|entry| entry.action != PlanAction::Fail)
+            .filter_map(|entry| Some((entry.output.clone()?, entry.source.clone())))
+            .collect()
+    }
+
+    pub fn skip(&mut self, source: PathBuf, reason: &str) {
+        self.entries.push(PlanEntry {
+            source,
+            action: PlanAction::Skip,
+            output: None,
+            estimated_bytes: 0,
+            reason: Some(reason.to_string()),
+        });
+    }
+
+    pub fn count(&self, action: PlanAction) -> usize {
+        self.entries
+            .iter()
+            .filter(|entry| entry.action == action)
+            .count()
+    }
+
+    pub fn estimated_bytes(&self) -> u64 {
+        self.entries.iter().map(|entry| entry.estimated_bytes).sum()
+    }
+
+    pub fn summary(&self) -> String {
+        format!(
+            "{} to convert, {} to copy, {} skipped, {} failing, about {}",
+            self.count(PlanAction::Convert),
+            self.count(PlanAction::Copy),
+            self.count(PlanAction::Skip),
+            self.count(PlanAction::Fail),
+            format_bytes(self.estimated_bytes())
+        )
+    }
+
+    /// Pretty printed and in a stable order, so that two plans diff well.
+    pub fn to_json(&self) -> String {
+        serde_json::to_string_pretty(self).expect("a plan is always valid JSON")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::app::converter::fixtures::write_album_track;
+    use crate::app::loudness::GainMode;
+    use std::fs;
+
+    #[test]
+    fn test_plan_without_sources() {
+        let gone = PathBuf::from("/m2psp-gone-for-good/01.flac");
+        let mut plan = ConversionPlan::new(
+            std::slice::from_ref(&gone),
+            Path::new("/media/PSP/MUSIC"),
+            |_| EncoderSettings::default(),
+            &HashMap::new(),
+        );
+        plan.skip(PathBuf::from("/music/live.flac"), "left out in the library");
+
+        assert_eq!(plan.entries[0].source, gone);
+        assert_eq!(plan.entries[0].action, PlanAction::Fail);
+        assert_eq!(plan.count(PlanAction::Skip), 1);
+        assert!(plan
+            .summary()
+            .starts_with("0 to convert, 0 to copy, 1 skipped, 1 failing"));
+
+        let json: serde_json::Value = serde_json::from_str(&plan.to_json()).unwrap();
+        assert_eq!(json["destination"], "/media/PSP/MUSIC");
+        assert_eq!(json["entries"][1]["action"], "skip");
+        assert_eq!(json["entries"][1]["reason"], "left out in the library");
+        assert!(json["entries"][1]["output"].is_null());
+    }
+
+    #[test]
+    fn test_plan_real_files() {
+        let dir = std::env::temp_dir().join(format!("m2psp-plan-{}", std::process::id()));
+        let (first, second) = (dir.join("a/01.mp3"), dir.join("b/01.mp3"));
+        for path in [&first, &second] {
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            // the same tags twice, a rip of the album kept in two folders
+            write_album_track(path, "Album", "1", "Song", 1);
+        }
+        let destination = dir.join("out");
+        let plan_with = |encoder_settings: EncoderSettings| {
+            ConversionPlan::new(
+                &[second.clone(), first.clone()],
+                &destination,
+                |_| encoder_settings.clone(),
+                &HashMap::new(),
+            )
+        };
+
+        // plays on the PSP as it is
+        let plan = plan_with(EncoderSettings::default());
+        let output = destination.join("Album/01 - Song.mp3");
+        assert_eq!(plan.entries[0].source, first);
+        assert_eq!(plan.entries[0].output, Some(output.clone()));
+        assert_eq!(plan.entries[0].action, PlanAction::Copy);
+        // the second copy would overwrite the first
+        assert_eq!(plan.entries[1].source, second);
+        assert_eq!(plan.entries[1].action, PlanAction::Fail);
+        assert_eq!(plan.output_owners(), HashMap::from([(output, first.clone())]));
+
+        // changing the volume takes re-encoding
+        let plan = plan_with(EncoderSettings {
+            gain_mode: GainMode::Track,
+            ..Default::default()
+        });
+        assert_eq!(plan.entries[0].action, PlanAction::Convert);
+        assert_eq!(plan.count(PlanAction::Fail), 1);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,197 @@
+// ID3v2.3 is the newest version the PSP reads, v2.4 tags show up as "Unknown"
+const VERSION: [u8; 2] = [3, 0];
+
+const ENCODING_LATIN_1: u8 = 0;
+const ENCODING_UTF_16: u8 = 1;
+
+// SYLT timestamps in milliseconds, holding lyrics
+const TIMESTAMP_FORMAT_MILLIS: u8 = 2;
+const CONTENT_TYPE_LYRICS: u8 = 1;
+
+// the APIC picture type the XMB shows
+const PICTURE_TYPE_FRONT_COVER: u8 = 3;
+
+/// An ID3v2.3 tag, built frame by frame and prepended to the MP3 frames.
+#[derive(Default)]
+pub struct Id3v2Tag {
+    frames: Vec<u8>,
+}
+
+impl Id3v2Tag {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a text frame such as TIT2 or TRCK, empty values are left out.
+    pub fn text(&mut self, frame_id: &str, value: &str) -> &mut Self {
+        if value.is_empty() {
+            return self;
+        }
+        let encoding = encoding_for(value);
+        let mut body = vec![encoding];
+        body.extend(encode(value, encoding));
+        self.frame(frame_id, &body)
+    }
+
+    pub fn comment(&mut self, text: &str) -> &mut Self {
+        if text.is_empty() {
+            return self;
+        }
+        let encoding = encoding_for(text);
+        let mut body = vec![encoding];
+        body.extend_from_slice(b"eng");
+        // no short description
+        body.extend(terminator(encoding));
+        body.extend(encode(text, encoding));
+        self.frame("COMM", &body)
+    }
+
+    /// Unsynchronised lyrics, a USLT frame.
+    pub fn lyrics(&mut self, text: &str) -> &mut Self {
+        if text.is_empty() {
+            return self;
+        }
+        let encoding = encoding_for(text);
+        let mut body = vec![encoding];
+        body.extend_from_slice(b"eng");
+        // no content descriptor
+        body.extend(terminator(encoding));
+        body.extend(encode(text, encoding));
+        self.frame("USLT", &body)
+    }
+
+    /// Lyrics timed line by line, a SYLT frame.
+    pub fn synced_lyrics(&mut self, lines: &[(u32, String)]) -> &mut Self {
+        if lines.is_empty() {
+            return self;
+        }
+        let all_text: String = lines.iter().map(|(_, line)| line.as_str()).collect();
+        let encoding = encoding_for(&all_text);
+        let mut body = vec![encoding];
+        body.extend_from_slice(b"eng");
+        body.push(TIMESTAMP_FORMAT_MILLIS);
+        body.push(CONTENT_TYPE_LYRICS);
+        body.extend(terminator(encoding));
+        for (ms, line) in lines {
+            body.extend(encode(line, encoding));
+            body.extend(terminator(encoding));
+            body.extend_from_slice(&ms.to_be_bytes());
+        }
+        self.frame("SYLT", &body)
+    }
+
+    /// A TXXX frame, where ReplayGain values and the like go.
+    pub fn user_text(&mut self, description: &str, value: &str) -> &mut Self {
+        let encoding = encoding_for(&format!("{}{}", description, value));
+        let mut body = vec![encoding];
+        body.extend(encode(description, encoding));
+        body.extend(terminator(encoding));
+        body.extend(encode(value, encoding));
+        self.frame("TXXX", &body)
+    }
+
+    pub fn picture(&mut self, image_data: &[u8]) -> &mut Self {
+        if image_data.is_empty() {
+            return self;
+        }
+        let mime_type: &[u8] = if image_data.starts_with(b"\x89PNG") {
+            b"image/png"
+        } else {
+            b"image/jpeg"
+        };
+        let mut body = vec![ENCODING_LATIN_1];
+        body.extend_from_slice(mime_type);
+        body.push(0);
+        body.push(PICTURE_TYPE_FRONT_COVER);
+        // no description
+        body.push(0);
+        body.extend_from_slice(image_data);
+        self.frame("APIC", &body)
+    }
+
+    fn frame(&mut self, frame_id: &str, body: &[u8]) -> &mut Self {
+        debug_assert_eq!(frame_id.len(), 4);
+        self.frames.extend_from_slice(frame_id.as_bytes());
+        // unlike the tag size, frame sizes in v2.3 are plain big endian
+        self.frames
+            .extend_from_slice(&(body.len() as u32).to_be_bytes());
+        self.frames.extend_from_slice(&[0, 0]);
+        self.frames.extend_from_slice(body);
+        self
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(10 + self.frames.len());
+        bytes.extend_from_slice(b"ID3");
+        bytes.extend_from_slice(&VERSION);
+        // no flags
+        bytes.push(0);
+        bytes.extend_from_slice(&synchsafe(self.frames.len() as u32));
+        bytes.extend_from_slice(&self.frames);
+        bytes
+    }
+}
+
+// Latin-1 when it fits, the PSP handles it everywhere, UTF-16 for everything else
+fn encoding_for(text: &str) -> u8 {
+    if text.chars().all(|c| (c as u32) <= 0xFF) {
+        ENCODING_LATIN_1
+    } else {
+        ENCODING_UTF_16
+    }
+}
+
+fn encode(text: &str, encoding: u8) -> Vec<u8> {
+    if encoding == ENCODING_LATIN_1 {
+        text.chars().map(|c| c as u8).collect()
+    } else {
+        let mut bytes = vec![0xFF, 0xFE];
+        bytes.extend(text.encode_utf16().flat_map(|unit| unit.to_le_bytes()));
+        bytes
+    }
+}
+
+fn terminator(encoding: u8) -> &'static [u8] {
+    if encoding == ENCODING_LATIN_1 {
+        &[0]
+    } else {
+        &[0, 0]
+    }
+}
+
+// 7 bits per byte, so the size never looks like an MPEG sync word
+fn synchsafe(size: u32) -> [u8; 4] {
+    [
+        (size >> 21) as u8 & 0x7F,
+        (size >> 14) as u8 & 0x7F,
+        (size >> 7) as u8 & 0x7F,
+        size as u8 & 0x7F,
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tag_layout() {
+        let bytes = Id3v2Tag::new()
+            .text("TIT2", "Café")
+            .text("TPE1", "")
+            .text("TALB", "日本")
+            .to_bytes();
+
+        assert_eq!(&bytes[..6], b"ID3\x03\x00\x00");
+        assert_eq!(&bytes[6..10], &synchsafe(bytes.len() as u32 - 10));
+
+        // Latin-1 title, the empty artist is skipped entirely
+        assert_eq!(&bytes[10..14], b"TIT2");
+        assert_eq!(&bytes[14..18], &[0, 0, 0, 5]);
+        assert_eq!(&bytes[20..25], b"\x00Caf\xE9");
+
+        // UTF-16 with a BOM for the album
+        assert_eq!(&bytes[25..29], b"TALB");
+        assert_eq!(&bytes[35..41], &[1, 0xFF, 0xFE, 0xE5, 0x65, 0x2C]);
+        assert_eq!(bytes.len(), 25 + 10 + 7);
+    }
+}
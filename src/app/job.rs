@@ -0,0 +1,219 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use glob::Pattern;
+use log::warn;
+
+use crate::app::atomic_write::write_atomically;
+use crate::app::converter::EncoderSettings;
+use crate::app::playlist::PlaylistOptions;
+use crate::app::profiles::find_profile;
+use crate::app::sources::SourceEntry;
+
+/// A whole sync job, kept in a file like `m2psp.toml` so that `m2psp sync m2psp.toml` runs the
+/// same conversion every time.
+#[derive(Clone, Debug, Default, PartialEq, serde::Deserialize, serde::Serialize)]
+#[serde(default)]
+pub struct Job {
+    pub destination: PathBuf,
+    // where the conversion report goes, the destination when left out
+    pub report_dir: Option<PathBuf>,
+    // a built-in or imported profile, the default settings when empty
+    pub profile: String,
+    // win over the profile's, written when the settings were changed after picking it
+    pub encoder_settings: Option<EncoderSettings>,
+    pub playlists: Option<PlaylistOptions>,
+    pub sources: Vec<SourceRoot>,
+}
+
+/// A folder, track or playlist to convert, and how it differs from the rest of the job.
+#[derive(Clone, Debug, Default, PartialEq, serde::Deserialize, serde::Serialize)]
+#[serde(default)]
+pub struct SourceRoot {
+    pub path: PathBuf,
+    // takes the subfolders along when `path` is a folder
+    pub recursive: bool,
+    // glob patterns, relative to `path`
+    pub exclude: Vec<String>,
+    pub bitrate_kbps: Option<u16>,
+    pub sample_rate: Option<u32>,
+    pub audiobook: Option<bool>,
+    pub trim_silence: Option<bool>,
+}
+
+impl SourceRoot {
+    pub fn new(path: PathBuf) -> Self {
+        SourceRoot {
+            path,
+            ..Default::default()
+        }
+    }
+
+    /// None when `path` is gone, or neither a folder, a track nor a playlist.
+    pub fn entry(&self) -> Option<SourceEntry> {
+        match SourceEntry::from_path(self.path.clone())? {
+            SourceEntry::Folder(path) if self.recursive => Some(SourceEntry::RecursiveFolder(path)),
+            entry => Some(entry),
+        }
+    }
+
+    pub fn contains(&self, file: &Path) -> bool {
+        file.starts_with(&self.path)
+    }
+
+    pub fn excludes(&self, file: &Path) -> bool {
+        let Ok(relative) = file.strip_prefix(&self.path) else {
+            return false;
+        };
+        self.exclude
+            .iter()
+            .any(|pattern| match Pattern::new(pattern) {
+                Ok(pattern) => pattern.matches_path(relative),
+                Err(e) => {
+                    warn!("Error in exclude pattern {:?}: {}", pattern, e);
+                    false
+                }
+            })
+    }
+
+    pub fn apply(&self, settings: &mut EncoderSettings) {
+        if let Some(bitrate_kbps) = self.bitrate_kbps {
+            settings.bitrate_kbps = bitrate_kbps;
+        }
+        if self.sample_rate.is_some() {
+            settings.sample_rate = self.sample_rate;
+        }
+        if let Some(audiobook) = self.audiobook {
+            settings.audiobook.enabled = audiobook;
+        }
+        if let Some(trim_silence) = self.trim_silence {
+            settings.silence_trim.enabled = trim_silence;
+        }
+    }
+}
+
+/// Drops the files an exclude pattern of their root matches, and sorts the rest so a job always
+/// goes through them in the same order.
+pub fn included_files(roots: &[SourceRoot], mut files: Vec<PathBuf>) -> Vec<PathBuf> {
+    files.retain(|file| !roots.iter().any(|root| root.excludes(file)));
+    files.sort();
+    files.dedup();
+    files
+}
+
+/// The files `included_files` drops, for telling the user what a job leaves out.
+pub fn excluded_files(roots: &[SourceRoot], files: &[PathBuf]) -> Vec<PathBuf> {
+    let mut excluded: Vec<PathBuf> = files
+        .iter()
+        .filter(|file| roots.iter().any(|root| root.excludes(file)))
+        .cloned()
+        .collect();
+    excluded.sort();
+    excluded.dedup();
+    excluded
+}
+
+impl Job {
+    /// Paths in the file may be relative to the folder the file is in.
+    pub fn load(path: &Path) -> io::Result<Self> {
+        let text = fs::read_to_string(path)?;
+        let mut job: Job =
+            toml::from_str(&text).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+
+        let base = path.parent().unwrap_or(Path::new(""));
+        job.destination = base.join(&job.destination);
+        job.report_dir = job.report_dir.map(|dir| base.join(dir));
+        for source in job.sources.iter_mut() {
+            source.path = base.join(&source.path);
+        }
+        Ok(job)
+    }
+
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        let text = toml::to_string_pretty(self)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        write_atomically(path, text.as_bytes())
+    }
+
+    /// The encoder settings and playlist options the job runs with.
+    pub fn resolve(&self) -> Result<(EncoderSettings, PlaylistOptions), String> {
+        let profile = if self.profile.is_empty() {
+            Default::default()
+        } else {
+            find_profile(&self.profile).ok_or_else(|| {
+                format!("no profile called {:?}, see `m2psp profiles`", self.profile)
+            })?
+        };
+        Ok((
+            self.encoder_settings
+                .clone()
+                .unwrap_or(profile.encoder_settings),
+            self.playlists.unwrap_or(profile.playlist_options),
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_load_job() {
+        let dir = std::env::temp_dir().join(format!("m2psp-job-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("m2psp.toml");
+        fs::write(
+            &path,
+            r#"
+            destination = "/media/PSP/MUSIC"
+            profile = "psp-1000 (2 gb stick)"
+
+            [[sources]]
+            path = "Music"
+            exclude = ["Live/**", "*.wav"]
+
+            [[sources]]
+            path = "/home/me/Audiobooks"
+            bitrate_kbps = 48
+            audiobook = true
+            "#,
+        )
+        .unwrap();
+
+        let job = Job::load(&path).unwrap();
+        assert_eq!(job.destination, Path::new("/media/PSP/MUSIC"));
+        let music = &job.sources[0];
+        assert_eq!(music.path, dir.join("Music"));
+        assert!(music.excludes(&dir.join("Music/Live/1999/01.flac")));
+        assert!(music.excludes(&dir.join("Music/Album/intro.wav")));
+        assert!(!music.excludes(&dir.join("Music/Album/01.flac")));
+        let files = [
+            dir.join("Music/Album/01.flac"),
+            dir.join("Music/Live/01.flac"),
+        ];
+        assert_eq!(
+            excluded_files(&job.sources, &files),
+            [dir.join("Music/Live/01.flac")]
+        );
+
+        let (encoder_settings, _) = job.resolve().unwrap();
+        assert_eq!(encoder_settings.bitrate_kbps, 160);
+        let mut audiobook_settings = encoder_settings;
+        job.sources[1].apply(&mut audiobook_settings);
+        assert_eq!(audiobook_settings.bitrate_kbps, 48);
+        assert!(audiobook_settings.audiobook.enabled);
+
+        // what gets saved loads back the same
+        job.save(&path).unwrap();
+        assert_eq!(Job::load(&path).unwrap(), job);
+
+        let unknown = Job {
+            profile: "Gramophone".to_string(),
+            ..job
+        };
+        assert!(unknown.resolve().is_err());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,196 @@
+use std::borrow::Cow;
+
+use mp3lame_encoder::{
+    ffi, max_required_buffer_size, Bitrate, BuildError, Builder, DualPcm, Encoder, FlushGap,
+    FlushNoGap, Mode, MonoPcm, Quality,
+};
+
+/// A LAME encoder that can be fed several tracks back to back, like `lame --nogap` does.
+///
+/// Every track comes out with its own LAME/Xing info frame, carrying the frame count, encoder
+/// delay and padding and the track's ReplayGain, so gapless aware players can trim it exactly.
+pub struct LameSession {
+    encoder: Encoder,
+    // the handle `encoder` owns, mp3lame-encoder only hands it out before building
+    lame: *mut ffi::lame_global_flags,
+    tracks_encoded: i32,
+    mono: bool,
+}
+
+impl LameSession {
+    /// `track_count` is how many tracks will go through this session, 1 for a standalone file.
+    /// With `mono` set the output has one channel, which spends the whole bitrate on it. Tracks
+    /// can come in mono then, or in stereo to be mixed down.
+    /// LAME resamples on its own when `out_sample_rate` differs from `sample_rate`.
+    pub fn new(
+        sample_rate: u32,
+        out_sample_rate: u32,
+        bitrate: Bitrate,
+        track_count: usize,
+        mono: bool,
+    ) -> Result<Self, BuildError> {
+        let mut builder = Builder::new().ok_or(BuildError::NoMem)?;
+        if mono {
+            builder.set_num_channels(1)?;
+            builder.set_mode(Mode::Mono)?;
+        } else {
+            builder.set_num_channels(2)?;
+        }
+        builder.set_sample_rate(sample_rate)?;
+        builder.set_brate(bitrate)?;
+        builder.set_quality(Quality::Best)?;
+        builder.set_to_write_vbr_tag(true)?;
+
+        let lame = unsafe { builder.as_ptr() };
+        unsafe {
+            // the ID3 tag is written by us, LAME can only hold one for the whole session
+            ffi::lame_set_write_id3tag_automatic(lame, 0);
+            ffi::lame_set_findReplayGain(lame, 1);
+            ffi::lame_set_out_samplerate(lame, out_sample_rate as i32);
+            if track_count > 1 {
+                ffi::lame_set_nogap_total(lame, track_count as i32);
+            }
+        }
+
+        Ok(Self {
+            encoder: builder.build()?,
+            lame,
+            tracks_encoded: 0,
+            mono,
+        })
+    }
+
+    /// Encodes one track and returns its MP3 frames, info frame included.
+    ///
+    /// Unless `is_last` is set, the samples that don't fill a whole frame are kept back and
+    /// open the next track, so nothing gets padded with silence in between.
+    pub fn encode_track(&mut self, pcm_data: &[Vec<f32>], is_last: bool) -> Vec<u8> {
+        unsafe {
+            if self.tracks_encoded > 0 {
+                // starts a new file: resets the frame count and writes a new blank info frame
+                ffi::lame_init_bitstream(self.lame);
+            }
+            ffi::lame_set_nogap_currentindex(self.lame, self.tracks_encoded);
+        }
+        self.tracks_encoded += 1;
+
+        let mut mp3_out_buffer = Vec::with_capacity(max_required_buffer_size(pcm_data[0].len()));
+        let encoded_size = if self.mono {
+            // audiobooks come decoded to one channel already
+            let mixed: Cow<'_, [f32]> = match pcm_data {
+                [mono] => Cow::Borrowed(mono),
+                [left, right, ..] => {
+                    Cow::Owned(left.iter().zip(right).map(|(l, r)| (l + r) / 2.0).collect())
+                }
+                [] => Cow::Borrowed(&[]),
+            };
+            self.encoder
+                .encode(MonoPcm(&mixed), mp3_out_buffer.spare_capacity_mut())
+        } else {
+            let input = DualPcm {
+                left: &pcm_data[0],
+                right: &pcm_data[1],
+            };
+            self.encoder
+                .encode(input, mp3_out_buffer.spare_capacity_mut())
+        }
+        .expect("To encode");
+        unsafe {
+            mp3_out_buffer.set_len(mp3_out_buffer.len().wrapping_add(encoded_size));
+        }
+
+        // the flush needs up to 7200 bytes on its own
+        mp3_out_buffer.reserve(7200);
+        let encoded_size = if is_last {
+            self.encoder
+                .flush::<FlushGap>(mp3_out_buffer.spare_capacity_mut())
+        } else {
+            self.encoder
+                .flush::<FlushNoGap>(mp3_out_buffer.spare_capacity_mut())
+        }
+        .expect("to flush");
+        unsafe {
+            mp3_out_buffer.set_len(mp3_out_buffer.len().wrapping_add(encoded_size));
+        }
+
+        self.write_info_frame(&mut mp3_out_buffer);
+        mp3_out_buffer
+    }
+
+    // LAME leaves a blank info frame at the start of every file and only fills it in once the
+    // whole file went through, since the frame count and padding aren't known before that
+    fn write_info_frame(&self, mp3_frames: &mut [u8]) {
+        let frame_size = unsafe { ffi::lame_get_lametag_frame(self.lame, std::ptr::null_mut(), 0) };
+        if frame_size == 0 || frame_size > mp3_frames.len() {
+            return;
+        }
+        unsafe {
+            ffi::lame_get_lametag_frame(self.lame, mp3_frames.as_mut_ptr(), frame_size);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SAMPLE_RATE: u32 = 44_100;
+
+    fn sine(samples: usize) -> Vec<Vec<f32>> {
+        let channel: Vec<f32> = (0..samples)
+            .map(|i| (i as f32 * 440.0 * std::f32::consts::TAU / SAMPLE_RATE as f32).sin() * 0.5)
+            .collect();
+        vec![channel.clone(), channel]
+    }
+
+    // (frame count, encoder delay, padding, radio ReplayGain field) out of the info frame
+    fn read_info_frame(mp3_frames: &[u8]) -> (u32, u32, u32, u16) {
+        let info = mp3_frames
+            .windows(4)
+            .position(|window| window == b"Info" || window == b"Xing")
+            .expect("no info frame");
+        let flags = u32::from_be_bytes(mp3_frames[info + 4..info + 8].try_into().unwrap());
+        assert_eq!(flags & 0x1, 0x1);
+        let frame_count = u32::from_be_bytes(mp3_frames[info + 8..info + 12].try_into().unwrap());
+
+        // frame count, byte count, TOC and quality all present, then the LAME extension
+        let lame = info + 8 + 4 + 4 + 100 + 4;
+        assert_eq!(&mp3_frames[lame..lame + 4], b"LAME");
+        let radio_gain = u16::from_be_bytes([mp3_frames[lame + 15], mp3_frames[lame + 16]]);
+        let delay_padding = &mp3_frames[lame + 21..lame + 24];
+        let delay = ((delay_padding[0] as u32) << 4) | (delay_padding[1] as u32 >> 4);
+        let padding = ((delay_padding[1] as u32 & 0x0F) << 8) | delay_padding[2] as u32;
+        (frame_count, delay, padding, radio_gain)
+    }
+
+    #[test]
+    fn test_info_frame_accounts_for_every_sample() {
+        let samples = SAMPLE_RATE as usize * 2 + 123;
+        let mut session =
+            LameSession::new(SAMPLE_RATE, SAMPLE_RATE, Bitrate::Kbps192, 1, false).unwrap();
+        let mp3_frames = session.encode_track(&sine(samples), true);
+
+        let (frame_count, delay, padding, radio_gain) = read_info_frame(&mp3_frames);
+        assert_eq!(frame_count * 1152, delay + samples as u32 + padding);
+        assert!(delay > 0);
+        // name code for "radio" gain, set once LAME analysed the track
+        assert_eq!(radio_gain & 0xE000, 0x2000);
+    }
+
+    #[test]
+    fn test_session_gives_every_track_its_own_info_frame() {
+        let mut session =
+            LameSession::new(SAMPLE_RATE, 48_000, Bitrate::Kbps128, 2, false).unwrap();
+        let first = session.encode_track(&sine(SAMPLE_RATE as usize), false);
+        let second = session.encode_track(&sine(SAMPLE_RATE as usize / 2), true);
+
+        for mp3_frames in [&first, &second] {
+            // the info frame opens the file, no audio in front of it
+            assert_eq!(mp3_frames[0], 0xFF);
+            // resampled from 44.1 to 48 kHz on the way
+            assert_eq!((mp3_frames[2] >> 2) & 0b11, 0b01);
+            let (frame_count, ..) = read_info_frame(mp3_frames);
+            assert!(frame_count > 0);
+        }
+    }
+}
@@ -0,0 +1,152 @@
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::Cursor;
+use std::path::{Path, PathBuf};
+
+use image::{DynamicImage, ImageReader};
+use log::error;
+use rayon::prelude::*;
+use symphonia::core::formats::FormatOptions;
+use symphonia::core::io::MediaSourceStream;
+use symphonia::core::meta::MetadataOptions;
+use symphonia::core::probe::Hint;
+
+use crate::app::converter::probe_track;
+
+pub const THUMBNAIL_PX: u32 = 96;
+
+/// One tile of the album grid.
+pub struct LibraryAlbum {
+    pub folder: PathBuf,
+    pub album: String,
+    pub artist: String,
+    pub tracks: Vec<PathBuf>,
+    pub duration_secs: f64,
+    pub thumbnail: Option<egui::ColorImage>,
+}
+
+/// Groups `files` into albums from their headers alone, the way the conversion will.
+pub fn scan_library(files: &[PathBuf]) -> Vec<LibraryAlbum> {
+    let probed: Vec<_> = files
+        .par_iter()
+        .filter_map(|file| match probe_track(file) {
+            Ok(track) => Some((file, track)),
+            Err(e) => {
+                error!(file:% = file.display(); "Error reading {:?}: {}", file, e);
+                None
+            }
+        })
+        .collect();
+
+    // (folder, album) pairs, like the gapless and album gain jobs
+    let mut albums: BTreeMap<(PathBuf, String), LibraryAlbum> = BTreeMap::new();
+    for (file, track) in probed {
+        let folder = file.parent().map(Path::to_path_buf).unwrap_or_default();
+        let album = albums
+            .entry((folder.clone(), track.album.clone()))
+            .or_insert_with(|| LibraryAlbum {
+                folder,
+                album: track.album,
+                artist: String::new(),
+                tracks: Vec::new(),
+                duration_secs: 0.0,
+                thumbnail: None,
+            });
+        if album.artist.is_empty() {
+            album.artist = track.artist;
+        }
+        album.tracks.push(file.clone());
+        album.duration_secs += track.duration_secs.unwrap_or(0.0);
+    }
+
+    let mut albums: Vec<LibraryAlbum> = albums.into_values().collect();
+    albums.par_iter_mut().for_each(|album| {
+        album.tracks.sort();
+        album.thumbnail = album
+            .tracks
+            .first()
+            .and_then(|track| find_cover(track))
+            .map(thumbnail);
+    });
+    albums
+}
+
+// the embedded picture, else the first image next to the track, like the converter picks
+fn find_cover(track: &Path) -> Option<DynamicImage> {
+    let embedded = embedded_cover(track).and_then(|bytes| {
+        ImageReader::new(Cursor::new(bytes))
+            .with_guessed_format()
+            .ok()?
+            .decode()
+            .ok()
+    });
+    embedded.or_else(|| {
+        let folder = track.parent()?.to_string_lossy().to_string();
+        ["jpg", "jpeg", "png"]
+            .iter()
+            .flat_map(|extension| glob::glob(&format!("{}/*.{}", folder, extension)).ok())
+            .flatten()
+            .flatten()
+            .find_map(|path| image::open(path).ok())
+    })
+}
+
+fn embedded_cover(track: &Path) -> Option<Box<[u8]>> {
+    let mut hint = Hint::new();
+    if let Some(extension) = track.extension().and_then(|ext| ext.to_str()) {
+        hint.with_extension(extension);
+    }
+    let mss = MediaSourceStream::new(Box::new(File::open(track).ok()?), Default::default());
+    let mut probed = symphonia::default::get_probe()
+        .format(
+            &hint,
+            mss,
+            &FormatOptions::default(),
+            &MetadataOptions::default(),
+        )
+        .ok()?;
+
+    let in_container = probed
+        .format
+        .metadata()
+        .current()
+        .and_then(|revision| revision.visuals().first().map(|visual| visual.data.clone()));
+    in_container.or_else(|| {
+        let metadata = probed.metadata.get()?;
+        let revision = metadata.current()?;
+        revision.visuals().first().map(|visual| visual.data.clone())
+    })
+}
+
+fn thumbnail(cover: DynamicImage) -> egui::ColorImage {
+    let rgba = cover.thumbnail(THUMBNAIL_PX, THUMBNAIL_PX).to_rgba8();
+    let size = [rgba.width() as usize, rgba.height() as usize];
+    egui::ColorImage::from_rgba_unmultiplied(size, rgba.as_flat_samples().as_slice())
+}
+
+/// "42:05", or "1:02:03" once it's over an hour.
+pub fn format_duration(secs: f64) -> String {
+    let secs = secs.round() as u64;
+    let (hours, minutes, secs) = (secs / 3600, secs / 60 % 60, secs % 60);
+    if hours > 0 {
+        format!("{}:{:02}:{:02}", hours, minutes, secs)
+    } else {
+        format!("{}:{:02}", minutes, secs)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_format_duration() {
+        assert_eq!(format_duration(0.0), "0:00");
+        assert_eq!(format_duration(2525.4), "42:05");
+        assert_eq!(format_duration(3723.0), "1:02:03");
+
+        let cover = DynamicImage::new_rgb8(500, 250);
+        let thumbnail = thumbnail(cover);
+        assert_eq!(thumbnail.size, [96, 48]);
+    }
+}
@@ -0,0 +1,245 @@
+use std::collections::{BTreeMap, VecDeque};
+use std::sync::Mutex;
+
+use log::kv::Key;
+use log::{Level, LevelFilter, Log, Metadata, Record};
+
+// the oldest lines go once there are more
+const MAX_LINES: usize = 5000;
+
+static LINES: Mutex<VecDeque<LogLine>> = Mutex::new(VecDeque::new());
+
+/// One message, `file` is the source it's about when it was logged with a `file` key.
+#[derive(Clone, Debug, PartialEq)]
+pub struct LogLine {
+    pub level: Level,
+    pub file: Option<String>,
+    pub message: String,
+}
+
+impl LogLine {
+    fn matches(&self, level: LevelFilter, search: &str) -> bool {
+        let search = search.to_lowercase();
+        self.level <= level
+            && (self.message.to_lowercase().contains(&search)
+                || self
+                    .file
+                    .as_ref()
+                    .is_some_and(|file| file.to_lowercase().contains(&search)))
+    }
+}
+
+impl std::fmt::Display for LogLine {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:<5} {}", self.level, self.message)
+    }
+}
+
+// keeps what m2psp logs for the console and hands everything to env_logger for the terminal,
+// which release builds on Windows don't have
+struct ConsoleLogger {
+    stderr: env_logger::Logger,
+}
+
+impl ConsoleLogger {
+    fn keeps(metadata: &Metadata<'_>) -> bool {
+        metadata.level() <= Level::Warn
+            || metadata.level() <= Level::Debug && metadata.target().starts_with("m2psp")
+    }
+}
+
+impl Log for ConsoleLogger {
+    fn enabled(&self, metadata: &Metadata<'_>) -> bool {
+        ConsoleLogger::keeps(metadata) || self.stderr.enabled(metadata)
+    }
+
+    fn log(&self, record: &Record<'_>) {
+        if self.stderr.matches(record) {
+            self.stderr.log(record);
+        }
+        if !ConsoleLogger::keeps(record.metadata()) {
+            return;
+        }
+        let line = LogLine {
+            level: record.level(),
+            file: record
+                .key_values()
+                .get(Key::from("file"))
+                .map(|file| file.to_string()),
+            message: record.args().to_string(),
+        };
+        let mut lines = LINES.lock().unwrap_or_else(|e| e.into_inner());
+        if lines.len() == MAX_LINES {
+            lines.pop_front();
+        }
+        lines.push_back(line);
+    }
+
+    fn flush(&self) {
+        self.stderr.flush();
+    }
+}
+
+/// Logs to the console window and, like before, to stderr with `RUST_LOG` picking what shows.
+pub fn init_logging() {
+    let stderr = env_logger::Builder::from_env(
+        env_logger::Env::default().default_filter_or("warn,m2psp=info"),
+    )
+    .build();
+    let max_level = stderr.filter().max(LevelFilter::Debug);
+    if log::set_boxed_logger(Box::new(ConsoleLogger { stderr })).is_ok() {
+        log::set_max_level(max_level);
+    }
+}
+
+pub fn error_count() -> usize {
+    let lines = LINES.lock().unwrap_or_else(|e| e.into_inner());
+    lines
+        .iter()
+        .filter(|line| line.level == Level::Error)
+        .count()
+}
+
+/// What the log window shows of everything logged so far.
+pub struct LogConsole {
+    pub level: LevelFilter,
+    pub search: String,
+    pub group_by_file: bool,
+}
+
+impl Default for LogConsole {
+    fn default() -> Self {
+        LogConsole {
+            level: LevelFilter::Info,
+            search: String::new(),
+            group_by_file: false,
+        }
+    }
+}
+
+impl LogConsole {
+    fn visible(&self, lines: &VecDeque<LogLine>) -> Vec<LogLine> {
+        lines
+            .iter()
+            .filter(|line| line.matches(self.level, &self.search))
+            .cloned()
+            .collect()
+    }
+
+    pub fn ui(&mut self, ui: &mut egui::Ui) {
+        // copied out so that nothing logging while we draw has to wait
+        let lines = self.visible(&LINES.lock().unwrap_or_else(|e| e.into_inner()));
+
+        ui.horizontal(|ui| {
+            egui::ComboBox::from_id_salt("log level")
+                .selected_text(self.level.as_str().to_lowercase())
+                .show_ui(ui, |ui| {
+                    for level in [
+                        LevelFilter::Error,
+                        LevelFilter::Warn,
+                        LevelFilter::Info,
+                        LevelFilter::Debug,
+                    ] {
+                        ui.selectable_value(&mut self.level, level, level.as_str().to_lowercase());
+                    }
+                });
+            ui.add(
+                egui::TextEdit::singleline(&mut self.search)
+                    .hint_text("search")
+                    .desired_width(160.0),
+            );
+            ui.checkbox(&mut self.group_by_file, "group by file");
+            if ui.button("copy to clipboard").clicked() {
+                let text: Vec<String> = lines.iter().map(LogLine::to_string).collect();
+                ui.ctx().copy_text(text.join("\n"));
+            }
+            if ui.button("clear").clicked() {
+                LINES.lock().unwrap_or_else(|e| e.into_inner()).clear();
+            }
+        });
+        ui.separator();
+
+        egui::ScrollArea::vertical()
+            .auto_shrink(false)
+            .stick_to_bottom(true)
+            .show(ui, |ui| {
+                if !self.group_by_file {
+                    for line in &lines {
+                        line_ui(ui, line);
+                    }
+                    return;
+                }
+                let mut files: BTreeMap<&str, Vec<&LogLine>> = BTreeMap::new();
+                for line in &lines {
+                    files
+                        .entry(line.file.as_deref().unwrap_or(""))
+                        .or_default()
+                        .push(line);
+                }
+                for (file, lines) in files {
+                    let name = if file.is_empty() { "(no file)" } else { file };
+                    egui::CollapsingHeader::new(format!("{} ({})", name, lines.len()))
+                        .id_salt(file)
+                        .show(ui, |ui| {
+                            for line in lines {
+                                line_ui(ui, line);
+                            }
+                        });
+                }
+            });
+    }
+}
+
+fn line_ui(ui: &mut egui::Ui, line: &LogLine) {
+    let text = egui::RichText::new(line.to_string()).monospace();
+    match line.level {
+        Level::Error => ui.colored_label(egui::Color32::RED, text),
+        Level::Warn => ui.colored_label(egui::Color32::YELLOW, text),
+        Level::Info => ui.label(text),
+        Level::Debug | Level::Trace => ui.weak(text),
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_visible_lines() {
+        let line = |level, file: Option<&str>, message: &str| LogLine {
+            level,
+            file: file.map(str::to_string),
+            message: message.to_string(),
+        };
+        let lines: VecDeque<LogLine> = [
+            line(
+                Level::Info,
+                Some("/music/01.flac"),
+                "Converted! : \"01.flac\"",
+            ),
+            line(
+                Level::Error,
+                Some("/music/02.flac"),
+                "Error for file/s : no metadata found",
+            ),
+            line(
+                Level::Debug,
+                None,
+                "Directory created: /media/PSP/MUSIC/Album",
+            ),
+        ]
+        .into();
+
+        let mut console = LogConsole::default();
+        assert_eq!(console.visible(&lines).len(), 2);
+        console.search = "02.FLAC".to_string();
+        assert_eq!(console.visible(&lines), [lines[1].clone()]);
+        console.level = LevelFilter::Warn;
+        console.search.clear();
+        assert_eq!(console.visible(&lines), [lines[1].clone()]);
+        assert_eq!(
+            lines[1].to_string(),
+            "ERROR Error for file/s : no metadata found"
+        );
+    }
+}
@@ -0,0 +1,472 @@
+use std::collections::VecDeque;
+use std::f64::consts::PI;
+
+/// Loudness everything gets brought to, the one ReplayGain 2.0 uses.
+pub const REFERENCE_LUFS: f64 = -18.0;
+
+/// How high the true peak may go once gain is applied, leaves room for the MP3 encoder.
+pub const TRUE_PEAK_CEILING_DBTP: f64 = -1.0;
+
+// 400 ms gating blocks that overlap by 75%, from ITU-R BS.1770
+const BLOCK_SECS: f64 = 0.4;
+const BLOCK_STEP_SECS: f64 = 0.1;
+const ABSOLUTE_GATE_LUFS: f64 = -70.0;
+const RELATIVE_GATE_LU: f64 = -10.0;
+
+// True peaks are measured on a 4x oversampled signal, 12 taps per phase
+const OVERSAMPLING: usize = 4;
+const TAPS_PER_PHASE: usize = 12;
+
+// How far ahead the limiter looks, and how quickly it lets go afterwards
+const LIMITER_LOOKAHEAD_SECS: f64 = 0.0015;
+const LIMITER_RELEASE_SECS: f64 = 0.05;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, serde::Deserialize, serde::Serialize)]
+pub enum GainMode {
+    #[default]
+    Off,
+    // every track on its own, evens out shuffled playlists
+    Track,
+    // one gain per album, keeps quiet tracks quieter than the loud ones around them
+    Album,
+}
+
+impl GainMode {
+    /// The gain in dB this mode gives a track measured as `track` on an album measured as `album`.
+    pub fn gain_db(self, track: &Loudness, album: &Loudness) -> f64 {
+        match self {
+            GainMode::Off => 0.0,
+            GainMode::Track => track.gain_db(),
+            GainMode::Album => album.gain_db(),
+        }
+    }
+}
+
+/// The EBU R128 loudness and the true peak of a track, or of a whole album.
+#[derive(Clone, Debug, Default)]
+pub struct Loudness {
+    // mean square of every gating block, kept so tracks can be merged into an album
+    block_energies: Vec<f64>,
+    pub true_peak: f32,
+}
+
+impl Loudness {
+    pub fn analyze(pcm_data: &[Vec<f32>], sample_rate: u32) -> Self {
+        let block_len = (BLOCK_SECS * sample_rate as f64) as usize;
+        let step = (BLOCK_STEP_SECS * sample_rate as f64) as usize;
+        let frame_count = pcm_data.first().map_or(0, Vec::len);
+
+        // per frame energy of the K-weighted signal, summed over the channels
+        let mut energies = vec![0.0f64; frame_count];
+        for channel in pcm_data {
+            let mut filter = KWeighting::new(sample_rate);
+            for (energy, &sample) in energies.iter_mut().zip(channel) {
+                let weighted = filter.process(sample as f64);
+                *energy += weighted * weighted;
+            }
+        }
+
+        let mut block_energies = Vec::new();
+        if block_len > 0 && step > 0 && frame_count >= block_len {
+            let mut sum: f64 = energies[..block_len].iter().sum();
+            let mut start = 0;
+            loop {
+                block_energies.push(sum / block_len as f64);
+                if start + step + block_len > frame_count {
+                    break;
+                }
+                sum -= energies[start..start + step].iter().sum::<f64>();
+                sum += energies[start + block_len..start + block_len + step]
+                    .iter()
+                    .sum::<f64>();
+                start += step;
+            }
+        }
+
+        Self {
+            block_energies,
+            true_peak: true_peak(pcm_data),
+        }
+    }
+
+    /// Gating runs over the blocks of every track together, as if they were one long track.
+    pub fn album(tracks: &[Loudness]) -> Self {
+        Self {
+            block_energies: tracks
+                .iter()
+                .flat_map(|track| track.block_energies.iter().copied())
+                .collect(),
+            true_peak: tracks
+                .iter()
+                .map(|track| track.true_peak)
+                .fold(0.0, f32::max),
+        }
+    }
+
+    /// Integrated loudness in LUFS, `None` for silence and anything shorter than a block.
+    pub fn integrated_lufs(&self) -> Option<f64> {
+        let absolute_gated: Vec<f64> = self
+            .block_energies
+            .iter()
+            .copied()
+            .filter(|&energy| to_lufs(energy) > ABSOLUTE_GATE_LUFS)
+            .collect();
+        if absolute_gated.is_empty() {
+            return None;
+        }
+
+        let relative_gate = to_lufs(mean(&absolute_gated)) + RELATIVE_GATE_LU;
+        let relative_gated: Vec<f64> = absolute_gated
+            .into_iter()
+            .filter(|&energy| to_lufs(energy) > relative_gate)
+            .collect();
+        Some(to_lufs(mean(&relative_gated)))
+    }
+
+    /// Gain in dB that brings this to `REFERENCE_LUFS`.
+    pub fn gain_db(&self) -> f64 {
+        self.integrated_lufs()
+            .map_or(0.0, |lufs| REFERENCE_LUFS - lufs)
+    }
+}
+
+fn to_lufs(energy: f64) -> f64 {
+    -0.691 + 10.0 * energy.max(f64::MIN_POSITIVE).log10()
+}
+
+fn mean(values: &[f64]) -> f64 {
+    values.iter().sum::<f64>() / values.len() as f64
+}
+
+pub fn db_to_linear(db: f64) -> f64 {
+    10f64.powf(db / 20.0)
+}
+
+pub fn linear_to_db(linear: f64) -> f64 {
+    20.0 * linear.max(f64::MIN_POSITIVE).log10()
+}
+
+/// The peaks of what is about to be encoded.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct PeakLevels {
+    pub sample_peak: f32,
+    pub true_peak: f32,
+    // samples past full scale, LAME clips every one of them
+    pub clipped_samples: u64,
+}
+
+impl PeakLevels {
+    pub fn measure(pcm_data: &[Vec<f32>]) -> Self {
+        let samples = pcm_data.iter().flatten();
+        Self {
+            sample_peak: samples
+                .clone()
+                .fold(0.0, |peak, sample| peak.max(sample.abs())),
+            true_peak: true_peak(pcm_data),
+            clipped_samples: samples.filter(|sample| sample.abs() > 1.0).count() as u64,
+        }
+    }
+
+    /// Over full scale, either on a sample or in between two of them.
+    pub fn is_clipping(&self) -> bool {
+        self.clipped_samples > 0 || self.true_peak > 1.0
+    }
+
+    /// The gain in dB, 0 or less, that just gets the true peak under the ceiling.
+    pub fn safe_gain_db(&self) -> f64 {
+        let true_peak_dbtp = linear_to_db(self.true_peak as f64);
+        (TRUE_PEAK_CEILING_DBTP - true_peak_dbtp).min(0.0)
+    }
+}
+
+pub fn scale(pcm_data: &mut [Vec<f32>], gain: f32) {
+    for channel in pcm_data.iter_mut() {
+        channel.iter_mut().for_each(|sample| *sample *= gain);
+    }
+}
+
+/// The true peak `apply_gain` leaves, worked out without touching the samples.
+pub fn peak_after_gain(peak_before: f32, gain_db: f64) -> f32 {
+    let ceiling = db_to_linear(TRUE_PEAK_CEILING_DBTP) as f32;
+    (peak_before * db_to_linear(gain_db) as f32).min(ceiling)
+}
+
+/// Applies `gain_db` to samples whose true peak is `peak_before`, pulling down whatever would
+/// end up above the true peak ceiling instead of letting it clip.
+///
+/// Returns the true peak after processing.
+pub fn apply_gain(
+    pcm_data: &mut [Vec<f32>],
+    gain_db: f64,
+    peak_before: f32,
+    sample_rate: u32,
+) -> f32 {
+    let gain = db_to_linear(gain_db) as f32;
+    let ceiling = db_to_linear(TRUE_PEAK_CEILING_DBTP) as f32;
+
+    if peak_before * gain <= ceiling {
+        scale(pcm_data, gain);
+        return peak_before * gain;
+    }
+
+    let frame_count = pcm_data.first().map_or(0, Vec::len);
+    let lookahead = ((LIMITER_LOOKAHEAD_SECS * sample_rate as f64) as usize).max(1);
+    let release = (1.0 / (LIMITER_RELEASE_SECS * sample_rate as f64)) as f32;
+
+    // the gain every frame can take at most, given the peaks right around it
+    let mut allowed = vec![gain; frame_count];
+    for channel in pcm_data.iter() {
+        for (allowed, peak) in allowed.iter_mut().zip(true_peak_envelope(channel)) {
+            if peak * gain > ceiling {
+                *allowed = allowed.min(ceiling / peak);
+            }
+        }
+    }
+
+    // Holding the lowest gain of the next `lookahead` frames and averaging over as many
+    // frames before turns every drop into a ramp that is already down once the peak arrives
+    let held = sliding_min(&allowed, lookahead);
+    let mut gains = Vec::with_capacity(frame_count);
+    let mut sum = 0.0f64;
+    let mut current = gain;
+    for i in 0..frame_count {
+        sum += held[i] as f64;
+        if i > lookahead {
+            sum -= held[i - lookahead - 1] as f64;
+        }
+        let smoothed = (sum / (i.min(lookahead) + 1) as f64) as f32;
+        // and coming back up happens slowly, so the gain doesn't pump along with the music
+        current = smoothed.min(current + (gain - current) * release);
+        gains.push(current);
+    }
+
+    for channel in pcm_data.iter_mut() {
+        for (sample, gain) in channel.iter_mut().zip(&gains) {
+            *sample *= gain;
+        }
+    }
+    true_peak(pcm_data)
+}
+
+// min of values[i..=i + window], with a deque of candidates so it stays linear
+fn sliding_min(values: &[f32], window: usize) -> Vec<f32> {
+    let mut minimums = vec![0.0; values.len()];
+    let mut candidates: VecDeque<usize> = VecDeque::new();
+    for i in (0..values.len()).rev() {
+        while candidates.back().is_some_and(|&j| values[j] >= values[i]) {
+            candidates.pop_back();
+        }
+        candidates.push_back(i);
+        while candidates.front().is_some_and(|&j| j > i + window) {
+            candidates.pop_front();
+        }
+        minimums[i] = values[candidates[0]];
+    }
+    minimums
+}
+
+/// The highest peak of the signal once it's turned back into an analog one, which can be
+/// higher than the highest sample.
+pub fn true_peak(pcm_data: &[Vec<f32>]) -> f32 {
+    pcm_data
+        .iter()
+        .flat_map(|channel| true_peak_envelope(channel))
+        .fold(0.0, f32::max)
+}
+
+// per sample, the highest of the sample itself and the oversampled values following it
+fn true_peak_envelope(channel: &[f32]) -> impl Iterator<Item = f32> + '_ {
+    let taps = interpolation_taps();
+    (0..channel.len()).map(move |i| {
+        let mut peak = channel[i].abs();
+        for phase in &taps {
+            let mut value = 0.0;
+            for (j, tap) in phase.iter().enumerate() {
+                // centered on the gap between sample i and i + 1
+                let index = (i + TAPS_PER_PHASE / 2).checked_sub(j);
+                if let Some(&sample) = index.and_then(|index| channel.get(index)) {
+                    value += sample * tap;
+                }
+            }
+            peak = peak.max(value.abs());
+        }
+        peak
+    })
+}
+
+// Windowed sinc lowpass at the original Nyquist frequency, split into its polyphase
+// components, one for every position between two samples
+fn interpolation_taps() -> Vec<[f32; TAPS_PER_PHASE]> {
+    let len = OVERSAMPLING * TAPS_PER_PHASE;
+    let center = (len / 2) as f64;
+    (1..OVERSAMPLING)
+        .map(|phase| {
+            let mut taps = [0.0; TAPS_PER_PHASE];
+            for (j, tap) in taps.iter_mut().enumerate() {
+                let n = (j * OVERSAMPLING + OVERSAMPLING - phase) as f64;
+                let x = (n - center) / OVERSAMPLING as f64;
+                let sinc = if x == 0.0 {
+                    1.0
+                } else {
+                    (PI * x).sin() / (PI * x)
+                };
+                // Hann window
+                let window = 0.5 - 0.5 * (2.0 * PI * n / len as f64).cos();
+                *tap = (sinc * window) as f32;
+            }
+            taps
+        })
+        .collect()
+}
+
+// K-weighting from BS.1770: a high shelf for the head, then a highpass, as two biquads
+struct KWeighting {
+    stages: [Biquad; 2],
+}
+
+impl KWeighting {
+    // coefficients worked out for any sample rate, the way libebur128 does it
+    fn new(sample_rate: u32) -> Self {
+        let rate = sample_rate as f64;
+
+        let f0 = 1681.974450955533;
+        let gain_db = 3.999843853973347;
+        let q = 0.7071752369554196;
+        let k = (PI * f0 / rate).tan();
+        let vh = 10f64.powf(gain_db / 20.0);
+        let vb = vh.powf(0.4996667741545416);
+        let a0 = 1.0 + k / q + k * k;
+        let shelf = Biquad::new(
+            [
+                (vh + vb * k / q + k * k) / a0,
+                2.0 * (k * k - vh) / a0,
+                (vh - vb * k / q + k * k) / a0,
+            ],
+            [2.0 * (k * k - 1.0) / a0, (1.0 - k / q + k * k) / a0],
+        );
+
+        let f0 = 38.13547087602444;
+        let q = 0.5003270373238773;
+        let k = (PI * f0 / rate).tan();
+        let a0 = 1.0 + k / q + k * k;
+        let highpass = Biquad::new(
+            [1.0, -2.0, 1.0],
+            [2.0 * (k * k - 1.0) / a0, (1.0 - k / q + k * k) / a0],
+        );
+
+        Self {
+            stages: [shelf, highpass],
+        }
+    }
+
+    fn process(&mut self, sample: f64) -> f64 {
+        self.stages
+            .iter_mut()
+            .fold(sample, |sample, stage| stage.process(sample))
+    }
+}
+
+struct Biquad {
+    b: [f64; 3],
+    a: [f64; 2],
+    state: [f64; 2],
+}
+
+impl Biquad {
+    fn new(b: [f64; 3], a: [f64; 2]) -> Self {
+        Self {
+            b,
+            a,
+            state: [0.0; 2],
+        }
+    }
+
+    // transposed direct form II
+    fn process(&mut self, input: f64) -> f64 {
+        let output = self.b[0] * input + self.state[0];
+        self.state[0] = self.b[1] * input - self.a[0] * output + self.state[1];
+        self.state[1] = self.b[2] * input - self.a[1] * output;
+        output
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SAMPLE_RATE: u32 = 48_000;
+
+    fn sine(frequency: f64, amplitude_dbfs: f64, phase: f64, secs: f64) -> Vec<Vec<f32>> {
+        let amplitude = db_to_linear(amplitude_dbfs);
+        let channel: Vec<f32> = (0..(secs * SAMPLE_RATE as f64) as usize)
+            .map(|i| {
+                let t = i as f64 / SAMPLE_RATE as f64;
+                (amplitude * (2.0 * PI * frequency * t + phase).sin()) as f32
+            })
+            .collect();
+        vec![channel.clone(), channel]
+    }
+
+    #[test]
+    fn test_integrated_loudness() {
+        // EBU Tech 3341, case 1: a -23 dBFS 1 kHz stereo sine reads -23 LUFS
+        let loudness = Loudness::analyze(&sine(1000.0, -23.0, 0.0, 20.0), SAMPLE_RATE);
+        let lufs = loudness.integrated_lufs().unwrap();
+        assert!((lufs - -23.0).abs() < 0.1, "{}", lufs);
+        assert!((loudness.gain_db() - 5.0).abs() < 0.1);
+
+        let silence = vec![vec![0.0; SAMPLE_RATE as usize]; 2];
+        assert_eq!(
+            Loudness::analyze(&silence, SAMPLE_RATE).integrated_lufs(),
+            None
+        );
+
+        // a quiet track and a loud one make an album somewhere in between
+        let quiet = Loudness::analyze(&sine(1000.0, -30.0, 0.0, 10.0), SAMPLE_RATE);
+        let album = Loudness::album(&[quiet, loudness])
+            .integrated_lufs()
+            .unwrap();
+        assert!(album > -30.0 && album < -23.0, "{}", album);
+    }
+
+    #[test]
+    fn test_true_peak_between_samples() {
+        // at a quarter of the sample rate and 45° off, every sample misses the crest by 3 dB
+        let pcm_data = sine(SAMPLE_RATE as f64 / 4.0, -6.0, PI / 4.0, 1.0);
+        let sample_peak = pcm_data[0].iter().fold(0.0f32, |peak, s| peak.max(s.abs()));
+        let peak = true_peak(&pcm_data);
+
+        assert!((sample_peak as f64 - db_to_linear(-9.01)).abs() < 0.01);
+        assert!((peak as f64 - db_to_linear(-6.0)).abs() < 0.02, "{}", peak);
+    }
+
+    #[test]
+    fn test_peak_levels() {
+        let mut pcm_data = sine(1000.0, -6.0, 0.0, 1.0);
+        pcm_data[0][100] = 1.25;
+        pcm_data[1][200] = -1.5;
+
+        let levels = PeakLevels::measure(&pcm_data);
+        assert_eq!(levels.sample_peak, 1.5);
+        assert!(levels.true_peak >= 1.5);
+        assert_eq!(levels.clipped_samples, 2);
+        assert!(levels.is_clipping());
+
+        scale(&mut pcm_data, db_to_linear(levels.safe_gain_db()) as f32);
+        let levels = PeakLevels::measure(&pcm_data);
+        assert!(!levels.is_clipping());
+        assert!((linear_to_db(levels.true_peak as f64) - TRUE_PEAK_CEILING_DBTP).abs() < 0.01);
+        assert!(levels.safe_gain_db() > -0.01);
+    }
+
+    #[test]
+    fn test_limiter_keeps_true_peak_under_ceiling() {
+        let mut pcm_data = sine(440.0, -3.0, 0.0, 2.0);
+        let peak = true_peak(&pcm_data);
+
+        let limited_peak = apply_gain(&mut pcm_data, 12.0, peak, SAMPLE_RATE);
+        assert!(limited_peak as f64 <= db_to_linear(TRUE_PEAK_CEILING_DBTP) * 1.01);
+        // the gain was still applied where it could be
+        assert!(limited_peak as f64 > db_to_linear(TRUE_PEAK_CEILING_DBTP) * 0.9);
+    }
+}
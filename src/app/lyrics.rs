@@ -0,0 +1,146 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::app::playlist::decode_text;
+
+/// Lyrics of a track, from its tags or from a .lrc file next to it.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Lyrics {
+    // the words alone, what USLT holds
+    pub text: String,
+    // (milliseconds, line) for SYLT, empty unless the source had timestamps
+    pub synced: Vec<(u32, String)>,
+    // the .lrc the lyrics came from
+    pub sidecar: Option<PathBuf>,
+}
+
+impl Lyrics {
+    /// Takes plain text as well as the LRC format, which some taggers also put in LYRICS tags.
+    pub fn parse(text: &str) -> Self {
+        let mut offset_ms = 0i64;
+        let mut synced = Vec::new();
+        let mut plain = Vec::new();
+
+        for line in text.lines() {
+            let mut rest = line.trim();
+            let mut times = Vec::new();
+            while let Some((tag, after)) = rest.strip_prefix('[').and_then(|r| r.split_once(']')) {
+                match parse_time(tag) {
+                    Some(ms) => times.push(ms),
+                    None => {
+                        // [ar:...], [ti:...] and friends, only the offset matters
+                        if let Some(offset) = tag.strip_prefix("offset:") {
+                            offset_ms = offset.trim().parse().unwrap_or(0);
+                        }
+                    }
+                }
+                rest = after;
+            }
+            // a line that was only ID tags is no lyric
+            if rest.is_empty() && times.is_empty() && line.trim().starts_with('[') {
+                continue;
+            }
+
+            let words = strip_word_times(rest);
+            if times.is_empty() {
+                plain.push(words);
+            } else {
+                synced.extend(times.into_iter().map(|ms| (ms, words.clone())));
+            }
+        }
+
+        // a positive offset makes the lyrics show up sooner
+        for (ms, _) in synced.iter_mut() {
+            *ms = (*ms as i64 - offset_ms).max(0) as u32;
+        }
+        synced.sort_by_key(|(ms, _)| *ms);
+
+        let text = if synced.is_empty() {
+            plain.join("\n").trim().to_string()
+        } else {
+            let lines: Vec<&str> = synced.iter().map(|(_, line)| line.as_str()).collect();
+            lines.join("\n").trim().to_string()
+        };
+        Lyrics {
+            text,
+            synced,
+            sidecar: None,
+        }
+    }
+
+    /// Reads the .lrc file that has the same name as `audio_path`.
+    pub fn find_for(audio_path: &Path) -> Option<Self> {
+        let sidecar = ["lrc", "LRC"]
+            .iter()
+            .map(|extension| audio_path.with_extension(extension))
+            .find(|path| path.is_file())?;
+        let lyrics = Lyrics::parse(&decode_text(fs::read(&sidecar).ok()?));
+        (!lyrics.text.is_empty()).then_some(Lyrics {
+            sidecar: Some(sidecar),
+            ..lyrics
+        })
+    }
+}
+
+// "mm:ss", "mm:ss.xx" or "mm:ss.xxx" -> milliseconds
+fn parse_time(tag: &str) -> Option<u32> {
+    let (minutes, seconds) = tag.split_once(':')?;
+    let minutes: u32 = minutes.trim().parse().ok()?;
+    let (seconds, fraction) = seconds.split_once('.').unwrap_or((seconds, ""));
+    let seconds: u32 = seconds.trim().parse().ok()?;
+    let fraction_ms = match fraction.len() {
+        0 => 0,
+        1 => fraction.parse::<u32>().ok()? * 100,
+        2 => fraction.parse::<u32>().ok()? * 10,
+        _ => fraction.get(..3)?.parse().ok()?,
+    };
+    Some((minutes * 60 + seconds) * 1000 + fraction_ms)
+}
+
+// enhanced LRC times single words with <mm:ss.xx>, SYLT only goes by line here
+fn strip_word_times(line: &str) -> String {
+    let mut words = String::with_capacity(line.len());
+    let mut rest = line;
+    while let Some(open) = rest.find('<') {
+        match rest[open..].find('>') {
+            Some(close) if parse_time(&rest[open + 1..open + close]).is_some() => {
+                words.push_str(&rest[..open]);
+                rest = &rest[open + close + 1..];
+            }
+            _ => {
+                words.push_str(&rest[..=open]);
+                rest = &rest[open + 1..];
+            }
+        }
+    }
+    words.push_str(rest);
+    words.trim().to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_lyrics() {
+        let lrc = "[ar:The Band]\n[ti:Song]\n[offset:+500]\n\
+                   [00:12.00]First <00:12.50>line\n\
+                   [00:20.5][01:02.250]Chorus\n\
+                   [00:30.00]Second line\n";
+        let lyrics = Lyrics::parse(lrc);
+        assert_eq!(
+            lyrics.synced,
+            vec![
+                (11_500, "First line".to_string()),
+                (20_000, "Chorus".to_string()),
+                (29_500, "Second line".to_string()),
+                (61_750, "Chorus".to_string()),
+            ]
+        );
+        assert_eq!(lyrics.text, "First line\nChorus\nSecond line\nChorus");
+
+        let plain = Lyrics::parse("Just words\n<not a time>\n");
+        assert!(plain.synced.is_empty());
+        assert_eq!(plain.text, "Just words\n<not a time>");
+    }
+}
@@ -0,0 +1,204 @@
+use std::ops::Range;
+
+/// Sample rates the PSP plays MP3s at.
+pub const PSP_SAMPLE_RATES: [u32; 2] = [44_100, 48_000];
+
+// MPEG-1 Layer III tables, indexed straight with the bits of the frame header
+const BITRATES_KBPS: [u32; 16] = [
+    0, 32, 40, 48, 56, 64, 80, 96, 112, 128, 160, 192, 224, 256, 320, 0,
+];
+const SAMPLE_RATES: [u32; 4] = [44_100, 48_000, 32_000, 0];
+const SAMPLES_PER_FRAME: u32 = 1152;
+
+// how far into the file the first frame may be hiding behind junk
+const MAX_SYNC_SEARCH: usize = 64 * 1024;
+
+/// What the frames of an MPEG-1 Layer III file say about it.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Mp3Stream {
+    pub sample_rate: u32,
+    pub average_bitrate_kbps: u16,
+    pub duration_secs: f64,
+    // the MPEG frames alone, without the ID3/APE tags around them
+    pub frames: Range<usize>,
+}
+
+impl Mp3Stream {
+    /// Walks every frame of the file. Anything that isn't MPEG-1 Layer III all the way
+    /// through to the tags at the end, at a single sample rate, gives `None`.
+    pub fn parse(bytes: &[u8]) -> Option<Self> {
+        let tag_end = id3v2_len(bytes);
+        let search_end = bytes.len().min(tag_end + MAX_SYNC_SEARCH);
+        // a real frame is followed by another one, a stray 0xFF in the padding isn't
+        let start = (tag_end..search_end).find(|&pos| {
+            frame_header(&bytes[pos..]).is_some_and(|(len, _)| {
+                frame_header(&bytes[(pos + len).min(bytes.len())..]).is_some()
+            })
+        })?;
+
+        let (first_len, sample_rate) = frame_header(&bytes[start..])?;
+        let mut pos = start;
+        let mut frame_count = 0u32;
+        while let Some((len, frame_sample_rate)) = frame_header(&bytes[pos..]) {
+            if frame_sample_rate != sample_rate {
+                return None;
+            }
+            if pos + len > bytes.len() {
+                break;
+            }
+            pos += len;
+            frame_count += 1;
+        }
+        // junk or a tag halfway through, copying only the frames in front would cut the track
+        if !is_trailer(&bytes[pos..]) {
+            return None;
+        }
+
+        // the LAME/Xing info frame in front holds no audio
+        let mut audio_bytes = pos - start;
+        if is_info_frame(&bytes[start..]) {
+            frame_count = frame_count.saturating_sub(1);
+            audio_bytes -= first_len;
+        }
+        if frame_count == 0 {
+            return None;
+        }
+
+        let duration_secs = (frame_count * SAMPLES_PER_FRAME) as f64 / sample_rate as f64;
+        let average_bitrate_kbps =
+            (audio_bytes as f64 * 8.0 / duration_secs / 1000.0).round() as u16;
+
+        Some(Self {
+            sample_rate,
+            average_bitrate_kbps,
+            duration_secs,
+            frames: start..pos,
+        })
+    }
+
+    pub fn playable_on_psp(&self) -> bool {
+        PSP_SAMPLE_RATES.contains(&self.sample_rate)
+    }
+}
+
+// size of the ID3v2 tag in front of the frames, 0 if there is none
+fn id3v2_len(bytes: &[u8]) -> usize {
+    if bytes.len() < 10 || &bytes[..3] != b"ID3" {
+        return 0;
+    }
+    let size = bytes[6..10]
+        .iter()
+        .fold(0usize, |size, &byte| (size << 7) | (byte & 0x7F) as usize);
+    // the footer flag adds another 10 bytes at the end
+    let footer = if bytes[5] & 0x10 != 0 { 10 } else { 0 };
+    10 + size + footer
+}
+
+// (frame length, sample rate) of the MPEG-1 Layer III frame starting at `bytes`
+fn frame_header(bytes: &[u8]) -> Option<(usize, u32)> {
+    let header = bytes.get(..4)?;
+    let is_sync = header[0] == 0xFF && header[1] & 0xE0 == 0xE0;
+    let is_mpeg1 = (header[1] >> 3) & 0b11 == 0b11;
+    let is_layer3 = (header[1] >> 1) & 0b11 == 0b01;
+    if !(is_sync && is_mpeg1 && is_layer3) {
+        return None;
+    }
+
+    let bitrate_kbps = BITRATES_KBPS[(header[2] >> 4) as usize];
+    let sample_rate = SAMPLE_RATES[((header[2] >> 2) & 0b11) as usize];
+    // bitrate 0 is free format, the PSP doesn't do those either
+    if bitrate_kbps == 0 || sample_rate == 0 {
+        return None;
+    }
+    let padding = ((header[2] >> 1) & 1) as u32;
+    Some((
+        (144_000 * bitrate_kbps / sample_rate + padding) as usize,
+        sample_rate,
+    ))
+}
+
+// what may follow the last frame: nothing, a frame cut short, an APE tag and an ID3v1 tag
+fn is_trailer(bytes: &[u8]) -> bool {
+    let mut rest = bytes;
+    if rest.len() >= 128 && rest[rest.len() - 128..].starts_with(b"TAG") {
+        rest = &rest[..rest.len() - 128];
+    }
+    if let Some(ape_len) = ape_tag_len(rest) {
+        rest = &rest[..rest.len() - ape_len];
+    }
+    rest.is_empty() || frame_header(rest).is_some_and(|(len, _)| len > rest.len())
+}
+
+// size of the APEv2 tag `bytes` end with, header included
+fn ape_tag_len(bytes: &[u8]) -> Option<usize> {
+    let footer = &bytes[bytes.len().checked_sub(32)?..];
+    if !footer.starts_with(b"APETAGEX") {
+        return None;
+    }
+    let field = |at: usize| {
+        u32::from_le_bytes([footer[at], footer[at + 1], footer[at + 2], footer[at + 3]])
+    };
+    // the size counts the items and the footer, the header comes on top when there is one
+    let header = if field(20) & (1 << 31) != 0 { 32 } else { 0 };
+    Some(field(12) as usize + header).filter(|&len| len <= bytes.len())
+}
+
+fn is_info_frame(frame: &[u8]) -> bool {
+    // the tag sits right after the side info, which is shorter for mono
+    let is_mono = frame.get(3).is_some_and(|byte| byte >> 6 == 0b11);
+    let offset = if is_mono { 4 + 17 } else { 4 + 32 };
+    matches!(frame.get(offset..offset + 4), Some(b"Xing") | Some(b"Info"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // a silent 128 kbps 44.1 kHz stereo frame, no padding
+    fn frame() -> Vec<u8> {
+        let mut frame = vec![0xFF, 0xFB, 0x90, 0x00];
+        frame.resize(417, 0);
+        frame
+    }
+
+    #[test]
+    fn test_parse_stream() {
+        let mut bytes = b"ID3\x03\x00\x00\x00\x00\x00\x05hello".to_vec();
+        let frames_start = bytes.len();
+        for _ in 0..100 {
+            bytes.extend(frame());
+        }
+        let frames_end = bytes.len();
+        bytes.extend(b"TAG");
+        bytes.resize(bytes.len() + 125, 0);
+
+        let stream = Mp3Stream::parse(&bytes).unwrap();
+        assert_eq!(stream.sample_rate, 44_100);
+        assert_eq!(stream.average_bitrate_kbps, 128);
+        assert_eq!(stream.frames, frames_start..frames_end);
+        assert!((stream.duration_secs - 100.0 * 1152.0 / 44_100.0).abs() < 1e-9);
+        assert!(stream.playable_on_psp());
+
+        // 32 kHz is MPEG-1, but not something the PSP plays
+        let mut low_rate = frame();
+        low_rate[2] = 0x98;
+        low_rate.resize(144 * 128 / 32, 0);
+        let low_rate = low_rate.repeat(10);
+        assert!(!Mp3Stream::parse(&low_rate).unwrap().playable_on_psp());
+
+        // an APE tag after the frames is fine, junk halfway through isn't
+        let mut ape_tagged = frame().repeat(10);
+        ape_tagged.extend(b"APETAGEX\xD0\x07\x00\x00\x20\x00\x00\x00");
+        ape_tagged.resize(ape_tagged.len() + 16, 0);
+        assert_eq!(Mp3Stream::parse(&ape_tagged).unwrap().frames, 0..4170);
+        let mut glitched = frame().repeat(10);
+        glitched.extend(b"garbage");
+        glitched.extend(frame().repeat(10));
+        assert_eq!(Mp3Stream::parse(&glitched), None);
+
+        // MPEG-2 Layer III isn't MPEG-1 Layer III
+        let mut mpeg2 = frame();
+        mpeg2[1] = 0xF3;
+        assert_eq!(Mp3Stream::parse(&mpeg2.repeat(10)), None);
+    }
+}
@@ -0,0 +1,98 @@
+use std::path::PathBuf;
+
+use crate::app::converter::sanitize_file_name;
+
+/// "Album/01 - Title.mp3", what the PSP's music browser lists best.
+pub const DEFAULT_PATH_TEMPLATE: &str = "{album}/{track} - {title}";
+
+/// The placeholders a path template can use.
+pub const PLACEHOLDERS: [&str; 8] = [
+    "album",
+    "album_artist",
+    "artist",
+    "disc",
+    "track",
+    "title",
+    "year",
+    "genre",
+];
+
+/// Where a track goes under the destination, with the placeholders of `template` filled in by
+/// `value_of`, which gets their names without the braces.
+///
+/// Every `/` of the template starts a folder, a `/` inside a value never does. Folders that come
+/// out empty are left out, and the file always gets the `.mp3` extension.
+pub fn render_path(
+    template: &str,
+    value_of: impl Fn(&str) -> Option<String>,
+    fat_safe: bool,
+) -> PathBuf {
+    let mut components: Vec<String> = template
+        .split('/')
+        .map(|component| sanitize_file_name(&fill_in(component, &value_of), fat_safe))
+        .filter(|component| !component.trim().is_empty())
+        .collect();
+    let Some(file_name) = components.pop() else {
+        return render_path(DEFAULT_PATH_TEMPLATE, value_of, fat_safe);
+    };
+    components.push(file_name + ".mp3");
+    components.iter().collect()
+}
+
+// unknown placeholders are left as they are, a typo then shows up in the file names
+fn fill_in(component: &str, value_of: impl Fn(&str) -> Option<String>) -> String {
+    let mut filled = String::new();
+    let mut rest = component;
+    while let Some(open) = rest.find('{') {
+        let Some(close) = rest[open..].find('}').map(|close| open + close) else {
+            break;
+        };
+        filled.push_str(&rest[..open]);
+        match value_of(&rest[open + 1..close]) {
+            // a value can't sneak in folders of its own
+            Some(value) => filled.push_str(&value.replace('/', "_")),
+            None => filled.push_str(&rest[open..=close]),
+        }
+        rest = &rest[close + 1..];
+    }
+    filled.push_str(rest);
+    filled
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render_path() {
+        let render = |template| {
+            let value_of = |placeholder: &str| {
+                let value = match placeholder {
+                    "album" => "AC/DC: Live",
+                    "album_artist" => "AC/DC",
+                    "disc" => "2",
+                    "track" => "01",
+                    "title" => "Who?",
+                    "year" => "1992",
+                    "genre" => "",
+                    _ => return None,
+                };
+                Some(value.to_string())
+            };
+            render_path(template, value_of, true)
+        };
+
+        assert_eq!(
+            render(DEFAULT_PATH_TEMPLATE),
+            PathBuf::from("AC_DC_ Live/01 - Who_.mp3")
+        );
+        assert_eq!(
+            render("{album_artist}/{year} - {album}/{disc}-{track} {title}"),
+            PathBuf::from("AC_DC/1992 - AC_DC_ Live/2-01 Who_.mp3")
+        );
+        // no genre, so no folder for it
+        assert_eq!(render("{genre}/{title}"), PathBuf::from("Who_.mp3"));
+        assert_eq!(render("{titel}"), PathBuf::from("{titel}.mp3"));
+        assert_eq!(render(""), render(DEFAULT_PATH_TEMPLATE));
+    }
+}
@@ -0,0 +1,386 @@
+use std::collections::{BTreeMap, HashSet};
+use std::path::{Path, PathBuf};
+
+use rayon::prelude::*;
+
+use crate::app::converter::{probe_track, EncoderSettings, ProbedTrack, SUPPORTED_BITRATES};
+use crate::app::thread_handler::catch_panic;
+
+// ID3 tag with a 500x500 cover plus the slack of a half used FAT cluster, per track
+const TAG_OVERHEAD_BYTES: u64 = 96 * 1024;
+// When a file doesn't tell us its length we guess it from its size at this bitrate
+const FALLBACK_SOURCE_KBPS: f64 = 128.0;
+
+pub fn estimate_track_bytes(duration_secs: f64, bitrate_kbps: u16) -> u64 {
+    (duration_secs * bitrate_kbps as f64 * 1000.0 / 8.0).ceil() as u64 + TAG_OVERHEAD_BYTES
+}
+
+/// The folder of a track and its album name. Albums of the same name in different folders, two
+/// "Greatest Hits" say, are told apart by it.
+pub type AlbumKey = (PathBuf, String);
+
+pub struct PlannedTrack {
+    pub path: PathBuf,
+    pub album: String,
+    pub duration_secs: f64,
+}
+
+impl PlannedTrack {
+    pub fn album_key(&self) -> AlbumKey {
+        let folder = self.path.parent().unwrap_or(Path::new("")).to_path_buf();
+        (folder, self.album.clone())
+    }
+
+    fn from_probe(path: &Path, probed: Option<ProbedTrack>) -> Self {
+        let probed_duration = probed.as_ref().and_then(|p| p.duration_secs);
+        let duration_secs = probed_duration.unwrap_or_else(|| {
+            let file_len = std::fs::metadata(path).map(|m| m.len()).unwrap_or(0);
+            file_len as f64 * 8.0 / (FALLBACK_SOURCE_KBPS * 1000.0)
+        });
+
+        Self {
+            path: path.to_path_buf(),
+            album: probed.map(|p| p.album).unwrap_or_default(),
+            duration_secs,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, serde::Deserialize, serde::Serialize)]
+pub enum FitMode {
+    // every album gets the same bitrate
+    Uniform,
+    // start from the uniform bitrate, then raise single albums while there is room left
+    PerAlbum,
+}
+
+/// Bitrates picked so that the selection fills up a size budget.
+#[derive(Debug)]
+pub struct BitratePlan {
+    pub album_bitrates: BTreeMap<AlbumKey, u16>,
+    pub predicted_bytes: u64,
+}
+
+/// Pre-flight check that the selected files will fit on the destination once converted.
+pub struct CapacityPlan {
+    pub tracks: Vec<PlannedTrack>,
+    // None when the free space of the destination could not be queried
+    pub free_bytes: Option<u64>,
+    pub excluded_albums: HashSet<AlbumKey>,
+}
+
+impl CapacityPlan {
+    pub fn new(files: &[PathBuf], destination: &Path) -> Self {
+        let tracks = files
+            .par_iter()
+            .map(|path| {
+                // a probe that panics leaves the file to be sized up from its length
+                let probed = catch_panic(path, || probe_track(path).ok()).flatten();
+                PlannedTrack::from_probe(path, probed)
+            })
+            .collect();
+
+        Self {
+            tracks,
+            free_bytes: fs2::available_space(destination).ok(),
+            excluded_albums: HashSet::new(),
+        }
+    }
+
+    pub fn selected_tracks(&self) -> impl Iterator<Item = &PlannedTrack> {
+        self.tracks
+            .iter()
+            .filter(|track| !self.excluded_albums.contains(&track.album_key()))
+    }
+
+    pub fn selected_files(&self) -> Vec<PathBuf> {
+        self.selected_tracks()
+            .map(|track| track.path.clone())
+            .collect()
+    }
+
+    pub fn estimated_bytes(&self, encoder_settings: &EncoderSettings) -> u64 {
+        self.selected_tracks()
+            .map(|track| {
+                estimate_track_bytes(
+                    track.duration_secs,
+                    encoder_settings.effective_bitrate_kbps(),
+                )
+            })
+            .sum()
+    }
+
+    pub fn fits(&self, encoder_settings: &EncoderSettings) -> bool {
+        match self.free_bytes {
+            Some(free_bytes) => self.estimated_bytes(encoder_settings) <= free_bytes,
+            None => true,
+        }
+    }
+
+    /// Highest supported bitrate at which the current selection fits, if any does.
+    pub fn highest_fitting_bitrate(&self, encoder_settings: &EncoderSettings) -> Option<u16> {
+        SUPPORTED_BITRATES
+            .iter()
+            .rev()
+            .copied()
+            .find(|&bitrate_kbps| {
+                let mut settings = encoder_settings.clone();
+                settings.bitrate_kbps = bitrate_kbps;
+                self.fits(&settings)
+            })
+    }
+
+    /// Picks the highest bitrates at which the selection still fits into `budget_bytes`.
+    pub fn fit_to_budget(&self, budget_bytes: u64, mode: FitMode) -> Option<BitratePlan> {
+        let mut album_durations: BTreeMap<AlbumKey, Vec<f64>> = BTreeMap::new();
+        for track in self.selected_tracks() {
+            album_durations
+                .entry(track.album_key())
+                .or_default()
+                .push(track.duration_secs);
+        }
+
+        let album_bytes = |durations: &[f64], bitrate_kbps: u16| -> u64 {
+            durations
+                .iter()
+                .map(|&duration_secs| estimate_track_bytes(duration_secs, bitrate_kbps))
+                .sum()
+        };
+        let total_bytes = |bitrates: &BTreeMap<&AlbumKey, usize>| -> u64 {
+            bitrates
+                .iter()
+                .map(|(album, &i)| album_bytes(&album_durations[album], SUPPORTED_BITRATES[i]))
+                .sum()
+        };
+
+        // index into SUPPORTED_BITRATES for every album
+        let uniform = (0..SUPPORTED_BITRATES.len()).rev().find(|&i| {
+            let bitrates = album_durations.keys().map(|album| (album, i)).collect();
+            total_bytes(&bitrates) <= budget_bytes
+        })?;
+        let mut bitrates: BTreeMap<&AlbumKey, usize> = album_durations
+            .keys()
+            .map(|album| (album, uniform))
+            .collect();
+
+        if mode == FitMode::PerAlbum {
+            // smaller albums first, so that the leftover space upgrades as many albums as possible
+            let mut by_size: Vec<&AlbumKey> = album_durations.keys().collect();
+            by_size.sort_by_key(|album| album_bytes(&album_durations[*album], 128));
+
+            let mut upgraded = true;
+            while upgraded {
+                upgraded = false;
+                for album in &by_size {
+                    let current = bitrates[album];
+                    if current + 1 >= SUPPORTED_BITRATES.len() {
+                        continue;
+                    }
+
+                    bitrates.insert(album, current + 1);
+                    if total_bytes(&bitrates) <= budget_bytes {
+                        upgraded = true;
+                    } else {
+                        bitrates.insert(album, current);
+                    }
+                }
+            }
+        }
+
+        Some(BitratePlan {
+            predicted_bytes: total_bytes(&bitrates),
+            album_bitrates: bitrates
+                .into_iter()
+                .map(|(album, i)| (album.clone(), SUPPORTED_BITRATES[i]))
+                .collect(),
+        })
+    }
+
+    /// Estimated output size of every album, sorted by folder and album name.
+    pub fn album_sizes(&self, encoder_settings: &EncoderSettings) -> BTreeMap<AlbumKey, u64> {
+        let mut sizes = BTreeMap::new();
+        for track in &self.tracks {
+            let bitrate_kbps = encoder_settings.effective_bitrate_kbps();
+            *sizes.entry(track.album_key()).or_insert(0) +=
+                estimate_track_bytes(track.duration_secs, bitrate_kbps);
+        }
+        sizes
+    }
+}
+
+pub fn format_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 4] = ["B", "KB", "MB", "GB"];
+
+    let mut value = bytes as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+
+    if unit == 0 {
+        format!("{} {}", bytes, UNITS[0])
+    } else {
+        format!("{:.1} {}", value, UNITS[unit])
+    }
+}
+
+/// Parses sizes like "3.5 GB", "700MB" or "2g". Units are binary, plain numbers are bytes.
+pub fn parse_size(input: &str) -> Option<u64> {
+    let input = input.trim();
+    let split = input
+        .find(|c: char| !(c.is_ascii_digit() || c == '.'))
+        .unwrap_or(input.len());
+    let (number, unit) = input.split_at(split);
+
+    let value: f64 = number.parse().ok()?;
+    let multiplier: u64 = match unit.trim().to_ascii_lowercase().as_str() {
+        "" | "b" => 1,
+        "k" | "kb" | "kib" => 1024,
+        "m" | "mb" | "mib" => 1024 * 1024,
+        "g" | "gb" | "gib" => 1024 * 1024 * 1024,
+        _ => return None,
+    };
+
+    Some((value * multiplier as f64) as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn plan(free_bytes: Option<u64>) -> CapacityPlan {
+        let track = |album: &str, duration_secs: f64| PlannedTrack {
+            path: PathBuf::from(format!("{}/01.flac", album)),
+            album: album.to_string(),
+            duration_secs,
+        };
+
+        CapacityPlan {
+            tracks: vec![track("A", 600.0), track("A", 600.0), track("B", 1200.0)],
+            free_bytes,
+            excluded_albums: HashSet::new(),
+        }
+    }
+
+    fn key(album: &str) -> AlbumKey {
+        (PathBuf::from(album), album.to_string())
+    }
+
+    #[test]
+    fn test_estimate_track_bytes() {
+        // 60 seconds at 128 kbps is 960 000 bytes of audio
+        assert_eq!(
+            estimate_track_bytes(60.0, 128),
+            960_000 + TAG_OVERHEAD_BYTES
+        );
+    }
+
+    #[test]
+    fn test_highest_fitting_bitrate() {
+        // 2400 seconds of audio, 3 tracks worth of overhead
+        let free_bytes = 2400 * 128 * 1000 / 8 + 3 * TAG_OVERHEAD_BYTES;
+        let mut plan = plan(Some(free_bytes));
+
+        assert!(!plan.fits(&EncoderSettings::default()));
+        assert_eq!(
+            plan.highest_fitting_bitrate(&EncoderSettings::default()),
+            Some(128)
+        );
+
+        plan.excluded_albums.insert(key("B"));
+        assert_eq!(plan.selected_files().len(), 2);
+        assert_eq!(
+            plan.highest_fitting_bitrate(&EncoderSettings::default()),
+            Some(256)
+        );
+    }
+
+    #[test]
+    fn test_unknown_free_space_always_fits() {
+        let encoder_settings = EncoderSettings {
+            bitrate_kbps: 320,
+            ..Default::default()
+        };
+        assert!(plan(None).fits(&encoder_settings));
+    }
+
+    #[test]
+    fn test_audiobook_bitrate() {
+        // a job or profile can ask for more, audiobooks still come out at 64 kbps
+        let mut encoder_settings = EncoderSettings {
+            bitrate_kbps: 320,
+            ..Default::default()
+        };
+        encoder_settings.audiobook.enabled = true;
+        assert_eq!(encoder_settings.effective_bitrate_kbps(), 64);
+        assert_eq!(
+            plan(None).estimated_bytes(&encoder_settings),
+            2400 * 64 * 1000 / 8 + 3 * TAG_OVERHEAD_BYTES
+        );
+    }
+
+    #[test]
+    fn test_fit_to_budget() {
+        let plan = plan(None);
+        let budget = 2400 * 128 * 1000 / 8 + 3 * TAG_OVERHEAD_BYTES;
+
+        let uniform = plan.fit_to_budget(budget, FitMode::Uniform).unwrap();
+        assert_eq!(uniform.album_bitrates[&key("A")], 128);
+        assert_eq!(uniform.album_bitrates[&key("B")], 128);
+        assert_eq!(uniform.predicted_bytes, budget);
+
+        // room for exactly one album to go up a step, B goes first since it is the smaller one
+        let budget = budget + 1200 * 32 * 1000 / 8;
+        let per_album = plan.fit_to_budget(budget, FitMode::PerAlbum).unwrap();
+        assert_eq!(per_album.album_bitrates[&key("A")], 128);
+        assert_eq!(per_album.album_bitrates[&key("B")], 160);
+        assert_eq!(per_album.predicted_bytes, budget);
+
+        assert!(plan.fit_to_budget(1024, FitMode::Uniform).is_none());
+    }
+
+    #[test]
+    fn test_albums_of_the_same_name() {
+        let mut plan = plan(None);
+        // another artist's album called A, half the length
+        plan.tracks.push(PlannedTrack {
+            path: PathBuf::from("Other/A/01.flac"),
+            album: "A".to_string(),
+            duration_secs: 600.0,
+        });
+        let other = (PathBuf::from("Other/A"), "A".to_string());
+
+        let sizes = plan.album_sizes(&EncoderSettings::default());
+        assert_eq!(sizes.len(), 3);
+        assert_eq!(sizes[&key("A")], 2 * sizes[&other]);
+
+        plan.excluded_albums.insert(other.clone());
+        assert_eq!(plan.selected_files().len(), 3);
+
+        plan.excluded_albums.clear();
+        let budget = 3000 * 128 * 1000 / 8 + 4 * TAG_OVERHEAD_BYTES + 600 * 32 * 1000 / 8;
+        let per_album = plan.fit_to_budget(budget, FitMode::PerAlbum).unwrap();
+        // only the smallest of them goes up a step
+        assert_eq!(per_album.album_bitrates[&key("A")], 128);
+        assert_eq!(per_album.album_bitrates[&other], 160);
+    }
+
+    #[test]
+    fn test_parse_size() {
+        assert_eq!(
+            parse_size("3.5 GB"),
+            Some(3 * 1024 * 1024 * 1024 + 512 * 1024 * 1024)
+        );
+        assert_eq!(parse_size("700MB"), Some(700 * 1024 * 1024));
+        assert_eq!(parse_size("2g"), Some(2 * 1024 * 1024 * 1024));
+        assert_eq!(parse_size("1000"), Some(1000));
+        assert_eq!(parse_size("lots"), None);
+    }
+
+    #[test]
+    fn test_format_bytes() {
+        assert_eq!(format_bytes(512), "512 B");
+        assert_eq!(format_bytes(3 * 1024 * 1024 * 1024 / 2), "1.5 GB");
+    }
+}
@@ -0,0 +1,513 @@
+use std::collections::{BTreeMap, HashMap};
+use std::io;
+use std::path::{Component, Path, PathBuf};
+
+use log::{error, warn};
+use regex::Regex;
+
+use crate::app::atomic_write::write_atomically;
+use crate::app::converter::{sanitize_file_name, ConversionOutput};
+
+const LIBRARY_PLAYLIST_NAME: &str = "All Music";
+
+pub const PLAYLIST_EXTENSIONS: [&str; 4] = ["m3u", "m3u8", "pls", "xspf"];
+
+// The XMB comes from a Windows world: it wants CRLF and backslashes, and reads plain .m3u
+// files as Latin-1. Entries that can't be written in Latin-1 would point at files the PSP
+// can't find, so they only make it into .m3u8 playlists, which are read as UTF-8.
+#[derive(Clone, Copy, Debug, PartialEq, serde::Deserialize, serde::Serialize)]
+pub enum PlaylistFormat {
+    M3u,
+    M3u8,
+}
+
+impl PlaylistFormat {
+    pub fn extension(&self) -> &'static str {
+        match self {
+            PlaylistFormat::M3u => "m3u",
+            PlaylistFormat::M3u8 => "m3u8",
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, serde::Deserialize, serde::Serialize)]
+#[serde(default)]
+pub struct PlaylistOptions {
+    pub per_album: bool,
+    pub library: bool,
+    pub format: PlaylistFormat,
+}
+
+impl Default for PlaylistOptions {
+    fn default() -> Self {
+        Self {
+            per_album: false,
+            library: false,
+            format: PlaylistFormat::M3u8,
+        }
+    }
+}
+
+/// Writes the playlists asked for in `options` for the tracks a job produced.
+///
+/// Album playlists go next to the tracks, the library playlist into `destination`.
+/// `fat_safe_names` names album playlists the way the tracks' folders were named.
+/// Returns the paths of the playlists that were written.
+pub fn write_job_playlists(
+    destination: &Path,
+    outputs: &[ConversionOutput],
+    options: &PlaylistOptions,
+    fat_safe_names: bool,
+) -> Vec<PathBuf> {
+    let mut playlists: Vec<(PathBuf, Vec<&ConversionOutput>)> = Vec::new();
+
+    if options.per_album {
+        let mut albums: BTreeMap<&str, Vec<&ConversionOutput>> = BTreeMap::new();
+        for output in outputs {
+            albums.entry(&output.album).or_default().push(output);
+        }
+
+        for (album, tracks) in albums {
+            // loose tracks without an album are already covered by the library playlist
+            let Some(album_dir) = tracks[0].output_path.parent().filter(|_| !album.is_empty())
+            else {
+                continue;
+            };
+            let file_name = sanitize_file_name(album, fat_safe_names);
+            let path = album_dir.join(format!("{}.{}", file_name, options.format.extension()));
+            playlists.push((path, tracks));
+        }
+    }
+
+    if options.library {
+        let path = destination.join(format!(
+            "{}.{}",
+            LIBRARY_PLAYLIST_NAME,
+            options.format.extension()
+        ));
+        playlists.push((path, outputs.iter().collect()));
+    }
+
+    playlists
+        .into_iter()
+        .filter_map(|(path, mut tracks)| {
+            sort_tracks(&mut tracks);
+            match write_playlist(&path, &tracks, options.format) {
+                Ok(()) => Some(path),
+                Err(e) => {
+                    error!("Error writing playlist {:?}: {}", path, e);
+                    None
+                }
+            }
+        })
+        .collect()
+}
+
+/// Rewrites playlists found among the sources so that they point at the converted tracks.
+///
+/// The curated order of the source playlist is kept, entries whose track failed to convert
+/// are left out. Every playlist ends up in `destination`, named after the source playlist.
+pub fn write_converted_playlists(
+    destination: &Path,
+    source_playlists: &[SourcePlaylist],
+    outputs: &[ConversionOutput],
+    format: PlaylistFormat,
+) -> Vec<PathBuf> {
+    // an album rip split along its CUE sheet turns one entry into many tracks
+    let mut by_source: HashMap<&Path, Vec<&ConversionOutput>> = HashMap::new();
+    for output in outputs {
+        by_source
+            .entry(output.source_path.as_path())
+            .or_default()
+            .push(output);
+    }
+
+    source_playlists
+        .iter()
+        .filter_map(|playlist| {
+            let mut tracks: Vec<&ConversionOutput> = Vec::new();
+            let mut missing = 0;
+            for entry in &playlist.entries {
+                match by_source.get(entry.as_path()) {
+                    Some(converted) => tracks.extend(converted),
+                    None => missing += 1,
+                }
+            }
+            if missing > 0 {
+                warn!(
+                    "{} of the tracks of {:?} were not converted",
+                    missing, playlist.path
+                );
+            }
+
+            let stem = playlist.path.file_stem()?.to_string_lossy();
+            let path = destination.join(format!("{}.{}", stem, format.extension()));
+            match write_playlist(&path, &tracks, format) {
+                Ok(()) => Some(path),
+                Err(e) => {
+                    error!("Error writing playlist {:?}: {}", path, e);
+                    None
+                }
+            }
+        })
+        .collect()
+}
+
+/// Writes `tracks`, in the given order, as a playlist with paths relative to its own folder.
+pub fn write_playlist(
+    playlist_path: &Path,
+    tracks: &[&ConversionOutput],
+    format: PlaylistFormat,
+) -> io::Result<()> {
+    let base_dir = playlist_path.parent().unwrap_or(Path::new(""));
+    write_atomically(playlist_path, &render_playlist(base_dir, tracks, format))
+}
+
+pub fn render_playlist(
+    base_dir: &Path,
+    tracks: &[&ConversionOutput],
+    format: PlaylistFormat,
+) -> Vec<u8> {
+    let mut bytes = b"#EXTM3U\r\n".to_vec();
+
+    for track in tracks {
+        let entry = relative_path(&track.output_path, base_dir);
+        let info = format!(
+            "#EXTINF:{},{} - {}",
+            track.duration_secs.round() as u64,
+            track.artist,
+            track.title
+        );
+
+        let Some(entry_bytes) = encode(&entry, format) else {
+            warn!("Can't write {:?} into a .m3u playlist, skipping it", entry);
+            continue;
+        };
+
+        // the info line is only cosmetic, so it is fine to lose characters in there
+        let info_bytes = encode(&info, format).unwrap_or_else(|| {
+            info.chars()
+                .map(|c| if (c as u32) < 0x100 { c as u8 } else { b'?' })
+                .collect()
+        });
+
+        bytes.extend(info_bytes);
+        bytes.extend(b"\r\n");
+        bytes.extend(entry_bytes);
+        bytes.extend(b"\r\n");
+    }
+
+    bytes
+}
+
+fn encode(line: &str, format: PlaylistFormat) -> Option<Vec<u8>> {
+    match format {
+        PlaylistFormat::M3u8 => Some(line.as_bytes().to_vec()),
+        PlaylistFormat::M3u => line.chars().map(|c| u8::try_from(c as u32).ok()).collect(),
+    }
+}
+
+// disc, then track, then file name for everything that has no numbers at all
+// album by album, so a library playlist doesn't play every track 1 before the track 2s
+fn sort_tracks(tracks: &mut [&ConversionOutput]) {
+    tracks.sort_by(|a, b| {
+        let key = |t: &ConversionOutput| {
+            (
+                t.output_path.parent().map(Path::to_path_buf),
+                t.album.clone(),
+                leading_number(&t.disc_number),
+                leading_number(&t.track_number),
+            )
+        };
+        key(a)
+            .cmp(&key(b))
+            .then_with(|| a.output_path.cmp(&b.output_path))
+    });
+}
+
+// "3/12" -> 3
+pub fn leading_number(str: &str) -> u32 {
+    let digits: String = str
+        .trim()
+        .chars()
+        .take_while(|c| c.is_ascii_digit())
+        .collect();
+    digits.parse().unwrap_or(0)
+}
+
+/// `path` relative to `base_dir`, using backslashes as separators.
+pub fn relative_path(path: &Path, base_dir: &Path) -> String {
+    let path_components: Vec<Component<'_>> = path.components().collect();
+    let base_components: Vec<Component<'_>> = base_dir.components().collect();
+
+    let common = path_components
+        .iter()
+        .zip(&base_components)
+        .take_while(|(a, b)| a == b)
+        .count();
+
+    let mut parts: Vec<String> = vec!["..".to_string(); base_components.len() - common];
+    parts.extend(
+        path_components[common..]
+            .iter()
+            .map(|c| c.as_os_str().to_string_lossy().to_string()),
+    );
+    parts.join("\\")
+}
+
+/// A playlist the user already had, pointing at source files.
+#[derive(Clone, Debug)]
+pub struct SourcePlaylist {
+    pub path: PathBuf,
+    // absolute paths, in playlist order
+    pub entries: Vec<PathBuf>,
+}
+
+impl SourcePlaylist {
+    pub fn read(path: &Path) -> io::Result<Self> {
+        let text = decode_text(std::fs::read(path)?);
+        let text = text.trim_start_matches('\u{feff}');
+        let base_dir = path.parent().unwrap_or(Path::new(""));
+
+        let extension = path
+            .extension()
+            .and_then(|ext| ext.to_str())
+            .unwrap_or_default()
+            .to_ascii_lowercase();
+        let entries = match extension.as_str() {
+            "pls" => parse_pls(text, base_dir),
+            "xspf" => parse_xspf(text, base_dir),
+            _ => parse_m3u(text, base_dir),
+        };
+
+        Ok(Self {
+            path: path.to_path_buf(),
+            entries,
+        })
+    }
+}
+
+// .m3u8 and .xspf are UTF-8 by definition, old .m3u, .pls and .cue files are often Latin-1
+pub fn decode_text(bytes: Vec<u8>) -> String {
+    match String::from_utf8(bytes) {
+        Ok(text) => text,
+        Err(e) => e.into_bytes().iter().map(|&b| b as char).collect(),
+    }
+}
+
+fn parse_m3u(text: &str, base_dir: &Path) -> Vec<PathBuf> {
+    text.lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(|line| resolve_entry(line, base_dir))
+        .collect()
+}
+
+fn parse_pls(text: &str, base_dir: &Path) -> Vec<PathBuf> {
+    let mut entries: Vec<(u32, PathBuf)> = text
+        .lines()
+        .filter_map(|line| {
+            let (key, value) = line.trim().split_once('=')?;
+            let number = key.strip_prefix("File")?.parse().ok()?;
+            Some((number, resolve_entry(value.trim(), base_dir)))
+        })
+        .collect();
+    entries.sort_by_key(|(number, _)| *number);
+    entries.into_iter().map(|(_, path)| path).collect()
+}
+
+fn parse_xspf(text: &str, base_dir: &Path) -> Vec<PathBuf> {
+    let location = Regex::new(r"(?s)<location>\s*(.*?)\s*</location>").unwrap();
+    location
+        .captures_iter(text)
+        .map(|captures| {
+            // every xspf location is a URI, relative ones included
+            let location = unescape_xml(&captures[1]);
+            if location.starts_with("file://") {
+                resolve_entry(&location, base_dir)
+            } else {
+                resolve_entry(&percent_decode(&location), base_dir)
+            }
+        })
+        .collect()
+}
+
+fn unescape_xml(str: &str) -> String {
+    str.replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&apos;", "'")
+        .replace("&amp;", "&")
+}
+
+// Turns a playlist entry (relative, absolute or a file:// URI) into an absolute path
+fn resolve_entry(entry: &str, base_dir: &Path) -> PathBuf {
+    let entry = match entry.strip_prefix("file://") {
+        // file:///C:/Music on Windows, file:///home/me/Music everywhere else
+        Some(uri) => {
+            let path = percent_decode(uri);
+            if path.len() > 3 && path.as_bytes()[2] == b':' {
+                path[1..].to_string()
+            } else {
+                path
+            }
+        }
+        None => entry.to_string(),
+    };
+
+    // playlists made on Windows use backslashes, which are just characters elsewhere
+    let entry = if cfg!(windows) {
+        entry
+    } else {
+        entry.replace('\\', "/")
+    };
+
+    let path = Path::new(&entry);
+    if path.is_absolute() {
+        path.to_path_buf()
+    } else {
+        base_dir.join(path)
+    }
+}
+
+fn percent_decode(str: &str) -> String {
+    let bytes = str.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+
+    let mut i = 0;
+    while i < bytes.len() {
+        let hex = bytes
+            .get(i + 1..i + 3)
+            .and_then(|hex| std::str::from_utf8(hex).ok())
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+        match (bytes[i], hex) {
+            (b'%', Some(byte)) => {
+                decoded.push(byte);
+                i += 3;
+            }
+            (byte, _) => {
+                decoded.push(byte);
+                i += 1;
+            }
+        }
+    }
+
+    String::from_utf8_lossy(&decoded).to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn output(path: &str, album: &str, disc: &str, track: &str, title: &str) -> ConversionOutput {
+        ConversionOutput {
+            source_path: PathBuf::new(),
+            output_path: PathBuf::from(path),
+            bytes_written: 0,
+            audio_bytes: 0,
+            duration_secs: 61.6,
+            elapsed_secs: 0.0,
+            title: title.to_string(),
+            artist: "Artist".to_string(),
+            album: album.to_string(),
+            track_number: track.to_string(),
+            disc_number: disc.to_string(),
+            levels: Default::default(),
+            attenuation_db: 0.0,
+            passthrough: false,
+            lossy_transcode: false,
+        }
+    }
+
+    #[test]
+    fn test_relative_path() {
+        let base = Path::new("/psp/MUSIC");
+        assert_eq!(
+            relative_path(Path::new("/psp/MUSIC/Album/01 - A.mp3"), base),
+            "Album\\01 - A.mp3"
+        );
+        assert_eq!(
+            relative_path(
+                Path::new("/psp/MUSIC/Album/01 - A.mp3"),
+                &base.join("Album")
+            ),
+            "01 - A.mp3"
+        );
+        assert_eq!(
+            relative_path(
+                Path::new("/psp/MUSIC/Other/01 - A.mp3"),
+                &base.join("Album")
+            ),
+            "..\\Other\\01 - A.mp3"
+        );
+    }
+
+    #[test]
+    fn test_render_playlist_order_and_encoding() {
+        let outputs = [
+            output("/m/Album/01 - B.mp3", "Album", "2", "1/10", "B"),
+            output("/m/Album/10 - A.mp3", "Album", "1", "10/10", "A"),
+            output("/m/Album/02 - C.mp3", "Album", "1", "2/10", "C"),
+            output("/m/Album/03 - 夢.mp3", "Album", "1", "3", "夢"),
+        ];
+        let mut tracks: Vec<&ConversionOutput> = outputs.iter().collect();
+        sort_tracks(&mut tracks);
+
+        let m3u8 = render_playlist(Path::new("/m"), &tracks, PlaylistFormat::M3u8);
+        assert_eq!(
+            String::from_utf8(m3u8).unwrap(),
+            "#EXTM3U\r\n\
+             #EXTINF:62,Artist - C\r\nAlbum\\02 - C.mp3\r\n\
+             #EXTINF:62,Artist - 夢\r\nAlbum\\03 - 夢.mp3\r\n\
+             #EXTINF:62,Artist - A\r\nAlbum\\10 - A.mp3\r\n\
+             #EXTINF:62,Artist - B\r\nAlbum\\01 - B.mp3\r\n"
+        );
+
+        // the Japanese title can't be written in Latin-1, so .m3u drops that entry
+        let m3u = render_playlist(Path::new("/m"), &tracks, PlaylistFormat::M3u);
+        let m3u = String::from_utf8(m3u).unwrap();
+        assert_eq!(m3u.lines().count(), 7);
+        assert!(!m3u.contains("03 - "));
+    }
+
+    #[test]
+    fn test_library_playlist_keeps_albums_together() {
+        let outputs = [
+            output("/m/Zebra/01 - Z1.mp3", "Zebra", "", "1", "Z1"),
+            output("/m/Alpha/02 - A2.mp3", "Alpha", "", "2", "A2"),
+            output("/m/Zebra/02 - Z2.mp3", "Zebra", "", "2", "Z2"),
+            output("/m/Alpha/01 - A1.mp3", "Alpha", "", "1", "A1"),
+        ];
+        let mut tracks: Vec<&ConversionOutput> = outputs.iter().collect();
+        sort_tracks(&mut tracks);
+
+        let titles: Vec<&str> = tracks.iter().map(|track| track.title.as_str()).collect();
+        assert_eq!(titles, ["A1", "A2", "Z1", "Z2"]);
+    }
+
+    #[test]
+    fn test_parse_source_playlists() {
+        let base = Path::new("/music/lists");
+        let expected = vec![
+            PathBuf::from("/music/lists/Album/01 - A.flac"),
+            PathBuf::from("/music/B & C.flac"),
+        ];
+
+        let m3u = "#EXTM3U\r\n#EXTINF:100,A\r\nAlbum/01 - A.flac\r\n\r\n/music/B & C.flac\r\n";
+        assert_eq!(parse_m3u(m3u, base), expected);
+
+        let pls = "[playlist]\nFile2=/music/B & C.flac\nTitle2=B\nFile1=Album/01 - A.flac\nNumberOfEntries=2\n";
+        assert_eq!(parse_pls(pls, base), expected);
+
+        let xspf = r#"<?xml version="1.0" encoding="UTF-8"?>
+<playlist version="1" xmlns="http://xspf.org/ns/0/">
+  <trackList>
+    <track><location>Album/01%20-%20A.flac</location></track>
+    <track>
+      <location>file:///music/B%20&amp;%20C.flac</location>
+    </track>
+  </trackList>
+</playlist>"#;
+        assert_eq!(parse_xspf(xspf, base), expected);
+    }
+}
@@ -0,0 +1,277 @@
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
+
+use rayon::prelude::*;
+
+use crate::app::converter::{probe_track, AudioConverter, AudioFiletype, TagOverrides, TrackTags};
+use crate::app::thread_handler::catch_panic;
+
+// MPEG-1 layer III tops out here, anything above gets resampled
+pub const MAX_SAMPLE_RATE: u32 = 48_000;
+
+/// Something about a source worth fixing before a long conversion, with the fix we'd suggest.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Problem {
+    Unreadable(String),
+    UnsupportedCodec(String),
+    HighSampleRate(u32),
+    MissingTitle { suggested: String },
+    MissingTrackNumber { suggested: String },
+    DuplicateTrackNumber { number: String, suggested: String },
+    MissingCover,
+    // the other tracks of the folder mostly say `expected`
+    AlbumArtistMismatch { found: String, expected: String },
+}
+
+impl Problem {
+    pub fn describe(&self) -> String {
+        match self {
+            Problem::Unreadable(e) => format!("can't be read: {}", e),
+            Problem::UnsupportedCodec(codec) => format!("{} can't be decoded", codec),
+            Problem::HighSampleRate(rate) => format!("{} Hz, over what MP3 can hold", rate),
+            Problem::MissingTitle { .. } => "no title".to_string(),
+            Problem::MissingTrackNumber { .. } => "no track number".to_string(),
+            Problem::DuplicateTrackNumber { number, .. } => {
+                format!("track number {} is taken twice", number)
+            }
+            Problem::MissingCover => "no cover".to_string(),
+            Problem::AlbumArtistMismatch { found, expected } => {
+                format!(
+                    "album artist {:?}, the rest of the folder has {:?}",
+                    found, expected
+                )
+            }
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Finding {
+    pub path: PathBuf,
+    pub problem: Problem,
+}
+
+/// Reads the headers and tags of every file, overrides included, without decoding any audio.
+pub fn scan_problems(
+    files: &[PathBuf],
+    tag_overrides: &HashMap<PathBuf, TagOverrides>,
+) -> Vec<Finding> {
+    let scanned: Vec<(PathBuf, Result<TrackTags, Problem>, Option<Problem>)> = files
+        .par_iter()
+        .map(|file| {
+            let scanned = catch_panic(file, || scan_file(file, tag_overrides))
+                .unwrap_or_else(|| Err(Problem::Unreadable("the decoder panicked".to_string())));
+            (file, scanned)
+        })
+        .map(|(file, scanned)| match scanned {
+            Ok((tags, sample_rate)) => {
+                let too_high = sample_rate.filter(|&rate| rate > MAX_SAMPLE_RATE);
+                (
+                    file.clone(),
+                    Ok(tags),
+                    too_high.map(Problem::HighSampleRate),
+                )
+            }
+            Err(problem) => (file.clone(), Err(problem), None),
+        })
+        .collect();
+
+    let mut findings = Vec::new();
+    let mut tagged = Vec::new();
+    for (path, tags, sample_rate_problem) in scanned {
+        if let Some(problem) = sample_rate_problem {
+            findings.push(Finding {
+                path: path.clone(),
+                problem,
+            });
+        }
+        match tags {
+            Ok(tags) => tagged.push((path, tags)),
+            Err(problem) => findings.push(Finding { path, problem }),
+        }
+    }
+    findings.extend(find_tag_problems(&tagged));
+    findings.sort_by(|a, b| a.path.cmp(&b.path));
+    findings
+}
+
+// the tags and the sample rate of a file
+fn scan_file(
+    file: &Path,
+    tag_overrides: &HashMap<PathBuf, TagOverrides>,
+) -> Result<(TrackTags, Option<u32>), Problem> {
+    let probed = probe_track(file).map_err(|e| Problem::Unreadable(e.to_string()))?;
+    if probed.codec.is_none() {
+        let extension = file.extension().unwrap_or_default().to_string_lossy();
+        return Err(Problem::UnsupportedCodec(extension.to_string()));
+    }
+    let tags = AudioConverter::new(file.to_path_buf(), AudioFiletype::MP3)
+        .map_err(|e| Problem::Unreadable(e.to_string()))?
+        .with_tag_overrides(tag_overrides.get(file).cloned().unwrap_or_default())
+        .read_tags()
+        .map_err(|e| Problem::Unreadable(e.to_string()))?;
+    Ok((tags, probed.sample_rate))
+}
+
+fn find_tag_problems(tracks: &[(PathBuf, TrackTags)]) -> Vec<Finding> {
+    let mut folders: BTreeMap<&Path, Vec<&(PathBuf, TrackTags)>> = BTreeMap::new();
+    for track in tracks {
+        folders
+            .entry(track.0.parent().unwrap_or(Path::new("")))
+            .or_default()
+            .push(track);
+    }
+
+    let mut findings = Vec::new();
+    for tracks in folders.values_mut() {
+        tracks.sort_by(|a, b| a.0.cmp(&b.0));
+        let expected_artist = most_common(tracks.iter().map(|(_, tags)| &tags.album_artist));
+        // every disc of a box set counts from 1 again
+        let disc = |tags: &TrackTags| parse_track_number(&tags.disc_number).unwrap_or(1);
+        let mut numbers: HashMap<(u32, u32), usize> = HashMap::new();
+        for (_, tags) in tracks.iter() {
+            if let Some(number) = parse_track_number(&tags.track_number) {
+                *numbers.entry((disc(tags), number)).or_default() += 1;
+            }
+        }
+
+        for (position, (path, tags)) in tracks.iter().enumerate() {
+            let mut report = |problem| {
+                findings.push(Finding {
+                    path: path.clone(),
+                    problem,
+                })
+            };
+            // the file name mostly starts with the number, else the order in the folder will do
+            let suggested_number = || {
+                let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+                let digits: String = stem.chars().take_while(char::is_ascii_digit).collect();
+                parse_track_number(&digits)
+                    .unwrap_or(position as u32 + 1)
+                    .to_string()
+            };
+
+            if tags.title.trim().is_empty() {
+                let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+                report(Problem::MissingTitle {
+                    suggested: stem.to_string(),
+                });
+            }
+            match parse_track_number(&tags.track_number) {
+                None => report(Problem::MissingTrackNumber {
+                    suggested: suggested_number(),
+                }),
+                Some(number) if numbers[&(disc(tags), number)] > 1 => {
+                    report(Problem::DuplicateTrackNumber {
+                        number: number.to_string(),
+                        suggested: suggested_number(),
+                    })
+                }
+                Some(_) => {}
+            }
+            if !tags.has_cover {
+                report(Problem::MissingCover);
+            }
+            if let Some(expected) = expected_artist {
+                if &tags.album_artist != expected {
+                    report(Problem::AlbumArtistMismatch {
+                        found: tags.album_artist.clone(),
+                        expected: expected.clone(),
+                    });
+                }
+            }
+        }
+    }
+    findings
+}
+
+// "3", "03" and "3/12" are all track 3
+fn parse_track_number(track_number: &str) -> Option<u32> {
+    let number = track_number.split('/').next()?.trim();
+    number.parse().ok().filter(|&number| number > 0)
+}
+
+// None when there is nothing but empty values
+fn most_common<'a>(values: impl Iterator<Item = &'a String>) -> Option<&'a String> {
+    let mut counts: BTreeMap<&String, usize> = BTreeMap::new();
+    for value in values.filter(|value| !value.is_empty()) {
+        *counts.entry(value).or_default() += 1;
+    }
+    counts
+        .into_iter()
+        .max_by_key(|&(_, count)| count)
+        .map(|(value, _)| value)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn track(path: &str, title: &str, number: &str, album_artist: &str) -> (PathBuf, TrackTags) {
+        let tags = TrackTags {
+            title: title.to_string(),
+            track_number: number.to_string(),
+            album_artist: album_artist.to_string(),
+            has_cover: true,
+            ..Default::default()
+        };
+        (PathBuf::from(path), tags)
+    }
+
+    fn disc_track(path: &str, disc_number: &str) -> (PathBuf, TrackTags) {
+        let (path, tags) = track(path, "Song", "1", "The Band");
+        let tags = TrackTags {
+            disc_number: disc_number.to_string(),
+            ..tags
+        };
+        (path, tags)
+    }
+
+    #[test]
+    fn test_find_tag_problems() {
+        let tracks = vec![
+            track("/album/01 Intro.flac", "Intro", "1/4", "The Band"),
+            track("/album/02 Song.flac", "", "2", "The Band"),
+            track("/album/03 Other.flac", "Other", "2", "The Band feat. Guest"),
+            track("/album/04 Outro.flac", "Outro", "", "The Band"),
+            track("/other/Untitled.flac", "Untitled", "", ""),
+            // the same number on two discs is no duplicate
+            disc_track("/box/1-01.flac", "1/2"),
+            disc_track("/box/2-01.flac", "2/2"),
+        ];
+        let finding = |path: &str, problem| Finding {
+            path: PathBuf::from(path),
+            problem,
+        };
+        let number = |suggested: &str| Problem::MissingTrackNumber {
+            suggested: suggested.to_string(),
+        };
+        let duplicate = |suggested: &str| Problem::DuplicateTrackNumber {
+            number: "2".to_string(),
+            suggested: suggested.to_string(),
+        };
+        assert_eq!(
+            find_tag_problems(&tracks),
+            vec![
+                finding(
+                    "/album/02 Song.flac",
+                    Problem::MissingTitle {
+                        suggested: "02 Song".to_string()
+                    }
+                ),
+                finding("/album/02 Song.flac", duplicate("2")),
+                finding("/album/03 Other.flac", duplicate("3")),
+                finding(
+                    "/album/03 Other.flac",
+                    Problem::AlbumArtistMismatch {
+                        found: "The Band feat. Guest".to_string(),
+                        expected: "The Band".to_string()
+                    }
+                ),
+                finding("/album/04 Outro.flac", number("4")),
+                // nothing to go by but the order in the folder
+                finding("/other/Untitled.flac", number("1")),
+            ]
+        );
+    }
+}
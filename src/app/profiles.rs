@@ -0,0 +1,208 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use log::error;
+
+use crate::app::atomic_write::write_atomically;
+use crate::app::converter::EncoderSettings;
+use crate::app::loudness::GainMode;
+use crate::app::path_template::DEFAULT_PATH_TEMPLATE;
+use crate::app::playlist::{PlaylistFormat, PlaylistOptions};
+
+/// A named set of conversion choices for one kind of player, shareable as a TOML file.
+#[derive(Clone, Debug, PartialEq, serde::Deserialize, serde::Serialize)]
+#[serde(default)]
+pub struct Profile {
+    pub name: String,
+    // what the player holds, fit to size aims for it
+    pub size_budget: String,
+    pub encoder_settings: EncoderSettings,
+    pub playlist_options: PlaylistOptions,
+}
+
+impl Default for Profile {
+    fn default() -> Self {
+        Self {
+            name: "Default".to_string(),
+            size_budget: "3.5 GB".to_string(),
+            encoder_settings: EncoderSettings::default(),
+            playlist_options: PlaylistOptions::default(),
+        }
+    }
+}
+
+impl Profile {
+    pub fn from_toml(text: &str) -> Result<Self, toml::de::Error> {
+        toml::from_str(text)
+    }
+
+    pub fn to_toml(&self) -> String {
+        toml::to_string_pretty(self).expect("a profile is always valid TOML")
+    }
+
+    pub fn import(path: &Path) -> io::Result<Self> {
+        let text = fs::read_to_string(path)?;
+        Profile::from_toml(&text).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+
+    pub fn export(&self, path: &Path) -> io::Result<()> {
+        write_atomically(path, self.to_toml().as_bytes())
+    }
+
+    // the name, made safe to be a file name anywhere
+    fn file_name(&self) -> String {
+        let stem: String = self
+            .name
+            .chars()
+            .map(|c| {
+                if c.is_alphanumeric() {
+                    c.to_ascii_lowercase()
+                } else {
+                    '-'
+                }
+            })
+            .collect();
+        format!("{}.toml", stem.trim_matches('-'))
+    }
+}
+
+/// The players we get asked about the most.
+pub fn built_in_profiles() -> Vec<Profile> {
+    let psp = EncoderSettings {
+        sample_rate: Some(44_100),
+        // the PSP lists the folders right under MUSIC and nothing deeper, on a FAT32 stick
+        path_template: DEFAULT_PATH_TEMPLATE.to_string(),
+        fat_safe_names: true,
+        ..Default::default()
+    };
+    vec![
+        Profile {
+            name: "PSP-1000 (2 GB stick)".to_string(),
+            size_budget: "1.8 GB".to_string(),
+            encoder_settings: EncoderSettings {
+                bitrate_kbps: 160,
+                ..psp.clone()
+            },
+            ..Default::default()
+        },
+        Profile {
+            name: "PSP Go (internal)".to_string(),
+            size_budget: "14 GB".to_string(),
+            encoder_settings: EncoderSettings {
+                bitrate_kbps: 256,
+                gapless_albums: true,
+                ..psp.clone()
+            },
+            ..Default::default()
+        },
+        Profile {
+            name: "Car stereo".to_string(),
+            size_budget: "30 GB".to_string(),
+            encoder_settings: EncoderSettings {
+                bitrate_kbps: 320,
+                // no volume knob fiddling between albums on the motorway
+                gain_mode: GainMode::Track,
+                prevent_clipping: true,
+                // head units choke on big covers
+                cover_max_px: 300,
+                // and browse by artist first
+                path_template: "{album_artist}/{album}/{track} - {title}".to_string(),
+                ..psp.clone()
+            },
+            playlist_options: PlaylistOptions {
+                library: true,
+                format: PlaylistFormat::M3u,
+                ..Default::default()
+            },
+        },
+    ]
+}
+
+/// Where imported profiles are kept, one TOML file each.
+pub fn profiles_dir() -> Option<PathBuf> {
+    eframe::storage_dir("M2PSP").map(|dir| dir.join("profiles"))
+}
+
+/// The built-in profiles followed by the imported ones. An imported profile with the name of a
+/// built-in one takes its place.
+pub fn all_profiles() -> Vec<Profile> {
+    let mut profiles = built_in_profiles();
+    let imported = profiles_dir()
+        .and_then(|dir| fs::read_dir(dir).ok())
+        .into_iter()
+        .flatten()
+        .filter_map(Result::ok)
+        .map(|entry| entry.path())
+        .filter(|path| path.extension().is_some_and(|ext| ext == "toml"));
+
+    for path in imported {
+        match Profile::import(&path) {
+            Ok(profile) => match profiles.iter_mut().find(|p| p.name == profile.name) {
+                Some(existing) => *existing = profile,
+                None => profiles.push(profile),
+            },
+            Err(e) => error!("Error reading profile {:?}: {}", path, e),
+        }
+    }
+    profiles
+}
+
+pub fn find_profile(name: &str) -> Option<Profile> {
+    all_profiles()
+        .into_iter()
+        .find(|profile| profile.name.eq_ignore_ascii_case(name))
+}
+
+/// Keeps `profile` among the imported ones, returns the file it went into.
+pub fn install_profile(profile: &Profile) -> io::Result<PathBuf> {
+    let dir = profiles_dir()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no storage directory"))?;
+    fs::create_dir_all(&dir)?;
+    let path = dir.join(profile.file_name());
+    profile.export(&path)?;
+    Ok(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_profile_toml_round_trip() {
+        for profile in built_in_profiles() {
+            let text = profile.to_toml();
+            assert_eq!(Profile::from_toml(&text).unwrap(), profile, "{}", text);
+        }
+
+        // hand written, with only what differs from the defaults
+        let shared = r#"
+            name = "Walkman"
+
+            [encoder_settings]
+            bitrate_kbps = 128
+            gain_mode = "Album"
+            fat_safe_names = false
+            path_template = "{artist}/{year} - {album}/{track} {title}"
+
+            [encoder_settings.silence_trim]
+            enabled = true
+        "#;
+        let profile = Profile::from_toml(shared).unwrap();
+        assert_eq!(profile.name, "Walkman");
+        assert_eq!(profile.encoder_settings.bitrate_kbps, 128);
+        assert_eq!(profile.encoder_settings.gain_mode, GainMode::Album);
+        assert!(profile.encoder_settings.silence_trim.enabled);
+        assert_eq!(profile.encoder_settings.silence_trim.threshold_db, -60.0);
+        assert_eq!(profile.encoder_settings.sample_rate, None);
+        assert!(!profile.encoder_settings.fat_safe_names);
+        assert_eq!(
+            profile.encoder_settings.path_template,
+            "{artist}/{year} - {album}/{track} {title}"
+        );
+        assert_eq!(profile.size_budget, "3.5 GB");
+        assert_eq!(profile.file_name(), "walkman.toml");
+
+        assert!(Profile::from_toml("encoder_settings = 3").is_err());
+    }
+}
@@ -0,0 +1,198 @@
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::app::atomic_write::write_atomically;
+use crate::app::converter::{probe_track, ConversionOutput};
+use crate::app::loudness::linear_to_db;
+
+pub const REPORT_NAME: &str = "m2psp-report";
+
+/// What happened to one input, or to one of the tracks a cue sheet or chapters split it into.
+#[derive(Clone, Debug, Default, PartialEq, serde::Serialize)]
+pub struct ReportEntry {
+    pub source: PathBuf,
+    pub output: Option<PathBuf>,
+    pub duration_secs: Option<f64>,
+    pub input_codec: Option<String>,
+    pub input_sample_rate: Option<u32>,
+    // average over the MP3 frames, so it's right for copied and VBR files too
+    pub output_kbps: Option<u32>,
+    pub bytes_written: u64,
+    // time spent on this output, or on the input until it failed
+    pub elapsed_secs: f64,
+    pub warnings: Vec<String>,
+    pub error: Option<String>,
+}
+
+impl ReportEntry {
+    pub fn converted(output: &ConversionOutput) -> Self {
+        let mut warnings = Vec::new();
+        if output.lossy_transcode {
+            warnings.push("re-encoded from a lossy source".to_string());
+        }
+        if output.levels.is_clipping() {
+            warnings.push(format!(
+                "{} sample/s over full scale, true peak {:.2} dBTP",
+                output.levels.clipped_samples,
+                linear_to_db(output.levels.true_peak as f64)
+            ));
+        }
+        if output.attenuation_db < 0.0 {
+            warnings.push(format!("turned down by {:.2} dB", -output.attenuation_db));
+        }
+        let output_kbps = Some(output.duration_secs)
+            .filter(|&secs| secs > 0.0)
+            .map(|secs| (output.audio_bytes as f64 * 8.0 / secs / 1000.0).round() as u32);
+
+        ReportEntry {
+            output: Some(output.output_path.clone()),
+            duration_secs: Some(output.duration_secs),
+            output_kbps,
+            bytes_written: output.bytes_written,
+            elapsed_secs: output.elapsed_secs,
+            warnings,
+            ..ReportEntry::probed(&output.source_path)
+        }
+    }
+
+    pub fn failed(source: &Path, error: String, elapsed_secs: f64) -> Self {
+        ReportEntry {
+            elapsed_secs,
+            error: Some(error),
+            ..ReportEntry::probed(source)
+        }
+    }
+
+    // what the headers of the input say
+    fn probed(source: &Path) -> Self {
+        let probed = probe_track(source).ok();
+        ReportEntry {
+            source: source.to_path_buf(),
+            duration_secs: probed.as_ref().and_then(|track| track.duration_secs),
+            input_codec: probed
+                .as_ref()
+                .and_then(|track| track.codec.map(str::to_string)),
+            input_sample_rate: probed.as_ref().and_then(|track| track.sample_rate),
+            ..Default::default()
+        }
+    }
+}
+
+/// Everything a batch did, for keeping next to the converted files or feeding to a script.
+#[derive(Clone, Debug, Default, serde::Serialize)]
+pub struct ConversionReport {
+    pub destination: PathBuf,
+    pub elapsed_secs: f64,
+    pub entries: Vec<ReportEntry>,
+}
+
+impl ConversionReport {
+    pub fn failures(&self) -> usize {
+        self.entries
+            .iter()
+            .filter(|entry| entry.error.is_some())
+            .count()
+    }
+
+    pub fn to_json(&self) -> String {
+        serde_json::to_string_pretty(self).expect("a report is always valid JSON")
+    }
+
+    pub fn to_csv(&self) -> String {
+        let mut csv = "source,output,duration_secs,input_codec,input_sample_rate,output_kbps,\
+                       bytes_written,elapsed_secs,warnings,error\n"
+            .to_string();
+        let path = |path: &Option<PathBuf>| {
+            path.as_ref()
+                .map(|path| path.to_string_lossy().to_string())
+                .unwrap_or_default()
+        };
+        let number = |number: Option<String>| number.unwrap_or_default();
+        for entry in &self.entries {
+            let fields = [
+                entry.source.to_string_lossy().to_string(),
+                path(&entry.output),
+                number(entry.duration_secs.map(|secs| format!("{:.3}", secs))),
+                entry.input_codec.clone().unwrap_or_default(),
+                number(entry.input_sample_rate.map(|rate| rate.to_string())),
+                number(entry.output_kbps.map(|kbps| kbps.to_string())),
+                entry.bytes_written.to_string(),
+                format!("{:.3}", entry.elapsed_secs),
+                entry.warnings.join("; "),
+                entry.error.clone().unwrap_or_default(),
+            ];
+            let fields: Vec<String> = fields.iter().map(|field| csv_field(field)).collect();
+            csv.push_str(&fields.join(","));
+            csv.push('\n');
+        }
+        csv
+    }
+
+    /// Writes `m2psp-report.json` and `m2psp-report.csv` into `dir`, replacing the last ones.
+    pub fn save(&self, dir: &Path) -> io::Result<PathBuf> {
+        let json_path = dir.join(REPORT_NAME).with_extension("json");
+        write_atomically(&json_path, self.to_json().as_bytes())?;
+        write_atomically(&json_path.with_extension("csv"), self.to_csv().as_bytes())?;
+        Ok(json_path)
+    }
+}
+
+// quoted when it would otherwise split the row
+fn csv_field(field: &str) -> String {
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_report_formats() {
+        let report = ConversionReport {
+            destination: PathBuf::from("/media/PSP/MUSIC"),
+            elapsed_secs: 12.5,
+            entries: vec![
+                ReportEntry {
+                    source: PathBuf::from("/music/Album/01 Intro, Part 1.flac"),
+                    output: Some(PathBuf::from("/media/PSP/MUSIC/Album/01 - Intro.mp3")),
+                    duration_secs: Some(61.0),
+                    input_codec: Some("flac".to_string()),
+                    input_sample_rate: Some(96_000),
+                    output_kbps: Some(160),
+                    bytes_written: 1_220_000,
+                    elapsed_secs: 3.25,
+                    warnings: vec!["turned down by 1.50 dB".to_string()],
+                    error: None,
+                },
+                ReportEntry::failed(
+                    Path::new("/m2psp-gone-for-good/02.flac"),
+                    "no \"metadata\" found".to_string(),
+                    0.0,
+                ),
+            ],
+        };
+        assert_eq!(report.failures(), 1);
+
+        let csv = report.to_csv();
+        let rows: Vec<&str> = csv.lines().collect();
+        assert_eq!(rows.len(), 3);
+        assert_eq!(
+            rows[1],
+            "\"/music/Album/01 Intro, Part 1.flac\",/media/PSP/MUSIC/Album/01 - Intro.mp3,61.000,\
+             flac,96000,160,1220000,3.250,turned down by 1.50 dB,"
+        );
+        assert_eq!(
+            rows[2],
+            "/m2psp-gone-for-good/02.flac,,,,,,0,0.000,,\"no \"\"metadata\"\" found\""
+        );
+
+        let json: serde_json::Value = serde_json::from_str(&report.to_json()).unwrap();
+        assert_eq!(json["entries"][0]["input_sample_rate"], 96_000);
+        assert_eq!(json["entries"][1]["error"], "no \"metadata\" found");
+        assert!(json["entries"][1]["output"].is_null());
+    }
+}
@@ -0,0 +1,122 @@
+use std::path::PathBuf;
+
+use crate::app::converter::EncoderSettings;
+use crate::app::planner::FitMode;
+use crate::app::playlist::PlaylistOptions;
+use crate::app::sources::SourceEntry;
+
+/// Bumped whenever a stored field changes meaning, `migrated` brings older settings up to date.
+/// Fields that are simply new don't need a bump, they come in with their defaults.
+pub const SETTINGS_VERSION: u32 = 2;
+
+/// Everything the user picked, kept across restarts in eframe's storage.
+#[derive(Clone, Debug, PartialEq, serde::Deserialize, serde::Serialize)]
+#[serde(default)]
+pub struct Settings {
+    // settings saved before versioning was introduced read as 0
+    #[serde(default)]
+    pub version: u32,
+    // version 1 only kept plain folders, `migrated` turns them into `sources`
+    #[serde(skip_serializing)]
+    pub folders: Vec<PathBuf>,
+    pub sources: Vec<SourceEntry>,
+    pub destination: Option<PathBuf>,
+    pub encoder_settings: EncoderSettings,
+    pub fit_to_budget: bool,
+    pub size_budget: String,
+    pub fit_mode: FitMode,
+    pub playlist_options: PlaylistOptions,
+    // name of the profile picked last
+    pub profile: String,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Self {
+            version: SETTINGS_VERSION,
+            folders: Vec::new(),
+            sources: Vec::new(),
+            destination: None,
+            encoder_settings: EncoderSettings::default(),
+            fit_to_budget: false,
+            size_budget: "3.5 GB".to_string(),
+            fit_mode: FitMode::Uniform,
+            playlist_options: PlaylistOptions::default(),
+            profile: String::new(),
+        }
+    }
+}
+
+impl Settings {
+    pub const STORAGE_KEY: &'static str = "settings";
+
+    pub fn load(storage: Option<&dyn eframe::Storage>) -> Self {
+        storage
+            .and_then(|storage| eframe::get_value::<Settings>(storage, Settings::STORAGE_KEY))
+            .map(Settings::migrated)
+            .unwrap_or_default()
+    }
+
+    pub fn save(&self, storage: &mut dyn eframe::Storage) {
+        eframe::set_value(storage, Settings::STORAGE_KEY, self);
+    }
+
+    fn migrated(mut self) -> Self {
+        if self.version < 2 {
+            let folders = std::mem::take(&mut self.folders);
+            self.sources
+                .extend(folders.into_iter().map(SourceEntry::Folder));
+        }
+        if self.version < SETTINGS_VERSION {
+            self.version = SETTINGS_VERSION;
+        }
+        // sources and a destination on a drive that isn't plugged in stay, the UI shows them
+        // as missing, dropping them here would lose them for good on the next save
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::app::loudness::GainMode;
+
+    #[test]
+    fn test_settings_survive_missing_and_unknown_fields() {
+        let dir = std::env::temp_dir();
+        let mut settings = Settings {
+            sources: vec![
+                SourceEntry::RecursiveFolder(dir.clone()),
+                SourceEntry::File(dir.join("m2psp-gone-for-good.flac")),
+            ],
+            destination: Some(dir.clone()),
+            ..Default::default()
+        };
+        settings.encoder_settings.gain_mode = GainMode::Album;
+        settings.encoder_settings.silence_trim.enabled = true;
+
+        let saved = ron::ser::to_string(&settings).unwrap();
+        let restored: Settings = ron::from_str(&saved).unwrap();
+        assert_eq!(restored, settings);
+        // the file that's gone is kept, it may be on a drive that comes back
+        assert_eq!(restored.migrated(), settings);
+
+        // written by an older build: no version, fields missing here and there
+        let old = format!(
+            r#"(folders: [{:?}], encoder_settings: (bitrate_kbps: 128), fit_to_budget: true)"#,
+            dir
+        );
+        let restored = ron::from_str::<Settings>(&old).unwrap();
+        assert_eq!(restored.version, 0);
+        assert_eq!(restored.encoder_settings.bitrate_kbps, 128);
+        assert_eq!(restored.encoder_settings.gain_mode, GainMode::Off);
+        assert_eq!(restored.size_budget, "3.5 GB");
+        let migrated = restored.migrated();
+        assert_eq!(migrated.version, SETTINGS_VERSION);
+        assert_eq!(migrated.sources, vec![SourceEntry::Folder(dir.clone())]);
+
+        // and by a newer one, with fields this build doesn't know about
+        let new = r#"(version: 7, size_budget: "1 GB", shiny_new_option: true)"#;
+        assert_eq!(ron::from_str::<Settings>(new).unwrap().size_budget, "1 GB");
+    }
+}
@@ -0,0 +1,155 @@
+use crate::app::loudness::db_to_linear;
+
+// how much of the silence is kept on either side when fading, just enough not to cut the
+// noise floor of a vinyl rip off abruptly
+const FADE_SECS: f64 = 0.25;
+
+/// Cuts long silences off the start and the end of tracks.
+#[derive(Clone, Copy, Debug, PartialEq, serde::Deserialize, serde::Serialize)]
+#[serde(default)]
+pub struct SilenceTrim {
+    pub enabled: bool,
+    // anything quieter than this counts as silence
+    pub threshold_db: f64,
+    // shorter silences are part of the music and stay
+    pub min_duration_secs: f64,
+    pub fade: bool,
+}
+
+impl Default for SilenceTrim {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            threshold_db: -60.0,
+            min_duration_secs: 2.0,
+            fade: false,
+        }
+    }
+}
+
+impl SilenceTrim {
+    /// Trims the start and/or the end of `pcm_data`, returns how many frames were cut off.
+    pub fn trim(
+        &self,
+        pcm_data: &mut [Vec<f32>],
+        sample_rate: u32,
+        trim_start: bool,
+        trim_end: bool,
+    ) -> usize {
+        let frame_count = pcm_data.first().map_or(0, Vec::len);
+        let threshold = db_to_linear(self.threshold_db) as f32;
+        let is_audible = |i: usize| pcm_data.iter().any(|channel| channel[i].abs() >= threshold);
+
+        // nothing but silence is better left alone than turned into an empty file
+        let Some(first_audible) = (0..frame_count).find(|&i| is_audible(i)) else {
+            return 0;
+        };
+        let last_audible = (0..frame_count).rev().find(|&i| is_audible(i)).unwrap();
+
+        let min_frames = (self.min_duration_secs * sample_rate as f64) as usize;
+        let fade_frames = if self.fade {
+            (FADE_SECS * sample_rate as f64) as usize
+        } else {
+            0
+        };
+
+        let leading = first_audible;
+        let trailing = frame_count - 1 - last_audible;
+        let start = if trim_start && leading >= min_frames {
+            leading.saturating_sub(fade_frames)
+        } else {
+            0
+        };
+        let end = if trim_end && trailing >= min_frames {
+            (last_audible + 1 + fade_frames).min(frame_count)
+        } else {
+            frame_count
+        };
+
+        for channel in pcm_data.iter_mut() {
+            channel.truncate(end);
+            channel.drain(..start);
+            if start > 0 {
+                fade_in(&mut channel[..fade_frames.min(first_audible - start)]);
+            }
+            if end < frame_count {
+                let len = channel.len();
+                fade_out(&mut channel[len - (end - last_audible - 1)..]);
+            }
+        }
+
+        frame_count - (end - start)
+    }
+}
+
+fn fade_in(samples: &mut [f32]) {
+    let len = samples.len() as f32;
+    for (i, sample) in samples.iter_mut().enumerate() {
+        *sample *= i as f32 / len;
+    }
+}
+
+fn fade_out(samples: &mut [f32]) {
+    let len = samples.len() as f32;
+    for (i, sample) in samples.iter_mut().enumerate() {
+        *sample *= (len - 1.0 - i as f32) / len;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SAMPLE_RATE: u32 = 1000;
+
+    // 3 s of near silence, 1 s of tone, 3 s of near silence
+    fn vinyl_rip() -> Vec<Vec<f32>> {
+        let rate = SAMPLE_RATE as usize;
+        let channel: Vec<f32> = (0..7 * rate)
+            .map(|i| {
+                if (3 * rate..4 * rate).contains(&i) {
+                    0.5
+                } else {
+                    0.0001
+                }
+            })
+            .collect();
+        vec![channel.clone(), channel]
+    }
+
+    #[test]
+    fn test_trim_silence() {
+        let trim = SilenceTrim {
+            enabled: true,
+            ..Default::default()
+        };
+
+        let mut pcm_data = vinyl_rip();
+        assert_eq!(trim.trim(&mut pcm_data, SAMPLE_RATE, true, true), 6000);
+        assert_eq!(pcm_data[0].len(), 1000);
+        assert_eq!(pcm_data[1][0], 0.5);
+
+        // only the end, like the first track of a CUE rip would get
+        let mut pcm_data = vinyl_rip();
+        trim.trim(&mut pcm_data, SAMPLE_RATE, false, true);
+        assert_eq!(pcm_data[0].len(), 4000);
+
+        // the silence is kept and faded in and out on either side
+        let fading = SilenceTrim { fade: true, ..trim };
+        let mut pcm_data = vinyl_rip();
+        fading.trim(&mut pcm_data, SAMPLE_RATE, true, true);
+        assert_eq!(pcm_data[0].len(), 1000 + 2 * 250);
+        assert_eq!(pcm_data[0][0], 0.0);
+        assert_eq!(pcm_data[0][250], 0.5);
+        assert_eq!(pcm_data[0][1500 - 1], 0.0);
+
+        // 3 s of silence isn't long enough to go
+        let patient = SilenceTrim {
+            min_duration_secs: 5.0,
+            ..trim
+        };
+        let mut pcm_data = vinyl_rip();
+        assert_eq!(patient.trim(&mut pcm_data, SAMPLE_RATE, true, true), 0);
+        assert_eq!(pcm_data[0].len(), 7000);
+    }
+}
@@ -0,0 +1,214 @@
+use std::collections::HashSet;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use log::error;
+
+use crate::app::playlist::{SourcePlaylist, PLAYLIST_EXTENSIONS};
+
+// what AudioConverter can decode, raw ADTS .aac is left out since it carries no tags
+pub const AUDIO_EXTENSIONS: [&str; 5] = ["flac", "ogg", "mp3", "m4a", "m4b"];
+
+/// Something the user added to be converted.
+#[derive(Clone, Debug, PartialEq, Eq, Hash, serde::Deserialize, serde::Serialize)]
+pub enum SourceEntry {
+    // the tracks right inside it
+    Folder(PathBuf),
+    // the tracks inside it and in all of its subfolders
+    RecursiveFolder(PathBuf),
+    File(PathBuf),
+    Playlist(PathBuf),
+}
+
+impl SourceEntry {
+    /// What `path` is to us, None for files that are neither tracks nor playlists.
+    pub fn from_path(path: PathBuf) -> Option<Self> {
+        if path.is_dir() {
+            Some(SourceEntry::Folder(path))
+        } else if has_extension(&path, &AUDIO_EXTENSIONS) {
+            Some(SourceEntry::File(path))
+        } else if has_extension(&path, &PLAYLIST_EXTENSIONS) {
+            Some(SourceEntry::Playlist(path))
+        } else {
+            None
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        match self {
+            SourceEntry::Folder(path)
+            | SourceEntry::RecursiveFolder(path)
+            | SourceEntry::File(path)
+            | SourceEntry::Playlist(path) => path,
+        }
+    }
+
+    pub fn kind(&self) -> &'static str {
+        match self {
+            SourceEntry::Folder(_) => "folder",
+            SourceEntry::RecursiveFolder(_) => "folder + subfolders",
+            SourceEntry::File(_) => "track",
+            SourceEntry::Playlist(_) => "playlist",
+        }
+    }
+
+    pub fn exists(&self) -> bool {
+        match self {
+            SourceEntry::Folder(path) | SourceEntry::RecursiveFolder(path) => path.is_dir(),
+            SourceEntry::File(path) | SourceEntry::Playlist(path) => path.is_file(),
+        }
+    }
+
+    fn tracks(&self) -> Vec<PathBuf> {
+        match self {
+            SourceEntry::Folder(dir) => {
+                collect_files_with_extensions(dir, &AUDIO_EXTENSIONS, false)
+            }
+            SourceEntry::RecursiveFolder(dir) => {
+                collect_files_with_extensions(dir, &AUDIO_EXTENSIONS, true)
+            }
+            SourceEntry::File(path) => vec![path.clone()],
+            SourceEntry::Playlist(_) => Vec::new(),
+        }
+    }
+
+    fn playlists(&self) -> Vec<PathBuf> {
+        match self {
+            SourceEntry::Folder(dir) => {
+                collect_files_with_extensions(dir, &PLAYLIST_EXTENSIONS, false)
+            }
+            SourceEntry::RecursiveFolder(dir) => {
+                collect_files_with_extensions(dir, &PLAYLIST_EXTENSIONS, true)
+            }
+            SourceEntry::File(_) => Vec::new(),
+            SourceEntry::Playlist(path) => vec![path.clone()],
+        }
+    }
+}
+
+/// Audio files of all `sources`, followed by the tracks of the playlists among them,
+/// each file only once.
+pub fn collect_sources(sources: &[SourceEntry]) -> (Vec<PathBuf>, Vec<SourcePlaylist>) {
+    let mut files: Vec<PathBuf> = Vec::new();
+    let mut seen: HashSet<PathBuf> = HashSet::new();
+    // kept in the list while their drive is unplugged, but there's nothing to read
+    let sources: Vec<&SourceEntry> = sources.iter().filter(|source| source.exists()).collect();
+    for file in sources.iter().flat_map(|source| source.tracks()) {
+        if seen.insert(file.clone()) {
+            files.push(file);
+        }
+    }
+
+    let mut playlists = Vec::new();
+    for playlist_path in sources.iter().flat_map(|source| source.playlists()) {
+        match SourcePlaylist::read(&playlist_path) {
+            Ok(playlist) => {
+                for entry in &playlist.entries {
+                    if has_extension(entry, &AUDIO_EXTENSIONS) && seen.insert(entry.clone()) {
+                        files.push(entry.clone());
+                    }
+                }
+                playlists.push(playlist);
+            }
+            Err(e) => error!("Error reading playlist {:?}: {}", playlist_path, e),
+        }
+    }
+
+    (files, playlists)
+}
+
+// Collects all the files that have an acceptable extension
+fn collect_files_with_extensions(
+    dir: &Path,
+    valid_extensions: &[&str],
+    recursive: bool,
+) -> Vec<PathBuf> {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) => {
+            error!("Error reading {:?}: {}", dir, e);
+            return Vec::new();
+        }
+    };
+    let mut paths: Vec<PathBuf> = entries.flatten().map(|entry| entry.path()).collect();
+    paths.sort();
+
+    let mut files = Vec::new();
+    for path in paths {
+        if path.is_dir() {
+            if recursive {
+                files.extend(collect_files_with_extensions(&path, valid_extensions, true));
+            }
+        } else if has_extension(&path, valid_extensions) {
+            files.push(path);
+        }
+    }
+    files
+}
+
+pub fn has_extension(path: &Path, extensions: &[&str]) -> bool {
+    path.extension()
+        .and_then(|ext| ext.to_str())
+        .is_some_and(|ext| extensions.contains(&ext.to_ascii_lowercase().as_str()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::app::converter::{AudioConverter, AudioFiletype};
+
+    #[test]
+    fn test_collect_sources() {
+        let dir = std::env::temp_dir().join(format!("m2psp-sources-{}", std::process::id()));
+        let album = dir.join("Artist").join("Album");
+        fs::create_dir_all(&album).unwrap();
+        for file in ["01.flac", "02.FLAC", "cover.jpg"] {
+            fs::write(album.join(file), b"").unwrap();
+        }
+        fs::write(dir.join("single.mp3"), b"").unwrap();
+        fs::write(dir.join("mix.m3u"), "Artist/Album/02.FLAC\nelsewhere.ogg\n").unwrap();
+
+        assert_eq!(
+            SourceEntry::from_path(dir.join("mix.m3u")),
+            Some(SourceEntry::Playlist(dir.join("mix.m3u")))
+        );
+        assert_eq!(SourceEntry::from_path(album.join("cover.jpg")), None);
+
+        // only what's right inside
+        let (files, playlists) = collect_sources(&[SourceEntry::Folder(dir.clone())]);
+        assert_eq!(files[0], dir.join("single.mp3"));
+        assert_eq!(playlists.len(), 1);
+        // 02.FLAC comes from the playlist, not from the folder
+        assert_eq!(files.len(), 3);
+
+        let (files, _) = collect_sources(&[
+            SourceEntry::RecursiveFolder(dir.clone()),
+            SourceEntry::File(dir.join("single.mp3")),
+        ]);
+        assert_eq!(
+            files,
+            vec![
+                album.join("01.flac"),
+                album.join("02.FLAC"),
+                dir.join("single.mp3"),
+                dir.join("elsewhere.ogg"),
+            ]
+        );
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_audio_extensions_convert() {
+        for extension in AUDIO_EXTENSIONS {
+            for extension in [extension.to_string(), extension.to_ascii_uppercase()] {
+                let path = PathBuf::from("/music/01").with_extension(&extension);
+                assert!(
+                    AudioConverter::new(path, AudioFiletype::MP3).is_ok(),
+                    "no converter for .{}",
+                    extension
+                );
+            }
+        }
+    }
+}
@@ -1,16 +1,73 @@
-use crate::app::converter::{AudioConverter, AudioFiletype};
+use crate::app::atomic_write::sweep_stale_temp_files;
+use crate::app::converter::{
+    probe_track, AudioConverter, AudioFiletype, ConversionOutput, ConversionResult,
+    EncoderSettings, TagOverrides,
+};
+use crate::app::dry_run::{ConversionPlan, OutputOwners};
+use crate::app::job::SourceRoot;
+use crate::app::loudness::{linear_to_db, GainMode};
+use crate::app::playlist::{
+    write_converted_playlists, write_job_playlists, PlaylistOptions, SourcePlaylist,
+};
+use crate::app::report::{ConversionReport, ReportEntry};
+use log::{error, info, warn};
 use rayon::prelude::*;
+use symphonia::core::errors::Error;
+use std::collections::{BTreeMap, HashMap};
+use std::panic::{self, AssertUnwindSafe};
 use std::path::{Path, PathBuf};
-use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
 use std::sync::Arc;
 use std::thread;
+use std::time::Instant;
+
+// clears the busy flag however the batch thread ends, a panic included
+struct BusyGuard(Arc<AtomicBool>);
+
+impl Drop for BusyGuard {
+    fn drop(&mut self) {
+        self.0.store(false, Ordering::Relaxed);
+    }
+}
+
+/// Runs `f` on behalf of `file`. A panic in there is logged against the file and comes back as
+/// `None`, rather than taking the rest of the background work down with it.
+pub(crate) fn catch_panic<T>(file: &Path, f: impl FnOnce() -> T) -> Option<T> {
+    panic::catch_unwind(AssertUnwindSafe(f))
+        .map_err(|payload| {
+            let message = payload
+                .downcast_ref::<&str>()
+                .map(|message| message.to_string())
+                .or_else(|| payload.downcast_ref::<String>().cloned())
+                .unwrap_or_default();
+            error!(file:% = file.display(); "Panicked on {:?}: {}", file, message);
+        })
+        .ok()
+}
 
 pub struct ThreadHandler {
     pub num_processing: Arc<AtomicUsize>,
     pub num_finished: Arc<AtomicUsize>,
+    pub bytes_written: Arc<AtomicU64>,
+    // tracks of the current job that go past full scale
+    pub clipped_tracks: Arc<AtomicUsize>,
+    // tracks of the current job re-encoded from a lossy source
+    pub lossy_transcodes: Arc<AtomicUsize>,
 
     file_buffer: Vec<PathBuf>,
     pub destination: PathBuf,
+    pub encoder_settings: EncoderSettings,
+    // per file bitrates picked by the fit-to-capacity planner, win over `encoder_settings`
+    pub bitrate_overrides: HashMap<PathBuf, u16>,
+    // per file tag edits from the editor
+    pub tag_overrides: HashMap<PathBuf, TagOverrides>,
+    // folders with settings of their own, from a job file
+    pub source_roots: Vec<SourceRoot>,
+    pub playlist_options: PlaylistOptions,
+    // playlists found among the sources, rewritten to point at the converted tracks
+    pub source_playlists: Vec<SourcePlaylist>,
+    // where the report of a batch is saved, the destination when None
+    pub report_dir: Option<PathBuf>,
     pub is_busy: Arc<AtomicBool>,
 }
 
@@ -19,48 +76,280 @@ impl ThreadHandler {
         Self {
             num_processing: Arc::new(AtomicUsize::new(0)),
             num_finished: Arc::new(AtomicUsize::new(0)),
+            bytes_written: Arc::new(AtomicU64::new(0)),
+            clipped_tracks: Arc::new(AtomicUsize::new(0)),
+            lossy_transcodes: Arc::new(AtomicUsize::new(0)),
             file_buffer: Vec::new(),
             destination: PathBuf::new(),
+            encoder_settings: EncoderSettings::default(),
+            bitrate_overrides: HashMap::new(),
+            tag_overrides: HashMap::new(),
+            source_roots: Vec::new(),
+            playlist_options: PlaylistOptions::default(),
+            source_playlists: Vec::new(),
+            report_dir: None,
             is_busy: Arc::new(AtomicBool::new(false)),
         }
     }
 
-    fn process( input_path: PathBuf, dest_path: PathBuf) {
-        let binding = input_path.clone();
-        let filename = binding.file_name().unwrap();
-        println!("Currently converting : {:?}", filename);
-        let audio_converter = AudioConverter::new(input_path, AudioFiletype::MP3);
-        let res = audio_converter.convert_file_to_mp3(dest_path);
+    fn process(
+        input_paths: &[PathBuf],
+        dest_path: PathBuf,
+        settings_for: impl Fn(&PathBuf) -> EncoderSettings,
+        tag_overrides: &HashMap<PathBuf, TagOverrides>,
+        output_owners: &Arc<OutputOwners>,
+    ) -> (Vec<ConversionOutput>, Vec<ReportEntry>) {
+        let mut converters: Vec<AudioConverter> = Vec::new();
+        let mut unsupported: Vec<(&PathBuf, Error)> = Vec::new();
+        for input_path in input_paths {
+            info!(
+                file:% = input_path.display();
+                "Currently converting : {:?}", input_path.file_name().unwrap()
+            );
+            match AudioConverter::new(input_path.clone(), AudioFiletype::MP3) {
+                Ok(converter) => converters.push(
+                    converter
+                        .with_encoder_settings(settings_for(input_path))
+                        .with_tag_overrides(
+                            tag_overrides.get(input_path).cloned().unwrap_or_default(),
+                        )
+                        .with_output_owners(Arc::clone(output_owners)),
+                ),
+                Err(e) => unsupported.push((input_path, e)),
+            }
+        }
+        let results: Vec<ConversionResult> = match converters.as_slice() {
+            [] => Vec::new(),
+            [audio_converter] => audio_converter.convert_file_to_mp3(dest_path),
+            _ => AudioConverter::convert_album_to_mp3(&converters, dest_path),
+        };
+        let results = unsupported
+            .into_iter()
+            .map(|(input, e)| Err((input.clone(), e.to_string(), 0.0)))
+            .chain(results);
 
-        match res {
-            Ok(()) => println!("Converted! : {:?}", filename),
-            Err(e) => eprintln!("Error for file {:?}... : {}", filename, e),
+        let mut outputs = Vec::new();
+        let mut entries = Vec::new();
+        for result in results {
+            let output = match result {
+                Ok(output) => output,
+                Err((input, e, elapsed_secs)) => {
+                    error!(file:% = input.display(); "Error for file {:?}... : {}", input, e);
+                    entries.push(ReportEntry::failed(&input, e, elapsed_secs));
+                    continue;
+                }
+            };
+            let filename = output.source_path.file_name().unwrap();
+            let source = output.source_path.display();
+            if output.passthrough {
+                info!(file:% = source; "Copied! : {:?} -> {:?}", filename, output.output_path);
+            } else {
+                info!(file:% = source; "Converted! : {:?} -> {:?}", filename, output.output_path);
+            }
+            if output.lossy_transcode {
+                warn!(
+                    file:% = source;
+                    "Lossy to lossy! : {:?} was re-encoded and lost some quality",
+                    filename
+                );
+            }
+            if output.levels.is_clipping() {
+                warn!(
+                    file:% = source;
+                    "Clipping! : {:?} has {} sample/s over full scale, true peak {:.2} dBTP",
+                    output.output_path,
+                    output.levels.clipped_samples,
+                    linear_to_db(output.levels.true_peak as f64)
+                );
+            }
+            if output.attenuation_db < 0.0 {
+                info!(
+                    file:% = source;
+                    "Turned down by {:.2} dB : {:?}",
+                    -output.attenuation_db, output.output_path
+                );
+            }
+            entries.push(ReportEntry::converted(&output));
+            outputs.push(output);
         }
+        (outputs, entries)
     }
+
+    // (folder, album) pairs, so two albums called "Greatest Hits" don't end up in one session
+    fn group_by_album(files: &[PathBuf]) -> Vec<Vec<PathBuf>> {
+        let keyed: Vec<((PathBuf, String), &PathBuf)> = files
+            .par_iter()
+            .map(|file| {
+                let folder = file.parent().map(Path::to_path_buf).unwrap_or_default();
+                let album = probe_track(file).map(|track| track.album).unwrap_or_default();
+                ((folder, album), file)
+            })
+            .collect();
+
+        let mut albums: BTreeMap<(PathBuf, String), Vec<PathBuf>> = BTreeMap::new();
+        for (key, file) in keyed {
+            albums.entry(key).or_default().push(file.clone());
+        }
+        albums.into_values().collect()
+    }
+
     // TODO : siamo sicuri che la cosa migliore da fare è .clone di pathbuf?
-    pub fn execute_threads(&self) {
+    pub fn execute_threads(&mut self) -> thread::JoinHandle<ConversionReport> {
         let num_processing = Arc::clone(&self.num_processing);
         let num_finished = Arc::clone(&self.num_finished);
+        let bytes_written = Arc::clone(&self.bytes_written);
+        let clipped_tracks = Arc::clone(&self.clipped_tracks);
+        let lossy_transcodes = Arc::clone(&self.lossy_transcodes);
         let is_busy = Arc::clone(&self.is_busy);
 
-        let file_buffer = self.file_buffer.clone();
+        // taken, so the next batch doesn't convert this one's files all over again
+        let file_buffer = std::mem::take(&mut self.file_buffer);
         let destination = self.destination.clone();
+        let encoder_settings = self.encoder_settings.clone();
+        let bitrate_overrides = self.bitrate_overrides.clone();
+        let tag_overrides = self.tag_overrides.clone();
+        let source_roots = self.source_roots.clone();
+        let playlist_options = self.playlist_options;
+        let source_playlists = self.source_playlists.clone();
+        let report_dir = self.report_dir.clone().unwrap_or_else(|| self.destination.clone());
 
-        let handle = thread::spawn(move || {
-            is_busy.store(true, Ordering::Relaxed);
-            file_buffer.par_iter().for_each(|input| {
-                num_processing.fetch_add(1, Ordering::SeqCst);
-                ThreadHandler::process(input.clone(), destination.clone());
-                num_finished.fetch_add(1, Ordering::SeqCst);
-            });
-            is_busy.store(false, Ordering::Relaxed);
-
+        // set before spawning so the very next frame already sees the job as running
+        is_busy.store(true, Ordering::Relaxed);
+        num_processing.store(0, Ordering::Relaxed);
+        num_finished.store(0, Ordering::Relaxed);
+        bytes_written.store(0, Ordering::Relaxed);
+        clipped_tracks.store(0, Ordering::Relaxed);
+        lossy_transcodes.store(0, Ordering::Relaxed);
+        thread::spawn(move || {
+            let _busy = BusyGuard(is_busy);
+            let started = Instant::now();
+            // leftovers from a previous run that got interrupted halfway through
+            let swept = sweep_stale_temp_files(&destination);
+            if swept > 0 {
+                info!("Removed {} stale temp file/s", swept);
+            }
+            let settings_for = |input: &PathBuf| {
+                settings_for(input, &encoder_settings, &source_roots, &bitrate_overrides)
+            };
+            // tracks that would overwrite each other: the one the dry run lets through is
+            // written, the others fail like the dry run says
+            let plan =
+                ConversionPlan::new(&file_buffer, &destination, settings_for, &tag_overrides);
+            let output_owners = Arc::new(plan.output_owners());
+            // album gain and the album ReplayGain tags need the whole album at once and a
+            // gapless album has to go through one encoder, so those albums become a single job
+            let whole_albums = encoder_settings.gapless_albums
+                || encoder_settings.gain_mode == GainMode::Album
+                || encoder_settings.replaygain_tags;
+            let jobs: Vec<Vec<PathBuf>> = if whole_albums {
+                ThreadHandler::group_by_album(&file_buffer)
+            } else {
+                file_buffer.iter().map(|input| vec![input.clone()]).collect()
+            };
+            let (outputs, entries): (Vec<Vec<ConversionOutput>>, Vec<Vec<ReportEntry>>) = jobs
+                .par_iter()
+                .map(|inputs| {
+                    num_processing.fetch_add(inputs.len(), Ordering::SeqCst);
+                    let processed = catch_panic(&inputs[0], || {
+                        ThreadHandler::process(
+                            inputs,
+                            destination.clone(),
+                            settings_for,
+                            &tag_overrides,
+                            &output_owners,
+                        )
+                    });
+                    // the rest of the batch carries on, the whole job goes in the report as failed
+                    let (outputs, entries) = processed.unwrap_or_else(|| {
+                        let entries = inputs
+                            .iter()
+                            .map(|input| {
+                                ReportEntry::failed(input, "the conversion panicked".into(), 0.0)
+                            })
+                            .collect();
+                        (Vec::new(), entries)
+                    });
+                    for output in &outputs {
+                        bytes_written.fetch_add(output.bytes_written, Ordering::SeqCst);
+                        if output.levels.is_clipping() {
+                            clipped_tracks.fetch_add(1, Ordering::SeqCst);
+                        }
+                        if output.lossy_transcode {
+                            lossy_transcodes.fetch_add(1, Ordering::SeqCst);
+                        }
+                    }
+                    num_finished.fetch_add(inputs.len(), Ordering::SeqCst);
+                    (outputs, entries)
+                })
+                .unzip();
+            let outputs: Vec<ConversionOutput> = outputs.into_iter().flatten().collect();
 
-        });
+            let mut playlists = write_job_playlists(
+                &destination,
+                &outputs,
+                &playlist_options,
+                encoder_settings.fat_safe_names,
+            );
+            playlists.extend(write_converted_playlists(
+                &destination,
+                &source_playlists,
+                &outputs,
+                playlist_options.format,
+            ));
+            for playlist in playlists {
+                info!("Playlist written : {:?}", playlist);
+            }
 
+            let mut entries: Vec<ReportEntry> = entries.into_iter().flatten().collect();
+            entries.sort_by(|a, b| (&a.source, &a.output).cmp(&(&b.source, &b.output)));
+            let report = ConversionReport {
+                destination,
+                elapsed_secs: started.elapsed().as_secs_f64(),
+                entries,
+            };
+            match report.save(&report_dir) {
+                Ok(path) => info!(
+                    "Report written : {:?}, {} failure/s",
+                    path,
+                    report.failures()
+                ),
+                Err(e) => error!("Error writing the report into {:?}: {}", report_dir, e),
+            }
+            report
+        })
     }
 
     pub fn add_files(&mut self, files: Vec<PathBuf>) {
         self.file_buffer.extend(files);
     }
+
+    /// What `execute_threads` would do with the files added so far, without writing anything.
+    pub fn plan(&self) -> ConversionPlan {
+        ConversionPlan::new(
+            &self.file_buffer,
+            &self.destination,
+            |input| {
+                let (roots, overrides) = (&self.source_roots, &self.bitrate_overrides);
+                settings_for(input, &self.encoder_settings, roots, overrides)
+            },
+            &self.tag_overrides,
+        )
+    }
+}
+
+// the job's settings with what its folder and the planner changed for `input`
+fn settings_for(
+    input: &PathBuf,
+    encoder_settings: &EncoderSettings,
+    source_roots: &[SourceRoot],
+    bitrate_overrides: &HashMap<PathBuf, u16>,
+) -> EncoderSettings {
+    let mut settings = encoder_settings.clone();
+    for root in source_roots.iter().filter(|root| root.contains(input)) {
+        root.apply(&mut settings);
+    }
+    if let Some(&bitrate_kbps) = bitrate_overrides.get(input) {
+        settings.bitrate_kbps = bitrate_kbps;
+    }
+    settings
 }
@@ -2,4 +2,4 @@
 
 mod app;
 
-pub use app::TemplateApp;
+pub use app::{cli, log_console, TemplateApp};
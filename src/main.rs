@@ -4,7 +4,21 @@
 // When compiling natively:
 #[cfg(not(target_arch = "wasm32"))]
 fn main() -> eframe::Result {
-    env_logger::init(); // Log to stderr (if you run with `RUST_LOG=debug`).
+    m2psp::log_console::init_logging(); // Log to stderr and to the log window.
+
+    // `m2psp --profile NAME` opens the window with a profile picked, anything else is a command
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let profile = match args.as_slice() {
+        [] => None,
+        [flag, name] if flag == "--profile" => Some(name.clone()),
+        _ => {
+            if let Err(e) = m2psp::cli::run(&args) {
+                eprintln!("{}", e);
+                std::process::exit(1);
+            }
+            return Ok(());
+        }
+    };
 
     let native_options = eframe::NativeOptions {
         viewport: egui::ViewportBuilder::default()
@@ -22,7 +36,15 @@ fn main() -> eframe::Result {
     eframe::run_native(
         "M2PSP",
         native_options,
-        Box::new(|cc| Ok(Box::new(m2psp::TemplateApp::new(cc)))),
+        Box::new(|cc| {
+            let mut app = m2psp::TemplateApp::new(cc);
+            if let Some(name) = profile {
+                if !app.use_profile(&name) {
+                    log::warn!("No profile called {:?}, see `m2psp profiles`", name);
+                }
+            }
+            Ok(Box::new(app))
+        }),
     )
 }
 